@@ -0,0 +1,20 @@
+-- Every undoable tag operation of a user
+CREATE TABLE tag_journal (
+  journal_id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  user_id text NOT NULL,
+  action text NOT NULL,
+  created_at integer NOT NULL DEFAULT 0
+);
+
+CREATE INDEX "tag_journal_user_id" ON tag_journal (
+  "user_id" ASC
+);
+
+-- The tags that were added (added = 1) or removed (added = 0) by an operation
+CREATE TABLE tag_journal_entry (
+  journal_id integer NOT NULL,
+  entity_id text NOT NULL,
+  tag_name text NOT NULL,
+  added integer NOT NULL,
+  PRIMARY KEY (journal_id, entity_id, tag_name)
+);
@@ -0,0 +1,15 @@
+-- Every tag change ever made to an entity of a user, and what caused it
+CREATE TABLE tag_history (
+  history_id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  user_id text NOT NULL,
+  entity_id text NOT NULL,
+  tag_name text NOT NULL,
+  added integer NOT NULL,
+  source text NOT NULL,
+  created_at integer NOT NULL DEFAULT 0
+);
+
+CREATE INDEX "tag_history_user_id_entity_id" ON tag_history (
+  "user_id" ASC,
+  "entity_id" ASC
+);
@@ -0,0 +1,7 @@
+-- Users that used /stop are kept around for a grace period before being purged
+ALTER TABLE user_data ADD COLUMN deleted_at INTEGER DEFAULT NULL;
+
+-- Only the data of users that are not scheduled for deletion, use this for reading
+CREATE VIEW active_entity_data AS
+SELECT * FROM entity_data
+WHERE user_id NOT IN (SELECT user_id FROM user_data WHERE deleted_at IS NOT NULL);
@@ -0,0 +1,5 @@
+-- Delta exports only contain what changed since the last export
+ALTER TABLE user_data ADD COLUMN last_export_time INTEGER NOT NULL DEFAULT 0;
+
+-- Imports can change the stats without using the entity
+ALTER TABLE entity_data ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+-- /fixentities runs in the background, and continues from here after a restart
+CREATE TABLE fix_job (
+  user_id text NOT NULL PRIMARY KEY,
+  chat_id integer NOT NULL,
+  message_id integer NOT NULL,
+  -- Entities are checked in order, everything up to this one is done
+  last_entity_id text NOT NULL DEFAULT '',
+  checked integer NOT NULL DEFAULT 0,
+  fixed integer NOT NULL DEFAULT 0,
+  -- A JSON list of the removed entities and why they were removed
+  diagnostics text NOT NULL DEFAULT '[]',
+  cancelled integer NOT NULL DEFAULT 0,
+  created_at integer NOT NULL DEFAULT 0
+);
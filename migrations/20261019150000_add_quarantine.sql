@@ -0,0 +1,4 @@
+-- Files that Telegram does not know anymore are hidden instead of deleted, they
+-- come back when the same entity is sent again
+ALTER TABLE entity_file ADD COLUMN broken_at INTEGER DEFAULT NULL;
+ALTER TABLE entity_file ADD COLUMN broken_reason TEXT DEFAULT NULL;
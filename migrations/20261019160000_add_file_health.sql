@@ -0,0 +1,8 @@
+-- Every file is checked with Telegram from time to time, oldest first
+ALTER TABLE entity_file ADD COLUMN last_checked INTEGER NOT NULL DEFAULT 0;
+-- 'ok' or 'broken', NULL if it was never checked
+ALTER TABLE entity_file ADD COLUMN check_status TEXT DEFAULT NULL;
+
+CREATE INDEX "entity_file_last_checked" ON entity_file (
+  "last_checked" ASC
+);
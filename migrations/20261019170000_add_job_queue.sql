@@ -0,0 +1,43 @@
+-- Long running work of users, run by a pool of workers and continued after a restart
+CREATE TABLE job (
+  job_id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  user_id text NOT NULL,
+  chat_id integer NOT NULL,
+  -- The message that shows the progress of the job
+  message_id integer NOT NULL,
+  -- The name of the job, and everything it needs to run as JSON
+  kind text NOT NULL,
+  payload text NOT NULL,
+  -- queued, running, done, failed or cancelled
+  status text NOT NULL DEFAULT 'queued',
+  progress text NOT NULL DEFAULT '',
+  -- How far the job got as JSON, for jobs that can continue where they left off
+  checkpoint text DEFAULT NULL,
+  cancelled integer NOT NULL DEFAULT 0,
+  created_at integer NOT NULL,
+  finished_at integer DEFAULT NULL
+);
+
+CREATE INDEX "job_status" ON job (
+  "status" ASC,
+  "job_id" ASC
+);
+
+CREATE INDEX "job_user_id" ON job (
+  "user_id" ASC,
+  "job_id" DESC
+);
+
+-- Running fix jobs continue as a job of the queue
+INSERT INTO job (user_id, chat_id, message_id, kind, payload, checkpoint, cancelled, created_at)
+  SELECT user_id, chat_id, message_id, 'fix_entities', '{"kind":"fix_entities"}',
+    json_object(
+      'last_entity_id', last_entity_id,
+      'checked', checked,
+      'fixed', fixed,
+      'diagnostics', json(diagnostics)
+    ),
+    cancelled, created_at
+  FROM fix_job;
+
+DROP TABLE fix_job;
@@ -0,0 +1,137 @@
+-- Rebuilds the tables with consistent types and foreign keys, so deleting an
+-- entity, user or journal removes everything that belongs to it
+
+-- Renaming a table would make the view point to the old one
+DROP VIEW active_entity_data;
+
+ALTER TABLE entity_file RENAME TO entity_file_old;
+ALTER TABLE entity_tag RENAME TO entity_tag_old;
+ALTER TABLE entity_data RENAME TO entity_data_old;
+ALTER TABLE entity_main RENAME TO entity_main_old;
+ALTER TABLE user_data RENAME TO user_data_old;
+ALTER TABLE tag_journal RENAME TO tag_journal_old;
+ALTER TABLE tag_journal_entry RENAME TO tag_journal_entry_old;
+
+CREATE TABLE entity_file (
+  entity_id text NOT NULL PRIMARY KEY,
+  file_id text NOT NULL,
+  entity_type text NOT NULL,
+  broken_at integer DEFAULT NULL,
+  broken_reason text DEFAULT NULL,
+  last_checked integer NOT NULL DEFAULT 0,
+  check_status text DEFAULT NULL
+);
+
+CREATE TABLE entity_tag (
+  tag_id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  tag_name text NOT NULL UNIQUE
+);
+
+CREATE TABLE entity_data (
+  combo_id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  user_id text NOT NULL,
+  entity_id text NOT NULL REFERENCES entity_file (entity_id) ON DELETE CASCADE,
+  count integer NOT NULL DEFAULT 0,
+  created_at integer NOT NULL DEFAULT 0,
+  last_used integer NOT NULL DEFAULT 0,
+  updated_at integer NOT NULL DEFAULT 0,
+  CONSTRAINT entity_data_unique UNIQUE (user_id, entity_id)
+);
+
+CREATE TABLE entity_main (
+  combo_id integer NOT NULL REFERENCES entity_data (combo_id) ON DELETE CASCADE,
+  tag_id integer NOT NULL REFERENCES entity_tag (tag_id) ON DELETE CASCADE,
+  PRIMARY KEY (combo_id, tag_id)
+);
+
+CREATE TABLE user_data (
+  user_id text NOT NULL PRIMARY KEY,
+  last_fixed_time integer NOT NULL DEFAULT 0,
+  deleted_at integer DEFAULT NULL,
+  last_export_time integer NOT NULL DEFAULT 0
+);
+
+CREATE TABLE tag_journal (
+  journal_id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  user_id text NOT NULL,
+  action text NOT NULL,
+  created_at integer NOT NULL DEFAULT 0
+);
+
+CREATE TABLE tag_journal_entry (
+  journal_id integer NOT NULL REFERENCES tag_journal (journal_id) ON DELETE CASCADE,
+  entity_id text NOT NULL,
+  tag_name text NOT NULL,
+  added integer NOT NULL,
+  PRIMARY KEY (journal_id, entity_id, tag_name)
+);
+
+-- Rows that point to something that is gone can't be used anyway, so they are
+-- left behind
+INSERT INTO entity_file
+  SELECT entity_id, file_id, entity_type, broken_at, broken_reason, last_checked, check_status
+  FROM entity_file_old;
+
+INSERT INTO entity_tag SELECT tag_id, tag_name FROM entity_tag_old;
+
+INSERT INTO entity_data
+  SELECT combo_id, CAST(user_id AS TEXT), entity_id, count, created_at, last_used, updated_at
+  FROM entity_data_old
+  WHERE entity_id IN (SELECT entity_id FROM entity_file);
+
+INSERT OR IGNORE INTO entity_main
+  SELECT combo_id, CAST(tag_id AS INTEGER) FROM entity_main_old
+  WHERE combo_id IN (SELECT combo_id FROM entity_data)
+  AND CAST(tag_id AS INTEGER) IN (SELECT tag_id FROM entity_tag);
+
+INSERT INTO user_data
+  SELECT CAST(user_id AS TEXT), COALESCE(last_fixed_time, 0), deleted_at, last_export_time
+  FROM user_data_old;
+
+INSERT INTO tag_journal SELECT journal_id, user_id, action, created_at FROM tag_journal_old;
+
+INSERT INTO tag_journal_entry
+  SELECT journal_id, entity_id, tag_name, added FROM tag_journal_entry_old
+  WHERE journal_id IN (SELECT journal_id FROM tag_journal);
+
+-- Keep counting where the old tables were, ids of deleted rows are not reused
+UPDATE sqlite_sequence SET seq = MAX(seq, COALESCE(
+  (SELECT old.seq FROM sqlite_sequence AS old WHERE old.name = sqlite_sequence.name || '_old'), 0))
+  WHERE name IN ('entity_tag', 'entity_data', 'tag_journal');
+
+DROP TABLE entity_file_old;
+DROP TABLE entity_tag_old;
+DROP TABLE entity_data_old;
+DROP TABLE entity_main_old;
+DROP TABLE user_data_old;
+DROP TABLE tag_journal_old;
+DROP TABLE tag_journal_entry_old;
+
+CREATE INDEX "entity_file_last_checked" ON entity_file (
+  "last_checked" ASC
+);
+
+CREATE INDEX "entity_tag_tag_name" ON entity_tag (
+  "tag_name" ASC
+);
+
+CREATE INDEX "entity_data_user_id" ON entity_data (
+  "user_id" ASC
+);
+
+CREATE INDEX "entity_data_entity_id" ON entity_data (
+  "entity_id" ASC
+);
+
+-- Foreign keys are looked up from the child side when a parent is deleted
+CREATE INDEX "entity_main_tag_id" ON entity_main (
+  "tag_id" ASC
+);
+
+CREATE INDEX "tag_journal_user_id" ON tag_journal (
+  "user_id" ASC
+);
+
+CREATE VIEW active_entity_data AS
+SELECT * FROM entity_data
+WHERE user_id NOT IN (SELECT user_id FROM user_data WHERE deleted_at IS NOT NULL);
@@ -0,0 +1,5 @@
+-- Settings changed from the bot itself, such as the maintenance mode
+CREATE TABLE bot_setting (
+  setting_key text NOT NULL PRIMARY KEY,
+  setting_value text NOT NULL
+);
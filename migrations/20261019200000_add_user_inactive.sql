@@ -0,0 +1,3 @@
+-- Set when a message to the user failed because they blocked the bot, or their
+-- account is gone
+ALTER TABLE user_data ADD COLUMN inactive_at integer DEFAULT NULL;
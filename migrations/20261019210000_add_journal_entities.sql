@@ -0,0 +1,7 @@
+-- The entities an operation added to the library of a user, undoing the
+-- operation removes them again
+CREATE TABLE tag_journal_entity (
+  journal_id integer NOT NULL REFERENCES tag_journal (journal_id) ON DELETE CASCADE,
+  entity_id text NOT NULL,
+  PRIMARY KEY (journal_id, entity_id)
+);
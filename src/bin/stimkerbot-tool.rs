@@ -0,0 +1,290 @@
+// Works with export files without Telegram or a database, for support requests
+// and backups
+use std::{
+  collections::{HashMap, HashSet},
+  path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use sticker_bot::database::{
+  import::{
+    self,
+    types::{BotImport, ImportItem, ImportReport},
+  },
+  Entity, EntityType,
+};
+
+const USAGE: &str = "Usage:
+  stimkerbot-tool inspect <file>...
+  stimkerbot-tool merge <output> <file>...
+  stimkerbot-tool convert <input> <output>
+  stimkerbot-tool validate <file>...
+
+Reads .stimkerbot exports, archives, CSV and JSON exports, and QuickStickBot exports.
+The output format follows the extension: .stimkerbot, .csv or .json";
+
+// How many tags inspect shows
+const TOP_TAGS: usize = 20;
+
+fn main() -> Result<()> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+
+  let Some((command, paths)) = args.split_first() else {
+    println!("{}", USAGE);
+    return Ok(());
+  };
+
+  match (command.as_str(), paths) {
+    ("inspect", [_, ..]) => inspect(paths),
+    ("merge", [output, inputs @ ..]) if !inputs.is_empty() => merge(output, inputs),
+    ("convert", [input, output]) => convert(input, output),
+    ("validate", [_, ..]) => {
+      if !validate(paths)? {
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    _ => {
+      eprintln!("{}", USAGE);
+      std::process::exit(2);
+    }
+  }
+}
+
+fn read(path: &str, report: &mut ImportReport) -> Result<import::ParsedExport> {
+  let file = std::fs::read(path).with_context(|| format!("Could not read {}", path))?;
+
+  import::parse_file(&file, report).with_context(|| format!("Could not parse {}", path))
+}
+
+fn inspect(paths: &[String]) -> Result<()> {
+  for path in paths {
+    let mut report = ImportReport::default();
+    let export = read(path, &mut report)?;
+
+    println!("{}", path);
+
+    if let Some(part) = &export.part {
+      println!(
+        "  Part {} of export {}{}",
+        part.index + 1,
+        part.export_id,
+        if part.last { ", the last one" } else { "" }
+      );
+    }
+    if export.is_delta {
+      println!(
+        "  Only the changes, {} deleted entities",
+        export.deleted.len()
+      );
+    }
+
+    let mut type_counts: HashMap<String, usize> = HashMap::new();
+    let mut tag_counts: HashMap<&str, usize> = HashMap::new();
+    for item in &export.items {
+      *type_counts.entry(type_name(&item.entity_type)).or_default() += 1;
+      for tag in &item.tags {
+        *tag_counts.entry(tag).or_default() += 1;
+      }
+    }
+
+    let mut type_counts: Vec<(String, usize)> = type_counts.into_iter().collect();
+    type_counts.sort();
+    println!(
+      "  Entities: {} ({})",
+      export.items.len(),
+      type_counts
+        .iter()
+        .map(|(entity_type, count)| format!("{} {}", count, entity_type))
+        .collect::<Vec<String>>()
+        .join(", ")
+    );
+    println!(
+      "  Untagged: {}",
+      export
+        .items
+        .iter()
+        .filter(|item| item.tags.is_empty())
+        .count()
+    );
+    println!(
+      "  Uses: {}",
+      export.items.iter().map(|item| item.count).sum::<i64>()
+    );
+    println!("  Tags: {}", tag_counts.len());
+
+    let mut tag_counts: Vec<(&str, usize)> = tag_counts.into_iter().collect();
+    tag_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let widest = tag_counts
+      .iter()
+      .take(TOP_TAGS)
+      .map(|(tag, _)| tag.chars().count())
+      .max()
+      .unwrap_or_default();
+    for (tag, count) in tag_counts.iter().take(TOP_TAGS) {
+      println!("    {:width$}  {}", tag, count, width = widest);
+    }
+    if tag_counts.len() > TOP_TAGS {
+      println!("    and {} more", tag_counts.len() - TOP_TAGS);
+    }
+
+    print_skipped(&report);
+  }
+
+  Ok(())
+}
+
+// Combines exports like the merge import mode, in the order they are given.
+// Exports of only the changes overwrite what came before them.
+fn merge(output: &str, inputs: &[String]) -> Result<()> {
+  let mut report = ImportReport::default();
+  let mut merged: Vec<ImportItem> = Vec::new();
+  let mut positions: HashMap<String, usize> = HashMap::new();
+
+  for path in inputs {
+    let export = read(path, &mut report)?;
+
+    for item in export.items {
+      let Some(&position) = positions.get(&item.entity_id) else {
+        positions.insert(item.entity_id.clone(), merged.len());
+        merged.push(item);
+        continue;
+      };
+
+      let existing = &mut merged[position];
+      if export.is_delta {
+        *existing = item;
+        continue;
+      }
+
+      for tag in item.tags {
+        if !existing.tags.contains(&tag) {
+          existing.tags.push(tag);
+        }
+      }
+      existing.count = existing.count.max(item.count);
+      existing.last_used = existing.last_used.max(item.last_used);
+      existing.created_at = match (existing.created_at, item.created_at) {
+        (0, created_at) | (created_at, 0) => created_at,
+        (a, b) => a.min(b),
+      };
+      // The newest export has the most likely working file id
+      existing.file_id = item.file_id;
+      existing.entity_type = item.entity_type;
+    }
+
+    let deleted: HashSet<String> = export.deleted.into_iter().collect();
+    if !deleted.is_empty() {
+      merged.retain(|item| !deleted.contains(&item.entity_id));
+      positions = merged
+        .iter()
+        .enumerate()
+        .map(|(position, item)| (item.entity_id.clone(), position))
+        .collect();
+    }
+  }
+
+  print_skipped(&report);
+  write(output, &merged)?;
+
+  println!("Merged {} entities into {}", merged.len(), output);
+
+  Ok(())
+}
+
+fn convert(input: &str, output: &str) -> Result<()> {
+  let mut report = ImportReport::default();
+  let export = read(input, &mut report)?;
+
+  if export.is_delta {
+    eprintln!("Warning: {} only contains the changes of an export", input);
+  }
+  if export.part.is_some() {
+    eprintln!("Warning: {} is only one part of an export", input);
+  }
+
+  print_skipped(&report);
+  write(output, &export.items)?;
+
+  println!("Converted {} entities into {}", export.items.len(), output);
+
+  Ok(())
+}
+
+// Returns whether every file id could be decoded
+fn validate(paths: &[String]) -> Result<bool> {
+  let mut valid = true;
+
+  for path in paths {
+    let mut report = ImportReport::default();
+    let export = read(path, &mut report)?;
+
+    let mut invalid = 0;
+    for item in &export.items {
+      match Entity::file_id_to_type(&item.file_id) {
+        Ok(entity_type) if entity_type == item.entity_type => {}
+        Ok(entity_type) => {
+          invalid += 1;
+          println!(
+            "  {}: is a {}, but the export says {}",
+            item.entity_id,
+            type_name(&entity_type),
+            type_name(&item.entity_type)
+          );
+        }
+        // The bot imports these anyway, Telegram decides if they still work
+        Err(e) => println!(
+          "  {}: could not read the file id, it is imported as it is: {}",
+          item.entity_id, e
+        ),
+      }
+    }
+
+    // Items that could not be read at all are invalid too
+    print_skipped(&report);
+    invalid += report.skipped.len();
+
+    println!(
+      "{}: {} of {} entities are valid",
+      path,
+      export.items.len() + report.skipped.len() - invalid,
+      export.items.len() + report.skipped.len()
+    );
+
+    valid &= invalid == 0;
+  }
+
+  Ok(valid)
+}
+
+fn write(path: &str, items: &BotImport) -> Result<()> {
+  let extension = Path::new(path)
+    .extension()
+    .and_then(|extension| extension.to_str())
+    .unwrap_or_default();
+
+  let data = match extension {
+    // The user is unknown offline, the bot does not check it on import
+    "stimkerbot" => import::write_export(items)?,
+    "csv" => import::to_csv(items)?,
+    "json" => import::to_json(items)?,
+    _ => bail!("Unknown output format, use .stimkerbot, .csv or .json"),
+  };
+
+  std::fs::write(path, data).with_context(|| format!("Could not write {}", path))
+}
+
+fn print_skipped(report: &ImportReport) {
+  if report.skipped.is_empty() {
+    return;
+  }
+
+  println!("  Skipped {} items:", report.skipped.len());
+  for skipped in &report.skipped {
+    println!("    {}: {}", skipped.item, skipped.reason);
+  }
+}
+
+fn type_name(entity_type: &EntityType) -> String {
+  format!("{:?}", entity_type).to_lowercase()
+}
@@ -1,8 +1,9 @@
-use anyhow::{bail, Result};
-use base64::{engine::general_purpose, Engine as _};
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use teloxide::types::*;
 
+use super::file_id::DecodedFileId;
+
 #[derive(Clone, Debug, PartialEq, PartialOrd, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(rename_all = "lowercase")]
 pub enum EntityType {
@@ -21,6 +22,16 @@ pub struct Entity {
   pub entity_type: EntityType,
 }
 
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct UntaggedEntity {
+  pub entity_id: String,
+  pub file_id: Option<String>,
+  pub entity_type: Option<EntityType>,
+  pub count: i64,
+  pub last_used: i64,
+  pub created_at: i64,
+}
+
 #[derive(Debug, sqlx::FromRow)]
 pub struct EntityStat {
   #[allow(dead_code)]
@@ -35,20 +46,8 @@ pub struct EntityStat {
 impl Entity {
   pub fn file_id_to_type<T: Into<String>>(file_id: T) -> Result<EntityType> {
     let file_id: String = file_id.into();
-    // decode base64
-    let file_id_decoded = general_purpose::URL_SAFE_NO_PAD.decode(file_id.as_bytes())?;
-    let rle_decoded = rle_decode(&file_id_decoded);
-    let mut type_num: u32 = u32::from_le_bytes(rle_decoded[0..4].try_into()?);
-
-    type_num &= !(1 << 24 | 1 << 25);
 
-    match type_num {
-      2 => Ok(EntityType::Photo),
-      4 => Ok(EntityType::Video),
-      8 => Ok(EntityType::Sticker),
-      10 => Ok(EntityType::Animation),
-      _ => bail!(format!("Unknown type: {}", type_num)),
-    }
+    DecodedFileId::decode(&file_id)?.entity_type()
   }
 
   pub fn to_inline(&self) -> InlineQueryResult {
@@ -110,24 +109,3 @@ impl Entity {
     }
   }
 }
-
-fn rle_decode(input: &[u8]) -> Vec<u8> {
-  let mut output = Vec::<u8>::new();
-  let mut last_is_zero = false;
-
-  for byte in input {
-    if last_is_zero {
-      for _ in 0..(*byte - 1) {
-        output.push(0x00);
-      }
-      last_is_zero = false;
-    } else {
-      output.push(*byte);
-      if *byte == 0x00 {
-        last_is_zero = true;
-      }
-    }
-  }
-
-  output
-}
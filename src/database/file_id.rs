@@ -0,0 +1,559 @@
+// Bot API file ids are a serialized TDLib remote file location, run length
+// encoded and then base64 encoded. The layout follows TDLib's FileId and
+// PhotoSizeSource serialization.
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+
+use super::EntityType;
+
+const WEB_LOCATION_FLAG: u32 = 1 << 24;
+const FILE_REFERENCE_FLAG: u32 = 1 << 25;
+
+// Newer versions might change the layout
+const MAX_VERSION: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+  Thumbnail,
+  ProfilePhoto,
+  Photo,
+  VoiceNote,
+  Video,
+  Document,
+  Encrypted,
+  Temp,
+  Sticker,
+  Audio,
+  Animation,
+  EncryptedThumbnail,
+  Wallpaper,
+  VideoNote,
+  SecureDecrypted,
+  SecureEncrypted,
+  Background,
+  DocumentAsFile,
+}
+
+impl FileType {
+  fn from_id(type_id: u32) -> Result<Self> {
+    Ok(match type_id {
+      0 => FileType::Thumbnail,
+      1 => FileType::ProfilePhoto,
+      2 => FileType::Photo,
+      3 => FileType::VoiceNote,
+      4 => FileType::Video,
+      5 => FileType::Document,
+      6 => FileType::Encrypted,
+      7 => FileType::Temp,
+      8 => FileType::Sticker,
+      9 => FileType::Audio,
+      10 => FileType::Animation,
+      11 => FileType::EncryptedThumbnail,
+      12 => FileType::Wallpaper,
+      13 => FileType::VideoNote,
+      14 => FileType::SecureDecrypted,
+      15 => FileType::SecureEncrypted,
+      16 => FileType::Background,
+      17 => FileType::DocumentAsFile,
+      _ => bail!("Unknown type: {}", type_id),
+    })
+  }
+
+  // Photos are stored by size, everything else as a document
+  fn is_photo(&self) -> bool {
+    matches!(
+      self,
+      FileType::Thumbnail
+        | FileType::ProfilePhoto
+        | FileType::Photo
+        | FileType::EncryptedThumbnail
+        | FileType::Wallpaper
+    )
+  }
+}
+
+// Which size of which photo a photo file id points to
+#[derive(Clone, Debug, PartialEq)]
+pub enum PhotoSizeSource {
+  Legacy {
+    secret: i64,
+  },
+  Thumbnail {
+    file_type: i32,
+    thumbnail_type: i32,
+  },
+  ChatPhoto {
+    big: bool,
+    chat_id: i64,
+    chat_access_hash: i64,
+  },
+  StickerSetThumbnail {
+    sticker_set_id: i64,
+    sticker_set_access_hash: i64,
+    version: Option<i32>,
+  },
+  FullLegacy {
+    volume_id: i64,
+    secret: i64,
+    local_id: i32,
+  },
+  ChatPhotoLegacy {
+    big: bool,
+    chat_id: i64,
+    chat_access_hash: i64,
+    volume_id: i64,
+    local_id: i32,
+  },
+  StickerSetThumbnailLegacy {
+    sticker_set_id: i64,
+    sticker_set_access_hash: i64,
+    volume_id: i64,
+    local_id: i32,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileLocation {
+  Web {
+    url: String,
+    access_hash: i64,
+  },
+  Photo {
+    media_id: i64,
+    access_hash: i64,
+    source: PhotoSizeSource,
+  },
+  Document {
+    media_id: i64,
+    access_hash: i64,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedFileId {
+  pub version: u8,
+  // Only version 4 and up have a sub version
+  pub sub_version: Option<u8>,
+  pub file_type: FileType,
+  pub dc_id: i32,
+  pub file_reference: Option<Vec<u8>>,
+  pub location: FileLocation,
+}
+
+impl DecodedFileId {
+  pub fn decode(file_id: &str) -> Result<Self> {
+    let decoded = general_purpose::URL_SAFE_NO_PAD
+      .decode(file_id.as_bytes())
+      .context("Invalid base64")?;
+    let data = rle_decode(&decoded);
+
+    let Some((&version, data)) = data.split_last() else {
+      bail!("File id is empty");
+    };
+    if version > MAX_VERSION {
+      bail!("Unsupported file id version: {}", version);
+    }
+    let (sub_version, data) = if version >= 4 {
+      let Some((&sub_version, data)) = data.split_last() else {
+        bail!("File id is too short");
+      };
+      (Some(sub_version), data)
+    } else {
+      (None, data)
+    };
+
+    let mut reader = Reader { data, position: 0 };
+
+    let type_id = reader.u32()?;
+    let file_type = FileType::from_id(type_id & !(WEB_LOCATION_FLAG | FILE_REFERENCE_FLAG))?;
+    let dc_id = reader.i32()?;
+
+    let file_reference = if type_id & FILE_REFERENCE_FLAG != 0 {
+      Some(reader.bytes()?)
+    } else {
+      None
+    };
+
+    let location = if type_id & WEB_LOCATION_FLAG != 0 {
+      FileLocation::Web {
+        url: String::from_utf8(reader.bytes()?).context("Invalid web location")?,
+        access_hash: reader.i64()?,
+      }
+    } else if file_type.is_photo() {
+      let media_id = reader.i64()?;
+      let access_hash = reader.i64()?;
+      let source = if version < 4 {
+        // Before version 4 everything was stored by volume
+        read_legacy_photo_source(&mut reader)?
+      } else {
+        read_photo_source(&mut reader)?
+      };
+
+      FileLocation::Photo {
+        media_id,
+        access_hash,
+        source,
+      }
+    } else {
+      FileLocation::Document {
+        media_id: reader.i64()?,
+        access_hash: reader.i64()?,
+      }
+    };
+
+    // Anything left over is ignored, like TDLib does for newer sub versions
+    Ok(DecodedFileId {
+      version,
+      sub_version,
+      file_type,
+      dc_id,
+      file_reference,
+      location,
+    })
+  }
+
+  // The type of entity this bot can store, if any
+  pub fn entity_type(&self) -> Result<EntityType> {
+    match self.file_type {
+      FileType::Photo => Ok(EntityType::Photo),
+      FileType::Video => Ok(EntityType::Video),
+      FileType::Sticker => Ok(EntityType::Sticker),
+      FileType::Animation => Ok(EntityType::Animation),
+      file_type => bail!("Unsupported type: {:?}", file_type),
+    }
+  }
+
+  // A short human readable summary, e.g. for /fix
+  pub fn describe(&self) -> String {
+    let version = match self.sub_version {
+      Some(sub_version) => format!("{}.{}", self.version, sub_version),
+      None => self.version.to_string(),
+    };
+
+    let mut description = format!(
+      "{:?} on DC {}, version {}",
+      self.file_type, self.dc_id, version
+    );
+    if self.file_reference.is_none() {
+      description += ", without a file reference";
+    }
+    if let FileLocation::Web { url, .. } = &self.location {
+      description += &format!(", from {}", url);
+    }
+
+    description
+  }
+}
+
+fn read_photo_source(reader: &mut Reader) -> Result<PhotoSizeSource> {
+  Ok(match reader.i32()? {
+    0 => PhotoSizeSource::Legacy {
+      secret: reader.i64()?,
+    },
+    1 => PhotoSizeSource::Thumbnail {
+      file_type: reader.i32()?,
+      thumbnail_type: reader.i32()?,
+    },
+    source @ (2 | 3) => PhotoSizeSource::ChatPhoto {
+      big: source == 3,
+      chat_id: reader.i64()?,
+      chat_access_hash: reader.i64()?,
+    },
+    4 => PhotoSizeSource::StickerSetThumbnail {
+      sticker_set_id: reader.i64()?,
+      sticker_set_access_hash: reader.i64()?,
+      version: None,
+    },
+    5 => PhotoSizeSource::FullLegacy {
+      volume_id: reader.i64()?,
+      secret: reader.i64()?,
+      local_id: reader.i32()?,
+    },
+    source @ (6 | 7) => PhotoSizeSource::ChatPhotoLegacy {
+      big: source == 7,
+      chat_id: reader.i64()?,
+      chat_access_hash: reader.i64()?,
+      volume_id: reader.i64()?,
+      local_id: reader.i32()?,
+    },
+    8 => PhotoSizeSource::StickerSetThumbnailLegacy {
+      sticker_set_id: reader.i64()?,
+      sticker_set_access_hash: reader.i64()?,
+      volume_id: reader.i64()?,
+      local_id: reader.i32()?,
+    },
+    9 => PhotoSizeSource::StickerSetThumbnail {
+      sticker_set_id: reader.i64()?,
+      sticker_set_access_hash: reader.i64()?,
+      version: Some(reader.i32()?),
+    },
+    source => bail!("Unknown photo size source: {}", source),
+  })
+}
+
+fn read_legacy_photo_source(reader: &mut Reader) -> Result<PhotoSizeSource> {
+  Ok(PhotoSizeSource::FullLegacy {
+    volume_id: reader.i64()?,
+    secret: reader.i64()?,
+    local_id: reader.i32()?,
+  })
+}
+
+// Reads little endian values, and TL serialized byte strings
+struct Reader<'a> {
+  data: &'a [u8],
+  position: usize,
+}
+
+impl Reader<'_> {
+  fn take(&mut self, length: usize) -> Result<&[u8]> {
+    let Some(bytes) = self.data.get(self.position..self.position + length) else {
+      bail!("File id is too short");
+    };
+    self.position += length;
+
+    Ok(bytes)
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+  }
+
+  fn i32(&mut self) -> Result<i32> {
+    Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+  }
+
+  fn i64(&mut self) -> Result<i64> {
+    Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+  }
+
+  // A length prefix of one byte, or 254 and three bytes, padded to 4 bytes
+  fn bytes(&mut self) -> Result<Vec<u8>> {
+    let (length, header_length) = match self.take(1)?[0] {
+      254 => {
+        let length = self.take(3)?;
+        (
+          u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize,
+          4,
+        )
+      }
+      255 => bail!("Invalid byte string length"),
+      length => (length as usize, 1),
+    };
+
+    let bytes = self.take(length)?.to_vec();
+    self.take((4 - (header_length + length) % 4) % 4)?;
+
+    Ok(bytes)
+  }
+}
+
+// A zero byte is followed by how many zero bytes there are
+fn rle_decode(input: &[u8]) -> Vec<u8> {
+  let mut output = Vec::<u8>::new();
+  let mut last_is_zero = false;
+
+  for byte in input {
+    if last_is_zero {
+      for _ in 0..byte.saturating_sub(1) {
+        output.push(0x00);
+      }
+      last_is_zero = false;
+    } else {
+      output.push(*byte);
+      if *byte == 0x00 {
+        last_is_zero = true;
+      }
+    }
+  }
+
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Sticker, animation, video and photo file ids from public Bot API examples,
+  // the older ones from before file references existed
+
+  #[test]
+  fn sticker() {
+    let file_id = DecodedFileId::decode(
+      "CAACAgIAAxkBAAIBB2B4nQ1W7KNcZ0VMXWE0P_EMKTW5AAIBAQACVp29CiqXDJ0IUyEOHwQ",
+    )
+    .unwrap();
+
+    assert_eq!(
+      file_id,
+      DecodedFileId {
+        version: 4,
+        sub_version: Some(31),
+        file_type: FileType::Sticker,
+        dc_id: 2,
+        file_reference: Some(vec![
+          1, 0, 0, 1, 7, 96, 120, 157, 13, 86, 236, 163, 92, 103, 69, 76, 93, 97, 52, 63, 241, 12,
+          41, 53, 185
+        ]),
+        location: FileLocation::Document {
+          media_id: 773947703670341889,
+          access_hash: 1018186287222134570,
+        },
+      }
+    );
+    assert_eq!(file_id.entity_type().unwrap(), EntityType::Sticker);
+    assert_eq!(file_id.describe(), "Sticker on DC 2, version 4.31");
+  }
+
+  #[test]
+  fn photo() {
+    let file_id = DecodedFileId::decode(
+      "AgACAgIAAxkBAAIBCmB4nRJh9dSQcQy3AbT1ZnlFvGFqAAJusjEbVp29Cqv7d6nBmXfRAQADAgADeAADHwQ",
+    )
+    .unwrap();
+
+    assert_eq!(file_id.version, 4);
+    assert_eq!(file_id.sub_version, Some(31));
+    assert_eq!(file_id.file_type, FileType::Photo);
+    assert_eq!(file_id.dc_id, 2);
+    assert_eq!(file_id.file_reference.as_ref().map(Vec::len), Some(25));
+    assert_eq!(
+      file_id.location,
+      FileLocation::Photo {
+        media_id: 773947704126583406,
+        access_hash: -3353042340503094357,
+        source: PhotoSizeSource::Thumbnail {
+          file_type: 2,
+          thumbnail_type: 'x' as i32,
+        },
+      }
+    );
+    assert_eq!(file_id.entity_type().unwrap(), EntityType::Photo);
+  }
+
+  #[test]
+  fn version_2() {
+    let sticker = DecodedFileId::decode("CAADAgADQAADyIsGAAE7MpzFPFQX5QI").unwrap();
+    assert_eq!(
+      sticker,
+      DecodedFileId {
+        version: 2,
+        sub_version: None,
+        file_type: FileType::Sticker,
+        dc_id: 2,
+        file_reference: None,
+        location: FileLocation::Document {
+          media_id: 1842540969984064,
+          access_hash: -1938988494569590213,
+        },
+      }
+    );
+    assert_eq!(
+      sticker.describe(),
+      "Sticker on DC 2, version 2, without a file reference"
+    );
+
+    let animation = DecodedFileId::decode("CgADBAADiaMAAnQaZAdI9EUzZbVYswI").unwrap();
+    assert_eq!(animation.version, 2);
+    assert_eq!(animation.dc_id, 4);
+    assert_eq!(animation.file_reference, None);
+    assert_eq!(
+      animation.location,
+      FileLocation::Document {
+        media_id: 532579741455131529,
+        access_hash: -5523465496713366456,
+      }
+    );
+    assert_eq!(animation.entity_type().unwrap(), EntityType::Animation);
+
+    let video = DecodedFileId::decode("BAADBAADBQADcJtxUBoeYS0xlRzSAg").unwrap();
+    assert_eq!(video.version, 2);
+    assert_eq!(video.dc_id, 4);
+    assert_eq!(
+      video.location,
+      FileLocation::Document {
+        media_id: 5796585100741181445,
+        access_hash: -3306603987949511142,
+      }
+    );
+    assert_eq!(video.entity_type().unwrap(), EntityType::Video);
+  }
+
+  // The ids below are synthetic, built byte by byte for cases that are hard to
+  // come by from a bot: a photo from before photo size sources, a sticker set
+  // thumbnail and a web file
+
+  #[test]
+  fn legacy_photo() {
+    let photo = DecodedFileId::decode("AgADAwADFQAHFgAHZAAHyAAHLAEAAgI").unwrap();
+    assert_eq!(photo.version, 2);
+    assert_eq!(
+      photo.location,
+      FileLocation::Photo {
+        media_id: 21,
+        access_hash: 22,
+        source: PhotoSizeSource::FullLegacy {
+          volume_id: 100,
+          secret: 200,
+          local_id: 300,
+        },
+      }
+    );
+  }
+
+  #[test]
+  fn sticker_set_thumbnail() {
+    let file_id = DecodedFileId::decode("AAQCAAMLAAcMAAcJAANFDAAGngoABgcAAy8E").unwrap();
+
+    assert_eq!(file_id.file_type, FileType::Thumbnail);
+    assert_eq!(
+      file_id.location,
+      FileLocation::Photo {
+        media_id: 11,
+        access_hash: 12,
+        source: PhotoSizeSource::StickerSetThumbnail {
+          sticker_set_id: 3141,
+          sticker_set_access_hash: 2718,
+          version: Some(7),
+        },
+      }
+    );
+    assert!(file_id.entity_type().is_err());
+  }
+
+  #[test]
+  fn web_location() {
+    let file_id =
+      DecodedFileId::decode("AgACAQAEHGh0dHBzOi8vZXhhbXBsZS5jb20vY2F0LndlYnAAA2MABy8E").unwrap();
+
+    assert_eq!(
+      file_id.location,
+      FileLocation::Web {
+        url: "https://example.com/cat.webp".to_string(),
+        access_hash: 99,
+      }
+    );
+    assert!(file_id.describe().contains("https://example.com/cat.webp"));
+  }
+
+  #[test]
+  fn invalid() {
+    // Not base64
+    assert!(DecodedFileId::decode("not a file id!").is_err());
+    // Cut off in the middle of the location
+    assert!(DecodedFileId::decode("CAACAgIAAxkBAAIBB2B4nQ1W7KNcZ0VMXWE0P_EMKTW5AAIB").is_err());
+    // Unknown type
+    assert!(DecodedFileId::decode("YwADAgADAQAHAgAHLwQ").is_err());
+    // Only the type
+    assert!(DecodedFileId::decode("CAADAQ").is_err());
+    assert!(DecodedFileId::decode("").is_err());
+  }
+
+  #[test]
+  fn rle() {
+    assert_eq!(rle_decode(&[1, 0, 3, 2]), vec![1, 0, 0, 0, 2]);
+    assert_eq!(rle_decode(&[0, 1]), vec![0]);
+    // A broken run does not panic
+    assert_eq!(rle_decode(&[0, 0]), vec![0]);
+  }
+}
@@ -0,0 +1,642 @@
+use std::{
+  collections::HashMap,
+  future::Future,
+  io::{Read, Write},
+  path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use teloxide::{
+  net::Download,
+  payloads::{SendAnimationSetters as _, SendPhotoSetters as _, SendVideoSetters as _},
+  requests::Requester,
+  types::{ChatId, InputFile},
+};
+
+use crate::{
+  database::{entities::EntityType, queries},
+  types::{BotType, DbConn},
+  util::{self, retry_after},
+};
+
+use super::{
+  export::export_batch,
+  import::{parse_export, ParsedExport},
+  progress::Progress,
+  types::{
+    BotImport, ExportEnvelope, ExportHeader, ExportPart, ImportItem, ImportReport,
+    FULL_EXPORT_FORMAT_VERSION,
+  },
+};
+
+// The tag data inside an archive, a normal export
+const MANIFEST_NAME: &str = "export.json";
+const MEDIA_DIRECTORY: &str = "media";
+
+// An archive is a gzipped tar file, which has "ustar" at this offset
+pub fn is_archive(file: &[u8]) -> bool {
+  let mut start = [0u8; 262];
+  GzDecoder::new(file).read_exact(&mut start).is_ok() && &start[257..262] == b"ustar"
+}
+
+// Exports everything together with the media itself, so it can be imported into
+// a bot with a different token. Returns the entities whose media could not be
+// downloaded, those are still exported with their old file id.
+pub async fn export_archive<F, Fut>(
+  db: &DbConn,
+  bot: &BotType,
+  user_id: String,
+  progress: &impl Progress,
+  part_size: usize,
+  mut send_file: F,
+) -> Result<Vec<String>>
+where
+  F: FnMut(Vec<u8>, String) -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  log::debug!("Exporting an archive for user {}", user_id);
+
+  let secret = queries::get_user_hash_secret(db).await?;
+  let mut writer = ArchiveWriter::new(util::user_hash(&secret, &user_id), part_size);
+  let mut missing_media: Vec<String> = Vec::new();
+  let mut item_count = 0;
+  let mut after = String::new();
+
+  // Downloading takes a while, so the entities are read in batches
+  loop {
+    let items = export_batch(db, &user_id, None, &after).await?;
+    let Some(last) = items.last() else {
+      break;
+    };
+    after = last.entity_id.clone();
+
+    progress.check_cancelled().await?;
+    progress
+      .report(format!(
+        "Downloading files {}-{}",
+        item_count + 1,
+        item_count + items.len()
+      ))
+      .await;
+    item_count += items.len();
+
+    for item in items {
+      let media = match download_media(bot, &item.file_id, part_size).await {
+        Ok(media) => Some(media),
+        Err(e) => {
+          log::warn!(
+            "Could not download {:?} for the archive: {:?}",
+            item.file_id,
+            e
+          );
+          missing_media.push(item.entity_id.clone());
+          None
+        }
+      };
+
+      if let Some((data, file_name)) = writer.add(item, media)? {
+        send_file(data, file_name).await?;
+      }
+    }
+  }
+
+  let exported_at = writer.exported_at;
+  let (data, file_name) = writer.finish()?;
+  send_file(data, file_name).await?;
+
+  queries::set_last_export_time(db, user_id, exported_at).await?;
+
+  Ok(missing_media)
+}
+
+async fn download_media(
+  bot: &BotType,
+  file_id: &str,
+  max_size: usize,
+) -> Result<(String, Vec<u8>)> {
+  let file = retry_after(|| bot.get_file(file_id)).await?;
+
+  if file.size as usize > max_size {
+    bail!("File is too large: {} bytes", file.size);
+  }
+
+  let mut data = Vec::new();
+  bot.download_file(&file.path, &mut data).await?;
+
+  let extension = Path::new(&file.path)
+    .extension()
+    .and_then(|extension| extension.to_str())
+    .unwrap_or("bin")
+    .to_string();
+
+  Ok((extension, data))
+}
+
+struct ArchiveWriter {
+  user_hash: String,
+  export_id: String,
+  exported_at: i64,
+  part_size: usize,
+  part_index: u32,
+  builder: tar::Builder<GzEncoder<Vec<u8>>>,
+  items: BotImport,
+  size: usize,
+}
+
+impl ArchiveWriter {
+  fn new(user_hash: String, part_size: usize) -> Self {
+    ArchiveWriter {
+      user_hash,
+      export_id: util::random_id(),
+      exported_at: util::get_unix(),
+      part_size,
+      part_index: 0,
+      builder: new_builder(),
+      items: Vec::new(),
+      size: 0,
+    }
+  }
+
+  // Returns the previous part if this item did not fit in it anymore
+  fn add(
+    &mut self,
+    item: ImportItem,
+    media: Option<(String, Vec<u8>)>,
+  ) -> Result<Option<(Vec<u8>, String)>> {
+    // Media is already compressed, so the raw size is a good estimate. Every
+    // item also takes up some space in the manifest and a tar header.
+    let item_size = 1024 + media.as_ref().map(|(_, data)| data.len()).unwrap_or(0);
+
+    let finished_part = if !self.items.is_empty() && self.size + item_size > self.part_size {
+      let part = self.finish_part(false)?;
+      self.part_index += 1;
+      Some(part)
+    } else {
+      None
+    };
+
+    if let Some((extension, data)) = media {
+      let path = format!("{}/{}.{}", MEDIA_DIRECTORY, item.entity_id, extension);
+      append_file(&mut self.builder, &path, &data)?;
+    }
+
+    self.size += item_size;
+    self.items.push(item);
+
+    Ok(finished_part)
+  }
+
+  fn finish(mut self) -> Result<(Vec<u8>, String)> {
+    self.finish_part(true)
+  }
+
+  fn finish_part(&mut self, last: bool) -> Result<(Vec<u8>, String)> {
+    let items = std::mem::take(&mut self.items);
+    let split = !last || self.part_index > 0;
+
+    let envelope = ExportEnvelope {
+      header: ExportHeader {
+        format_version: FULL_EXPORT_FORMAT_VERSION,
+        bot_version: env!("CARGO_PKG_VERSION").to_string(),
+        exported_at: self.exported_at,
+        user_hash: self.user_hash.clone(),
+        item_count: items.len(),
+        checksum: util::sha256_hex(serde_json::to_vec(&items)?),
+        part: split.then(|| ExportPart {
+          export_id: self.export_id.clone(),
+          index: self.part_index,
+          last,
+        }),
+        since: None,
+      },
+      items,
+      deleted: Vec::new(),
+    };
+
+    log::debug!("Finished archive part {:?}", envelope.header);
+
+    append_file(
+      &mut self.builder,
+      MANIFEST_NAME,
+      &serde_json::to_vec(&envelope)?,
+    )?;
+
+    let builder = std::mem::replace(&mut self.builder, new_builder());
+    let data = builder.into_inner()?.finish()?;
+    self.size = 0;
+
+    let file_name = if split {
+      format!("archive-part{}.stimkerarchive", self.part_index + 1)
+    } else {
+      "archive.stimkerarchive".to_string()
+    };
+
+    Ok((data, file_name))
+  }
+}
+
+fn new_builder() -> tar::Builder<GzEncoder<Vec<u8>>> {
+  // Media is already compressed, trying harder only costs time
+  tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()))
+}
+
+fn append_file<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> Result<()> {
+  let mut header = tar::Header::new_gnu();
+  header.set_size(data.len() as u64);
+  header.set_mode(0o644);
+  header.set_mtime((util::get_unix() / 1000) as u64);
+  header.set_cksum();
+  builder.append_data(&mut header, path, data)?;
+
+  Ok(())
+}
+
+struct ArchiveContent {
+  export: ParsedExport,
+  // Entity id to file extension and data
+  media: HashMap<String, (String, Vec<u8>)>,
+}
+
+fn read_archive(file: &[u8], report: &mut ImportReport) -> Result<ArchiveContent> {
+  let mut archive = tar::Archive::new(GzDecoder::new(file));
+  let mut export: Option<ParsedExport> = None;
+  let mut media = HashMap::new();
+
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let path = entry.path()?.to_string_lossy().to_string();
+
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+
+    if path == MANIFEST_NAME {
+      export = Some(parse_export(&data, report)?);
+    } else if let Some(file_name) = path.strip_prefix(&format!("{}/", MEDIA_DIRECTORY)) {
+      if let Some((entity_id, extension)) = file_name.rsplit_once('.') {
+        media.insert(entity_id.to_string(), (extension.to_string(), data));
+      }
+    }
+  }
+
+  Ok(ArchiveContent {
+    export: export.context("This archive has no export.json")?,
+    media,
+  })
+}
+
+// Only the tag data of an archive, without uploading its media
+pub fn read_archive_export(file: &[u8], report: &mut ImportReport) -> Result<ParsedExport> {
+  Ok(read_archive(file, report)?.export)
+}
+
+// Uploads the media of one archive (part) again, to get file ids that work for
+// this bot. The entity ids are kept, as they are the same for every bot, so the
+// stats and tags keep working for what the user already sends. The returned
+// export is imported with import like any other.
+pub async fn upload_archive_media(
+  db: &DbConn,
+  bot: &BotType,
+  chat_id: ChatId,
+  progress: &impl Progress,
+  file: &[u8],
+  dry_run: bool,
+  report: &mut ImportReport,
+) -> Result<ParsedExport> {
+  let ArchiveContent {
+    mut export,
+    mut media,
+  } = read_archive(file, report)?;
+
+  // Uploading is slow and visible, a dry run only needs the tag data
+  if dry_run {
+    return Ok(export);
+  }
+
+  let items = std::mem::take(&mut export.items);
+  let item_count = items.len();
+
+  for (index, mut item) in items.into_iter().enumerate() {
+    if index % 50 == 0 {
+      progress.check_cancelled().await?;
+      progress
+        .report(format!(
+          "Uploading files {}-{} of {}",
+          index + 1,
+          (index + 50).min(item_count),
+          item_count
+        ))
+        .await;
+    }
+
+    // Someone already sent this to this bot, no need to upload it again
+    if let Some(file_id) = queries::get_file_id(db, item.entity_id.clone()).await? {
+      item.file_id = file_id;
+    } else if let Some((extension, data)) = media.remove(&item.entity_id) {
+      let file_name = format!("{}.{}", item.entity_id, extension);
+      match upload_media(bot, chat_id, &item.entity_type, data, file_name).await {
+        Ok((file_id, entity_id)) => {
+          // Telegram converts some files when they are uploaded, the tags
+          // and stats then belong to the file it made
+          if entity_id != item.entity_id {
+            log::info!(
+              "Uploading {:?} turned it into {:?}",
+              item.entity_id,
+              entity_id
+            );
+            report.remapped_entities += 1;
+            item.entity_id = entity_id;
+          }
+          item.file_id = file_id;
+        }
+        Err(e) => {
+          log::warn!("Could not upload {:?}: {:?}", item.entity_id, e);
+          report.skip(&item.entity_id, format!("Could not upload the file: {}", e));
+          continue;
+        }
+      }
+    }
+    // Without media the old file id is kept, which only works if the archive
+    // came from this bot. /fix quarantines it otherwise.
+
+    export.items.push(item);
+  }
+
+  Ok(export)
+}
+
+// Sends the media to the user, and removes the message right away, the file id
+// and unique id are all that is needed
+pub(super) async fn upload_media(
+  bot: &BotType,
+  chat_id: ChatId,
+  entity_type: &EntityType,
+  data: Vec<u8>,
+  file_name: String,
+) -> Result<(String, String)> {
+  let file = InputFile::memory(data).file_name(file_name);
+
+  let message = match entity_type {
+    EntityType::Sticker => retry_after(|| bot.send_sticker(chat_id, file.clone())).await?,
+    EntityType::Animation => {
+      retry_after(|| {
+        bot
+          .send_animation(chat_id, file.clone())
+          .disable_notification(true)
+      })
+      .await?
+    }
+    EntityType::Photo => {
+      retry_after(|| {
+        bot
+          .send_photo(chat_id, file.clone())
+          .disable_notification(true)
+      })
+      .await?
+    }
+    EntityType::Video => {
+      retry_after(|| {
+        bot
+          .send_video(chat_id, file.clone())
+          .disable_notification(true)
+      })
+      .await?
+    }
+  };
+
+  let file = match entity_type {
+    EntityType::Sticker => message.sticker().map(|sticker| &sticker.file),
+    EntityType::Animation => message.animation().map(|animation| &animation.file),
+    // The same size as when a photo is sent to the bot
+    EntityType::Photo => message
+      .photo()
+      .and_then(|photo| photo.first())
+      .map(|photo| &photo.file),
+    EntityType::Video => message.video().map(|video| &video.file),
+  };
+  let file_ids = file.map(|file| (file.id.clone(), file.unique_id.clone()));
+
+  if let Err(e) = bot.delete_message(chat_id, message.id).await {
+    log::debug!("Could not remove the uploaded file: {:?}", e);
+  }
+
+  file_ids.context("Telegram turned this into a different kind of file")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+  };
+
+  use serde_json::json;
+  use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+  use teloxide::{requests::RequesterExt, types::ParseMode, Bot};
+
+  use super::*;
+
+  // Answers like the Bot API would, with the file name before the extension as
+  // the unique id of an uploaded sticker. Returns the url and the methods called.
+  fn stand_in_api(converted: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let calls = Arc::new(Mutex::new(Vec::new()));
+
+    let methods = calls.clone();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+          let mut line = String::new();
+          reader.read_line(&mut line).unwrap();
+          if line.trim().is_empty() {
+            break;
+          }
+          if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+              content_length = value.trim().parse().unwrap();
+            }
+          }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let body = String::from_utf8_lossy(&body);
+
+        let path = request_line.split(' ').nth(1).unwrap_or_default();
+        let method = path.rsplit('/').next().unwrap_or_default().to_lowercase();
+        methods.lock().unwrap().push(method.clone());
+
+        let chat = json!({"id": 1, "type": "private", "first_name": "User"});
+        let result = match method.as_str() {
+          "sendsticker" => {
+            let file_name = body
+              .split("filename=\"")
+              .nth(1)
+              .and_then(|rest| rest.split('.').next())
+              .unwrap_or_default();
+            let unique_id = if file_name == converted {
+              "AgADconverted"
+            } else {
+              file_name
+            };
+            json!({
+              "message_id": 2, "date": 0, "chat": chat,
+              "sticker": {
+                "file_id": format!("new-{}", unique_id), "file_unique_id": unique_id,
+                "type": "regular", "width": 512, "height": 512,
+                "is_animated": false, "is_video": false
+              }
+            })
+          }
+          "editmessagetext" => json!({"message_id": 1, "date": 0, "chat": chat, "text": ""}),
+          _ => json!(true),
+        };
+
+        let response = json!({"ok": true, "result": result}).to_string();
+        write!(
+          stream,
+          "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+          response.len(),
+          response
+        )
+        .unwrap();
+      }
+    });
+
+    (url, calls)
+  }
+
+  // Never cancelled and starts fresh, like a job that was just claimed
+  struct NoProgress;
+
+  impl Progress for NoProgress {
+    async fn report(&self, _text: String) {}
+
+    async fn is_cancelled(&self) -> Result<bool> {
+      Ok(false)
+    }
+
+    async fn check_cancelled(&self) -> Result<()> {
+      Ok(())
+    }
+
+    fn checkpoint<T: serde::de::DeserializeOwned + Default>(&self) -> Result<T> {
+      Ok(T::default())
+    }
+
+    async fn save_checkpoint<T: serde::Serialize + Sync>(&self, _checkpoint: &T) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  async fn database() -> DbConn {
+    let db = SqlitePoolOptions::new()
+      .max_connections(1)
+      .connect_with(SqliteConnectOptions::new().in_memory(true))
+      .await
+      .unwrap();
+    sqlx::migrate!().run(&db).await.unwrap();
+
+    db
+  }
+
+  fn bot(api_url: &str) -> BotType {
+    Bot::new("1:token")
+      .set_api_url(url::Url::parse(api_url).unwrap())
+      .parse_mode(ParseMode::Html)
+  }
+
+  fn archive(entity_ids: &[&str]) -> Vec<u8> {
+    let mut writer = ArchiveWriter::new(String::new(), usize::MAX);
+    for entity_id in entity_ids {
+      let item = ImportItem {
+        entity_id: entity_id.to_string(),
+        file_id: format!("old-{}", entity_id),
+        entity_type: EntityType::Sticker,
+        tags: vec!["cat".to_string()],
+        count: 1,
+        last_used: 0,
+        created_at: 0,
+      };
+      let media = ("webp".to_string(), b"RIFF\0\0\0\0WEBP".to_vec());
+      assert!(writer.add(item, Some(media)).unwrap().is_none());
+    }
+
+    writer.finish().unwrap().0
+  }
+
+  #[tokio::test]
+  async fn upload() {
+    let (api_url, calls) = stand_in_api("AgADsticker2");
+    let db = database().await;
+    let archive = archive(&["AgADsticker1", "AgADsticker2"]);
+
+    let mut report = ImportReport::default();
+    let export = upload_archive_media(
+      &db,
+      &bot(&api_url),
+      ChatId(1),
+      &NoProgress,
+      &archive,
+      false,
+      &mut report,
+    )
+    .await
+    .unwrap();
+
+    let items: Vec<(&str, &str)> = export
+      .items
+      .iter()
+      .map(|item| (item.entity_id.as_str(), item.file_id.as_str()))
+      .collect();
+    assert_eq!(
+      items,
+      [
+        ("AgADsticker1", "new-AgADsticker1"),
+        ("AgADconverted", "new-AgADconverted"),
+      ]
+    );
+    assert_eq!(report.remapped_entities, 1);
+    assert!(report.skipped.is_empty());
+
+    let calls = calls.lock().unwrap();
+    assert_eq!(
+      calls.iter().filter(|call| *call == "sendsticker").count(),
+      2
+    );
+    assert_eq!(
+      calls.iter().filter(|call| *call == "deletemessage").count(),
+      2
+    );
+  }
+
+  #[tokio::test]
+  async fn dry_run_does_not_upload() {
+    let (api_url, calls) = stand_in_api("");
+    let db = database().await;
+    let archive = archive(&["AgADsticker1"]);
+
+    let mut report = ImportReport::default();
+    let export = upload_archive_media(
+      &db,
+      &bot(&api_url),
+      ChatId(1),
+      &NoProgress,
+      &archive,
+      true,
+      &mut report,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(export.items[0].file_id, "old-AgADsticker1");
+    assert!(calls.lock().unwrap().is_empty());
+  }
+}
@@ -1,45 +1,329 @@
-use std::io::Write;
+use std::{future::Future, io::Write};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
 
 use crate::{
-  database::import::types::{BotImport, ExportItem, ImportItem},
+  database::{
+    import::{
+      formats::ReadableWriter,
+      types::{
+        BotImport, ExportFormat, ExportHeader, ExportItem, ExportPart, ImportItem,
+        EXPORT_FORMAT_VERSION, FULL_EXPORT_FORMAT_VERSION,
+      },
+    },
+    queries,
+  },
   types::DbConn,
+  util,
 };
 
-pub async fn export(db: &DbConn, user_id: String) -> Result<Vec<u8>> {
-  log::debug!("Exporting for user {}", user_id);
+// Bots can only download files up to 20MB, every part has to stay below that to
+// be importable again
+pub const EXPORT_PART_SIZE: usize = 19_000_000;
 
-  let results: Vec<ExportItem> = sqlx::query_as(
+// How many entities are read from the database at once
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+// Exports all entities of a user, or only what changed after since. Every
+// finished file is handed to send_file together with its file name.
+pub async fn export<F, Fut>(
+  db: &DbConn,
+  user_id: String,
+  format: ExportFormat,
+  since: Option<i64>,
+  part_size: usize,
+  mut send_file: F,
+) -> Result<()>
+where
+  F: FnMut(Vec<u8>, String) -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  log::debug!(
+    "Exporting for user {} as {:?} since {:?}",
+    user_id,
+    format,
+    since
+  );
+
+  if since.is_some() && format != ExportFormat::Stimkerbot {
+    bail!("Only the default export format can contain just the changes");
+  }
+
+  let exported_at = util::get_unix();
+  let mut writer = match format {
+    ExportFormat::Stimkerbot => {
+      let secret = queries::get_user_hash_secret(db).await?;
+      let user_hash = util::user_hash(&secret, &user_id);
+      Writer::Export(Box::new(ExportWriter::new(
+        user_hash,
+        exported_at,
+        since,
+        part_size,
+      )?))
+    }
+    _ => Writer::Readable(ReadableWriter::new(format, part_size)),
+  };
+
+  let mut after = String::new();
+  loop {
+    let items = export_batch(db, &user_id, since, &after).await?;
+    let Some(last) = items.last() else {
+      break;
+    };
+    after = last.entity_id.clone();
+
+    for item in &items {
+      if let Some((data, file_name)) = writer.write(item)? {
+        send_file(data, file_name).await?;
+      }
+    }
+  }
+
+  let (data, file_name) = match writer {
+    Writer::Export(writer) => {
+      let deleted = match since {
+        Some(since) => deleted_entities(db, &user_id, since).await?,
+        None => Vec::new(),
+      };
+      writer.finish(deleted)?
+    }
+    Writer::Readable(writer) => writer.finish(),
+  };
+  send_file(data, file_name).await?;
+
+  // Only the default format counts for /export since last export
+  if format == ExportFormat::Stimkerbot {
+    queries::set_last_export_time(db, user_id, exported_at).await?;
+  }
+
+  Ok(())
+}
+
+// Writes a complete export in one part, for exports that are not read from the
+// database
+pub fn write_export(items: &BotImport) -> Result<Vec<u8>> {
+  let mut writer = ExportWriter::new(String::new(), util::get_unix(), None, usize::MAX)?;
+  for item in items {
+    writer.write(item)?;
+  }
+
+  Ok(writer.finish(Vec::new())?.0)
+}
+
+// Entities changed after since are those with changed tags or stats. Returns
+// the next batch in order of the entity id, so the connection is not held while
+// the files are sent.
+pub(super) async fn export_batch(
+  db: &DbConn,
+  user_id: &str,
+  since: Option<i64>,
+  after: &str,
+) -> Result<BotImport> {
+  let items: Vec<ExportItem> = sqlx::query_as(
         "SELECT entity_data.entity_id, entity_file.file_id, entity_file.entity_type, group_concat(entity_tag.tag_name, \" \") as tags, entity_data.count, entity_data.last_used, entity_data.created_at FROM entity_main \
-        JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+        JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
         JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
         JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
-        WHERE entity_data.user_id = $1 \
-        GROUP BY entity_data.entity_id"
-    ).bind(&user_id).fetch_all(db).await?;
-
-  let import: BotImport = results
-    .into_iter()
-    .map(|item| ImportItem {
-      entity_id: item.entity_id,
-      file_id: item.file_id,
-      entity_type: item.entity_type,
-      tags: item.tags.split(" ").map(|s| s.to_string()).collect(),
-      count: item.count,
-      last_used: item.last_used,
-      created_at: item.created_at,
-    })
-    .collect();
-
-  log::debug!("Exported {} items", import.len());
-
-  let json = serde_json::to_vec(&import)?;
-
-  let mut compressor = GzEncoder::new(Vec::new(), Compression::best());
-  compressor.write_all(json.as_slice())?;
-  let compressed = compressor.finish()?;
-
-  Ok(compressed)
+        WHERE entity_data.user_id = $1 AND entity_data.entity_id > $3 \
+        AND ($2 IS NULL OR entity_data.last_used > $2 OR entity_data.created_at > $2 OR entity_data.updated_at > $2 \
+          OR entity_data.entity_id IN (SELECT entity_id FROM tag_history WHERE user_id = $1 AND created_at > $2)) \
+        GROUP BY entity_data.entity_id \
+        ORDER BY entity_data.entity_id \
+        LIMIT $4"
+    )
+    .bind(user_id)
+    .bind(since)
+    .bind(after)
+    .bind(EXPORT_BATCH_SIZE)
+    .fetch_all(db)
+    .await?;
+
+  Ok(
+    items
+      .into_iter()
+      .map(|item| ImportItem {
+        entity_id: item.entity_id,
+        file_id: item.file_id,
+        entity_type: item.entity_type,
+        tags: item.tags.split(" ").map(|s| s.to_string()).collect(),
+        count: item.count,
+        last_used: item.last_used,
+        created_at: item.created_at,
+      })
+      .collect(),
+  )
+}
+
+// Entities that lost all their tags after since, a full export would not
+// contain them anymore
+async fn deleted_entities(db: &DbConn, user_id: &str, since: i64) -> Result<Vec<String>> {
+  let deleted: Vec<String> = sqlx::query_scalar(
+    "SELECT DISTINCT entity_id FROM tag_history \
+      WHERE user_id = $1 AND created_at > $2 \
+      AND entity_id NOT IN (SELECT entity_data.entity_id FROM entity_main \
+        JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
+        JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+        WHERE entity_data.user_id = $1)",
+  )
+  .bind(user_id)
+  .bind(since)
+  .fetch_all(db)
+  .await?;
+
+  Ok(deleted)
+}
+
+// The default format, or one of the readable formats
+enum Writer {
+  Export(Box<ExportWriter>),
+  Readable(ReadableWriter),
+}
+
+impl Writer {
+  fn write(&mut self, item: &ImportItem) -> Result<Option<(Vec<u8>, String)>> {
+    match self {
+      Writer::Export(writer) => writer.write(item),
+      Writer::Readable(writer) => writer.write(item),
+    }
+  }
+}
+
+// Writes the envelope by hand, so items can go straight into the compressor.
+// The header comes after the items, as the count and checksum are only known
+// at the end.
+struct ExportWriter {
+  user_hash: String,
+  export_id: String,
+  exported_at: i64,
+  since: Option<i64>,
+  part_size: usize,
+  part_index: u32,
+  encoder: GzEncoder<Vec<u8>>,
+  checksum: Sha256,
+  item_count: usize,
+}
+
+impl ExportWriter {
+  fn new(
+    user_hash: String,
+    exported_at: i64,
+    since: Option<i64>,
+    part_size: usize,
+  ) -> Result<Self> {
+    let mut writer = ExportWriter {
+      user_hash,
+      export_id: util::random_id(),
+      exported_at,
+      since,
+      part_size,
+      part_index: 0,
+      encoder: GzEncoder::new(Vec::new(), Compression::best()),
+      checksum: Sha256::new(),
+      item_count: 0,
+    };
+    writer.start_part()?;
+
+    Ok(writer)
+  }
+
+  fn start_part(&mut self) -> Result<()> {
+    self.encoder = GzEncoder::new(Vec::new(), Compression::best());
+    self.checksum = Sha256::new();
+    self.item_count = 0;
+
+    self.encoder.write_all(b"{\"items\":[")?;
+    self.checksum.update(b"[");
+
+    Ok(())
+  }
+
+  // Returns the previous part if it was full
+  fn write(&mut self, item: &ImportItem) -> Result<Option<(Vec<u8>, String)>> {
+    // The compressor buffers a bit, so this can overshoot by a few kilobytes
+    let finished_part = if self.item_count > 0 && self.encoder.get_ref().len() >= self.part_size {
+      let part = self.finish_part(false, &[])?;
+      self.part_index += 1;
+      self.start_part()?;
+      Some(part)
+    } else {
+      None
+    };
+
+    let json = serde_json::to_vec(item)?;
+    if self.item_count > 0 {
+      self.encoder.write_all(b",")?;
+      self.checksum.update(b",");
+    }
+    self.encoder.write_all(&json)?;
+    self.checksum.update(&json);
+    self.item_count += 1;
+
+    Ok(finished_part)
+  }
+
+  // Deleted entities are only part of the last part
+  fn finish(mut self, deleted: Vec<String>) -> Result<(Vec<u8>, String)> {
+    self.finish_part(true, &deleted)
+  }
+
+  fn finish_part(&mut self, last: bool, deleted: &[String]) -> Result<(Vec<u8>, String)> {
+    self.checksum.update(b"]");
+
+    let deleted_json = serde_json::to_vec(deleted)?;
+    if !deleted.is_empty() {
+      self.checksum.update(&deleted_json);
+    }
+
+    let split = !last || self.part_index > 0;
+    let header = ExportHeader {
+      format_version: if self.since.is_some() {
+        EXPORT_FORMAT_VERSION
+      } else {
+        FULL_EXPORT_FORMAT_VERSION
+      },
+      bot_version: env!("CARGO_PKG_VERSION").to_string(),
+      exported_at: self.exported_at,
+      user_hash: self.user_hash.clone(),
+      item_count: self.item_count,
+      checksum: format!("{:x}", std::mem::take(&mut self.checksum).finalize()),
+      part: split.then(|| ExportPart {
+        export_id: self.export_id.clone(),
+        index: self.part_index,
+        last,
+      }),
+      since: self.since,
+    };
+
+    self.encoder.write_all(b"],")?;
+    if !deleted.is_empty() {
+      self.encoder.write_all(b"\"deleted\":")?;
+      self.encoder.write_all(&deleted_json)?;
+      self.encoder.write_all(b",")?;
+    }
+    self.encoder.write_all(b"\"header\":")?;
+    serde_json::to_writer(&mut self.encoder, &header)?;
+    self.encoder.write_all(b"}")?;
+
+    let encoder = std::mem::replace(
+      &mut self.encoder,
+      GzEncoder::new(Vec::new(), Compression::best()),
+    );
+
+    log::debug!("Finished export part {:?}", header);
+
+    let name = if self.since.is_some() {
+      "changes"
+    } else {
+      "export"
+    };
+    let file_name = if split {
+      format!("{}-part{}.stimkerbot", name, self.part_index + 1)
+    } else {
+      format!("{}.stimkerbot", name)
+    };
+
+    Ok((encoder.finish()?, file_name))
+  }
 }
@@ -1,102 +1,158 @@
+use std::time::Duration;
+
 use anyhow::Result;
-use teloxide::{requests::Requester, types::Message, ApiError, RequestError};
+use serde::{Deserialize, Serialize};
+use teloxide::{requests::Requester, ApiError, RequestError};
 
 use crate::{
-  database::queries::find_entities,
-  types::{BotType, DbConn, EntitySort, InlineSearchQuery},
+  database::{
+    file_id::DecodedFileId,
+    queries::{self, FileStatus},
+  },
+  types::{BotType, DbConn},
+  util::retry_after,
 };
 
+use super::progress::Progress;
+
+// How many files are checked between saving the progress
+const BATCH_SIZE: i64 = 50;
+
+// Stays well below the limits of the Bot API, a 429 is waited out as well
+const REQUEST_INTERVAL: Duration = Duration::from_millis(100);
+
+// How far the job got, entities are checked in order so everything up to
+// last_entity_id is done
+#[derive(Serialize, Deserialize, Default)]
+struct FixCheckpoint {
+  last_entity_id: String,
+  checked: i64,
+  fixed: i64,
+  // The entity id and why it was quarantined
+  diagnostics: Vec<(String, String)>,
+  // The entity id and why it could not be checked
+  #[serde(default)]
+  errors: Vec<(String, String)>,
+}
+
+pub struct FixReport {
+  pub checked: i64,
+  pub fixed: i64,
+  // The entity id and why it was quarantined
+  pub diagnostics: Vec<(String, String)>,
+  // The entity id and why it could not be checked, these are left alone
+  pub errors: Vec<(String, String)>,
+  pub cancelled: bool,
+}
+
+// Checks every file of a user with Telegram, and quarantines the ones that
+// don't work anymore. Continues where the job left off before a restart.
 pub async fn fix(
   db: &DbConn,
   bot: &BotType,
-  progress_message: &Message,
   user_id: String,
-) -> Result<i32> {
-  let mut i: i32 = 0;
-  let mut file_ids_to_remove: Vec<String> = Vec::new();
-  loop {
-    let entities = find_entities(
+  progress: &impl Progress,
+) -> Result<FixReport> {
+  let mut checkpoint: FixCheckpoint = progress.checkpoint()?;
+  let total = queries::count_entities_to_fix(db, user_id.clone()).await? + checkpoint.fixed;
+
+  let mut interval = tokio::time::interval(REQUEST_INTERVAL);
+  let mut cancelled = false;
+
+  'batches: loop {
+    let entities = queries::get_entities_to_fix(
       db,
-      user_id.to_owned(),
-      InlineSearchQuery {
-        sort: EntitySort::LastAdded,
-        get_all: true,
-        ..Default::default()
-      },
-      i,
+      user_id.clone(),
+      checkpoint.last_entity_id.clone(),
+      BATCH_SIZE,
     )
     .await?;
-    if entities.len() == 0 {
+    if entities.is_empty() {
       break;
     }
 
-    bot
-      .edit_message_text(
-        progress_message.chat.id,
-        progress_message.id,
-        format!(
-          "Checking files {}-{}",
-          i * 50 + 1,
-          i * 50 + entities.len() as i32
-        ),
-      )
-      .await?;
-
-    i += 1;
-
-    for entity in entities {
-      log::trace!("Checking file {:?}", entity.file_id);
-      let file = bot.get_file(&entity.file_id).await;
-      if let Err(RequestError::Api(ApiError::Unknown(e))) = &file {
-        if e.contains("wrong file_id") {
-          file_ids_to_remove.push(entity.file_id);
+    progress
+      .report(format!(
+        "Checking files {}-{} of {}",
+        checkpoint.checked + 1,
+        checkpoint.checked + entities.len() as i64,
+        total
+      ))
+      .await;
+
+    for (entity_id, file_id) in entities {
+      // Cancelling only sets a flag, so look for it before every request
+      if progress.is_cancelled().await? {
+        cancelled = true;
+        break 'batches;
+      }
+
+      interval.tick().await;
+
+      // One file Telegram can't answer for should not stop the others
+      let problem = match check_file(bot, &file_id).await {
+        Ok(problem) => problem,
+        Err(e) => {
+          log::warn!("Could not check file {:?}: {:?}", file_id, e);
+          queries::set_file_checked(db, entity_id.clone(), FileStatus::Error).await?;
+          checkpoint.errors.push((entity_id.clone(), e.to_string()));
+          checkpoint.checked += 1;
+          checkpoint.last_entity_id = entity_id;
           continue;
         }
+      };
+      let status = match problem {
+        Some(_) => FileStatus::Broken,
+        None => FileStatus::Ok,
+      };
+      queries::set_file_checked(db, entity_id.clone(), status).await?;
+
+      if let Some(diagnostic) = problem {
+        log::info!("Quarantining file {:?}: {}", file_id, diagnostic);
+        queries::quarantine_entity(db, entity_id.clone(), diagnostic.clone()).await?;
+        checkpoint.diagnostics.push((entity_id.clone(), diagnostic));
+        checkpoint.fixed += 1;
       }
-      file?;
-    }
-  }
 
-  let fixed = file_ids_to_remove.len() as i32;
+      checkpoint.checked += 1;
+      checkpoint.last_entity_id = entity_id;
+    }
 
-  for file_id in file_ids_to_remove {
-    log::info!("Removing file {:?}", file_id);
-    remove_file_id(db, file_id).await?;
+    progress.save_checkpoint(&checkpoint).await?;
   }
 
-  Ok(fixed)
+  Ok(FixReport {
+    checked: checkpoint.checked,
+    fixed: checkpoint.fixed,
+    diagnostics: checkpoint.diagnostics,
+    errors: checkpoint.errors,
+    cancelled,
+  })
 }
 
-async fn remove_file_id(db: &DbConn, file_id: String) -> Result<()> {
-  // Remove the enitity_data with the file_id, and all tables referencing its combo_id
-
-  let mut transaction = db.begin().await?;
-
-  // Delete all entity_main where combo_id is in entity_data with file_id
-  sqlx::query(
-      "DELETE FROM entity_main WHERE combo_id IN \
-      (SELECT combo_id FROM entity_data JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
-       WHERE entity_file.file_id = $1)")
-      .bind(&file_id)
-      .execute(transaction.as_mut())
-      .await?;
-
-  // Delete all entity_data where entity_id is in entity_file with file_id
-  sqlx::query(
-    "DELETE FROM entity_data WHERE entity_id IN \
-      (SELECT entity_id FROM entity_file WHERE file_id = $1)",
-  )
-  .bind(&file_id)
-  .execute(transaction.as_mut())
-  .await?;
-
-  // Delete all entity_file with file_id
-  sqlx::query("DELETE FROM entity_file WHERE file_id = $1")
-    .bind(&file_id)
-    .execute(transaction.as_mut())
-    .await?;
+// Returns why the file has to be quarantined, if it does
+pub async fn check_file(bot: &BotType, file_id: &str) -> Result<Option<String>> {
+  log::trace!("Checking file {:?}", file_id);
+
+  // Newer file ids can fail to decode while Telegram still knows them, so only
+  // Telegram decides if a file is broken
+  let decoded = DecodedFileId::decode(file_id);
 
-  transaction.commit().await?;
+  let file = retry_after(|| bot.get_file(file_id)).await;
+  let unknown = match &file {
+    Err(RequestError::Api(ApiError::WrongFileId | ApiError::FileIdInvalid)) => true,
+    Err(RequestError::Api(ApiError::Unknown(e))) => {
+      e.contains("wrong file_id") || e.contains("invalid file_id")
+    }
+    _ => false,
+  };
+  if unknown {
+    return Ok(Some(match decoded {
+      Ok(decoded) => format!("Unknown to this bot: {}", decoded.describe()),
+      Err(e) => format!("Damaged file id: {}", e),
+    }));
+  }
+  file?;
 
-  Ok(())
+  Ok(None)
 }
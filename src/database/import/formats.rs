@@ -0,0 +1,603 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::database::entities::{Entity, EntityType};
+
+use super::{
+  import::parse_items,
+  types::{BotImport, ExportFormat, ImportItem, ImportReport},
+};
+
+const CSV_COLUMNS: [&str; 7] = [
+  "entity_id",
+  "file_id",
+  "type",
+  "tags",
+  "count",
+  "last_used",
+  "created_at",
+];
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// The spreadsheet friendly version of an ImportItem
+#[derive(Deserialize, Serialize, Debug)]
+struct ReadableItem {
+  entity_id: String,
+  file_id: String,
+  #[serde(rename = "type")]
+  entity_type: String,
+  tags: Vec<String>,
+  #[serde(default)]
+  count: i64,
+  #[serde(default)]
+  last_used: String,
+  #[serde(default)]
+  created_at: String,
+}
+
+impl From<&ImportItem> for ReadableItem {
+  fn from(item: &ImportItem) -> Self {
+    ReadableItem {
+      entity_id: item.entity_id.clone(),
+      file_id: item.file_id.clone(),
+      entity_type: type_to_string(&item.entity_type),
+      tags: item.tags.clone(),
+      count: item.count,
+      last_used: format_time(item.last_used),
+      created_at: format_time(item.created_at),
+    }
+  }
+}
+
+pub fn to_json(import: &BotImport) -> Result<Vec<u8>> {
+  write_all(ExportFormat::Json, import)
+}
+
+pub fn from_json(items: Vec<Value>, report: &mut ImportReport) -> BotImport {
+  let items: Vec<ReadableItem> = parse_items(items, "entity_id", report);
+  let mut import: BotImport = Vec::new();
+
+  for item in items {
+    let times = parse_time(&item.last_used)
+      .and_then(|last_used| Ok((last_used, parse_time(&item.created_at)?)));
+
+    match times {
+      Ok((last_used, created_at)) => import.push(ImportItem {
+        entity_type: parse_type(&item.entity_type, &item.file_id),
+        entity_id: item.entity_id,
+        file_id: item.file_id,
+        tags: item.tags,
+        count: item.count,
+        last_used,
+        created_at,
+      }),
+      Err(e) => report.skip(&item.entity_id, e.to_string()),
+    }
+  }
+
+  import
+}
+
+pub fn to_csv(import: &BotImport) -> Result<Vec<u8>> {
+  write_all(ExportFormat::Csv, import)
+}
+
+fn write_all(format: ExportFormat, import: &BotImport) -> Result<Vec<u8>> {
+  let mut writer = ReadableWriter::new(format, usize::MAX);
+  for item in import {
+    writer.write(item)?;
+  }
+
+  Ok(writer.finish().0)
+}
+
+// Writes the readable formats, split into parts of about part_size. Every part
+// is a complete file that a spreadsheet program can open on its own.
+pub struct ReadableWriter {
+  format: ExportFormat,
+  part_size: usize,
+  part_index: u32,
+  data: Vec<u8>,
+  item_count: usize,
+}
+
+impl ReadableWriter {
+  pub fn new(format: ExportFormat, part_size: usize) -> Self {
+    let mut writer = ReadableWriter {
+      format,
+      part_size,
+      part_index: 0,
+      data: Vec::new(),
+      item_count: 0,
+    };
+    writer.start_part();
+
+    writer
+  }
+
+  fn start_part(&mut self) {
+    self.item_count = 0;
+    self.data = match self.format {
+      ExportFormat::Csv => (CSV_COLUMNS.join(",") + "\r\n").into_bytes(),
+      _ => b"[".to_vec(),
+    };
+  }
+
+  // Returns the previous part if this item did not fit in it anymore
+  pub fn write(&mut self, item: &ImportItem) -> Result<Option<(Vec<u8>, String)>> {
+    let encoded = match self.format {
+      ExportFormat::Csv => csv_row(item),
+      // The same as pretty printing the whole array at once
+      _ => {
+        let json = String::from_utf8(serde_json::to_vec_pretty(&ReadableItem::from(item))?)?;
+        format!("\n  {}", json.replace('\n', "\n  "))
+      }
+    };
+
+    let finished_part = if self.item_count > 0 && self.data.len() + encoded.len() > self.part_size {
+      let part = self.finish_part(false);
+      self.part_index += 1;
+      self.start_part();
+      Some(part)
+    } else {
+      None
+    };
+
+    if self.item_count > 0 && self.format != ExportFormat::Csv {
+      self.data.push(b',');
+    }
+    self.data.extend(encoded.as_bytes());
+    self.item_count += 1;
+
+    Ok(finished_part)
+  }
+
+  pub fn finish(mut self) -> (Vec<u8>, String) {
+    self.finish_part(true)
+  }
+
+  fn finish_part(&mut self, last: bool) -> (Vec<u8>, String) {
+    let mut data = std::mem::take(&mut self.data);
+    if self.format != ExportFormat::Csv {
+      if self.item_count > 0 {
+        data.extend(b"\n");
+      }
+      data.extend(b"]");
+    }
+
+    let file_name = if !last || self.part_index > 0 {
+      let (name, extension) = self.format.file_name().rsplit_once('.').unwrap_or_default();
+      format!("{}-part{}.{}", name, self.part_index + 1, extension)
+    } else {
+      self.format.file_name().to_string()
+    };
+
+    (data, file_name)
+  }
+}
+
+fn csv_row(item: &ImportItem) -> String {
+  let row = [
+    item.entity_id.clone(),
+    item.file_id.clone(),
+    type_to_string(&item.entity_type),
+    item.tags.join(" "),
+    item.count.to_string(),
+    format_time(item.last_used),
+    format_time(item.created_at),
+  ];
+
+  row
+    .iter()
+    .map(|field| escape_csv_field(field))
+    .collect::<Vec<String>>()
+    .join(",")
+    + "\r\n"
+}
+
+pub fn from_csv(data: &[u8], report: &mut ImportReport) -> Result<BotImport> {
+  let text = String::from_utf8_lossy(data);
+  // Spreadsheet programs like to add a byte order mark
+  let text = text.trim_start_matches('\u{feff}');
+
+  // Some locales save with semicolons instead of commas
+  let header_line = text.lines().next().unwrap_or_default();
+  let delimiter = if header_line.contains(';') && !header_line.contains(',') {
+    ';'
+  } else {
+    ','
+  };
+
+  let mut rows = parse_csv(text, delimiter).into_iter();
+  let Some(header) = rows.next() else {
+    bail!("The CSV file is empty");
+  };
+
+  let header: Vec<String> = header.iter().map(|h| h.trim().to_lowercase()).collect();
+  let column = |name: &str| header.iter().position(|h| h == name);
+
+  let (Some(entity_id_column), Some(file_id_column)) = (column("entity_id"), column("file_id"))
+  else {
+    bail!("The CSV file needs atleast an entity_id and a file_id column");
+  };
+  let type_column = column("type");
+  let tags_column = column("tags");
+  let count_column = column("count");
+  let last_used_column = column("last_used");
+  let created_at_column = column("created_at");
+
+  let mut import: BotImport = Vec::new();
+
+  for (index, row) in rows.enumerate() {
+    // Empty lines at the end are common
+    if row.iter().all(|field| field.trim().is_empty()) {
+      continue;
+    }
+
+    let field = |column: Option<usize>| {
+      column
+        .and_then(|column| row.get(column))
+        .map(|field| field.trim())
+        .unwrap_or_default()
+    };
+
+    let entity_id = field(Some(entity_id_column)).to_string();
+    let name = if entity_id.is_empty() {
+      format!("row {}", index + 2)
+    } else {
+      entity_id.clone()
+    };
+
+    let result: Result<ImportItem> = (|| {
+      let count = match field(count_column) {
+        "" => 0,
+        count => count
+          .parse()
+          .map_err(|_| anyhow!("Invalid count: {}", count))?,
+      };
+
+      Ok(ImportItem {
+        entity_id: entity_id.clone(),
+        file_id: field(Some(file_id_column)).to_string(),
+        entity_type: parse_type(field(type_column), field(Some(file_id_column))),
+        tags: field(tags_column)
+          .replace(",", " ")
+          .split(" ")
+          .map(|tag| tag.trim().to_string())
+          .filter(|tag| !tag.is_empty())
+          .collect(),
+        count,
+        last_used: parse_time(field(last_used_column))?,
+        created_at: parse_time(field(created_at_column))?,
+      })
+    })();
+
+    match result {
+      Ok(item) => import.push(item),
+      Err(e) => report.skip(&name, e.to_string()),
+    }
+  }
+
+  Ok(import)
+}
+
+fn escape_csv_field(field: &str) -> String {
+  if field.contains([',', ';', '"', '\n', '\r']) {
+    format!("\"{}\"", field.replace("\"", "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+// Splits RFC 4180 style CSV into rows of fields, quoted fields can contain
+// delimiters, quotes and newlines
+fn parse_csv(text: &str, delimiter: char) -> Vec<Vec<String>> {
+  let mut rows = Vec::new();
+  let mut row = Vec::new();
+  let mut field = String::new();
+  let mut in_quotes = false;
+  let mut chars = text.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    if in_quotes {
+      match c {
+        '"' if chars.peek() == Some(&'"') => {
+          field.push('"');
+          chars.next();
+        }
+        '"' => in_quotes = false,
+        _ => field.push(c),
+      }
+      continue;
+    }
+
+    match c {
+      '"' => in_quotes = true,
+      '\r' => {}
+      '\n' => {
+        row.push(std::mem::take(&mut field));
+        rows.push(std::mem::take(&mut row));
+      }
+      c if c == delimiter => row.push(std::mem::take(&mut field)),
+      _ => field.push(c),
+    }
+  }
+
+  if !field.is_empty() || !row.is_empty() {
+    row.push(field);
+    rows.push(row);
+  }
+
+  rows
+}
+
+fn type_to_string(entity_type: &EntityType) -> String {
+  format!("{:?}", entity_type).to_lowercase()
+}
+
+// The type column is only informational, the file id is the source of truth
+fn parse_type(entity_type: &str, file_id: &str) -> EntityType {
+  match entity_type.to_lowercase().as_str() {
+    "sticker" => EntityType::Sticker,
+    "animation" | "gif" => EntityType::Animation,
+    "photo" => EntityType::Photo,
+    "video" => EntityType::Video,
+    _ => Entity::file_id_to_type(file_id).unwrap_or(EntityType::Sticker),
+  }
+}
+
+// Timestamps are UTC, 0 means never
+fn format_time(unix: i64) -> String {
+  if unix == 0 {
+    return String::new();
+  }
+
+  DateTime::<Utc>::from_timestamp_millis(unix)
+    .map(|time| time.format(TIME_FORMAT).to_string())
+    .unwrap_or_default()
+}
+
+pub fn parse_time(time: &str) -> Result<i64> {
+  let time = time.trim();
+  if time.is_empty() || time.eq_ignore_ascii_case("never") {
+    return Ok(0);
+  }
+
+  // Raw unix timestamps in milliseconds are accepted as well
+  if let Ok(unix) = time.parse::<i64>() {
+    return Ok(unix);
+  }
+
+  if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+    return Ok(time.timestamp_millis());
+  }
+
+  // Spreadsheets tend to drop the seconds when saving
+  for format in [TIME_FORMAT, "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"] {
+    if let Ok(time) = NaiveDateTime::parse_from_str(time, format) {
+      return Ok(time.and_utc().timestamp_millis());
+    }
+  }
+
+  // A date alone means the start of that day
+  if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+    return Ok(date.and_time(NaiveTime::MIN).and_utc().timestamp_millis());
+  }
+
+  bail!("Invalid time: {}", time)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_import() -> BotImport {
+    vec![
+      ImportItem {
+        entity_id: "AgADywQAAh8BAAI".to_string(),
+        file_id: "CAACAgIAAxkBAAIBTgECAwQFBgcICQoLDA0ODxAREhMUAALLBPtxHwEAAk-XIcX_____LwQ"
+          .to_string(),
+        entity_type: EntityType::Sticker,
+        tags: vec!["cat".to_string(), "happy".to_string()],
+        count: 12,
+        last_used: 1_700_000_000_000,
+        created_at: 1_690_000_000_000,
+      },
+      ImportItem {
+        entity_id: "with,comma \"and\"\nline break".to_string(),
+        file_id: "CgACAgIAAxkBAAIBTg".to_string(),
+        entity_type: EntityType::Animation,
+        tags: vec!["dance".to_string()],
+        count: 0,
+        last_used: 0,
+        created_at: 0,
+      },
+    ]
+  }
+
+  fn assert_same(import: &BotImport, expected: &BotImport) {
+    assert_eq!(import.len(), expected.len());
+    for (item, expected) in import.iter().zip(expected) {
+      assert_eq!(item.entity_id, expected.entity_id);
+      assert_eq!(item.file_id, expected.file_id);
+      assert_eq!(item.entity_type, expected.entity_type);
+      assert_eq!(item.tags, expected.tags);
+      assert_eq!(item.count, expected.count);
+      assert_eq!(item.last_used, expected.last_used);
+      assert_eq!(item.created_at, expected.created_at);
+    }
+  }
+
+  #[test]
+  fn csv_quoting() {
+    let rows = parse_csv(
+      "a,\"b,c\",\"say \"\"hi\"\"\"\r\n\"multi\nline\",,end\r\n",
+      ',',
+    );
+
+    assert_eq!(
+      rows,
+      vec![
+        vec!["a", "b,c", "say \"hi\""],
+        vec!["multi\nline", "", "end"],
+      ]
+    );
+  }
+
+  #[test]
+  fn csv_without_trailing_newline() {
+    let rows = parse_csv("a;b\nc;d", ';');
+
+    assert_eq!(rows, vec![vec!["a", "b"], vec!["c", "d"]]);
+  }
+
+  #[test]
+  fn csv_round_trip() {
+    let import = sample_import();
+    let mut report = ImportReport::default();
+
+    let parsed = from_csv(&to_csv(&import).unwrap(), &mut report).unwrap();
+
+    assert!(report.skipped.is_empty());
+    assert_same(&parsed, &import);
+  }
+
+  #[test]
+  fn csv_spreadsheet_export() {
+    // A byte order mark, semicolons, other column order and casing, and no
+    // optional columns
+    let csv = "\u{feff}File_ID;Entity_ID;Tags\n\
+      CAACAgIAAxkBAAIBTgECAwQFBgcICQoLDA0ODxAREhMUAALLBPtxHwEAAk-XIcX_____LwQ;first;\"cat, dog\"\n\
+      ;;\n\
+      CgACAgIAAxkBAAIBTg;second;\n";
+    let mut report = ImportReport::default();
+
+    let parsed = from_csv(csv.as_bytes(), &mut report).unwrap();
+
+    assert!(report.skipped.is_empty());
+    assert_eq!(parsed.len(), 2);
+    assert_eq!(parsed[0].entity_id, "first");
+    assert_eq!(parsed[0].tags, vec!["cat", "dog"]);
+    assert_eq!(parsed[0].count, 0);
+    assert_eq!(parsed[0].last_used, 0);
+    assert_eq!(parsed[1].entity_id, "second");
+    assert!(parsed[1].tags.is_empty());
+  }
+
+  #[test]
+  fn csv_invalid_rows_are_skipped() {
+    let csv = "entity_id,file_id,count,last_used\n\
+      good,CgACAgIAAxkBAAIBTg,3,2024-01-02\n\
+      bad_count,CgACAgIAAxkBAAIBTg,many,\n\
+      bad_time,CgACAgIAAxkBAAIBTg,1,yesterday\n";
+    let mut report = ImportReport::default();
+
+    let parsed = from_csv(csv.as_bytes(), &mut report).unwrap();
+
+    assert_eq!(parsed.len(), 1);
+    assert_eq!(parsed[0].last_used, 1_704_153_600_000);
+    let skipped: Vec<&str> = report.skipped.iter().map(|s| s.item.as_str()).collect();
+    assert_eq!(skipped, vec!["bad_count", "bad_time"]);
+  }
+
+  #[test]
+  fn csv_needs_ids() {
+    let mut report = ImportReport::default();
+
+    assert!(from_csv(b"tags,count\ncat,1\n", &mut report).is_err());
+    assert!(from_csv(b"", &mut report).is_err());
+  }
+
+  #[test]
+  fn json_round_trip() {
+    let import = sample_import();
+    let mut report = ImportReport::default();
+
+    let items: Vec<Value> = serde_json::from_slice(&to_json(&import).unwrap()).unwrap();
+    let parsed = from_json(items, &mut report);
+
+    assert!(report.skipped.is_empty());
+    assert_same(&parsed, &import);
+  }
+
+  #[test]
+  fn json_invalid_items_are_skipped() {
+    let items: Vec<Value> = serde_json::from_str(
+      r#"[
+        {"entity_id": "good", "file_id": "CgACAgIAAxkBAAIBTg", "type": "gif", "tags": ["a"]},
+        {"entity_id": "no_tags", "file_id": "CgACAgIAAxkBAAIBTg", "type": "gif"},
+        {"entity_id": "bad_time", "file_id": "x", "type": "photo", "tags": [], "last_used": "soon"},
+        "not an item"
+      ]"#,
+    )
+    .unwrap();
+    let mut report = ImportReport::default();
+
+    let parsed = from_json(items, &mut report);
+
+    assert_eq!(parsed.len(), 1);
+    assert_eq!(parsed[0].entity_type, EntityType::Animation);
+    let skipped: Vec<&str> = report.skipped.iter().map(|s| s.item.as_str()).collect();
+    assert_eq!(skipped, vec!["no_tags", "#4", "bad_time"]);
+  }
+
+  #[test]
+  fn split_parts() {
+    for format in [ExportFormat::Csv, ExportFormat::Json] {
+      let mut writer = ReadableWriter::new(format, 200);
+      let mut parts = Vec::new();
+      for item in &sample_import() {
+        parts.extend(writer.write(item).unwrap());
+      }
+      parts.push(writer.finish());
+
+      let names: Vec<&str> = parts.iter().map(|(_, name)| name.as_str()).collect();
+      let extension = if format == ExportFormat::Csv {
+        "csv"
+      } else {
+        "json"
+      };
+      assert_eq!(
+        names,
+        vec![
+          format!("export-part1.{}", extension),
+          format!("export-part2.{}", extension)
+        ]
+      );
+
+      // Every part can be read on its own
+      let mut report = ImportReport::default();
+      let mut parsed = Vec::new();
+      for (data, _) in &parts {
+        parsed.extend(match format {
+          ExportFormat::Csv => from_csv(data, &mut report).unwrap(),
+          _ => from_json(serde_json::from_slice(data).unwrap(), &mut report),
+        });
+      }
+      assert!(report.skipped.is_empty());
+      assert_same(&parsed, &sample_import());
+    }
+  }
+
+  #[test]
+  fn times() {
+    assert_eq!(parse_time("").unwrap(), 0);
+    assert_eq!(parse_time("Never").unwrap(), 0);
+    assert_eq!(parse_time("1700000000000").unwrap(), 1_700_000_000_000);
+    assert_eq!(
+      parse_time("2023-11-14 22:13:20").unwrap(),
+      1_700_000_000_000
+    );
+    assert_eq!(parse_time("2023-11-14 22:13").unwrap(), 1_699_999_980_000);
+    assert_eq!(
+      parse_time("2023-11-14T23:13:20+01:00").unwrap(),
+      1_700_000_000_000
+    );
+    assert!(parse_time("yesterday").is_err());
+    assert_eq!(format_time(1_700_000_000_000), "2023-11-14 22:13:20");
+    assert_eq!(format_time(0), "");
+  }
+}
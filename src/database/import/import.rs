@@ -1,24 +1,67 @@
 use crate::{
-  database::entities::{Entity, EntityType},
+  database::{
+    entities::{Entity, EntityType},
+    queries::{journal_entities_added, journal_tags_added, journal_tags_removed},
+  },
   types::DbConn,
+  util,
 };
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use sqlx::QueryBuilder;
 use std::collections::HashSet;
 use std::io::Read;
 
-use super::types::{BotImport, ImportItem, QSBotImport};
+use super::{
+  archive, formats,
+  types::{
+    BotImport, ExportPart, ExportVersion, ImportItem, ImportMode, ImportReport, QSBotImport,
+    RawExportEnvelope, EXPORT_FORMAT_VERSION,
+  },
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
-pub async fn quickstickbot_import(db: &DbConn, user_id: String, file: Vec<u8>) -> Result<()> {
+pub async fn quickstickbot_import(
+  db: &DbConn,
+  user_id: String,
+  file: Vec<u8>,
+  journal_id: i64,
+) -> Result<ImportReport> {
   // Parse the file
-  let qs_import: QSBotImport = serde_json::from_slice(&file)?;
+  let mut report = ImportReport::default();
+  let qs_import: QSBotImport = parse_items(serde_json::from_slice(&file)?, "id", &mut report);
 
   // Insert the file into the database
+  let bot_import = convert_quickstickbot(qs_import, &mut report);
+
+  import_botimport(
+    db,
+    user_id,
+    ParsedExport::from_items(bot_import),
+    ImportMode::Merge,
+    false,
+    journal_id,
+    report,
+  )
+  .await
+}
+
+// QuickStickBot exports have no statistics, and no type besides the file id
+fn convert_quickstickbot(qs_import: QSBotImport, report: &mut ImportReport) -> BotImport {
   let mut bot_import: BotImport = Vec::new();
 
   for item in qs_import {
-    let entity_type = Entity::file_id_to_type(&item.file_id)?;
+    let entity_type = match Entity::file_id_to_type(&item.file_id) {
+      Ok(entity_type) => entity_type,
+      Err(e) => {
+        report.skip(&item.id, format!("Unsupported file: {}", e));
+        continue;
+      }
+    };
 
     bot_import.push(ImportItem {
       entity_id: item.id,
@@ -31,26 +74,409 @@ pub async fn quickstickbot_import(db: &DbConn, user_id: String, file: Vec<u8>) -
     });
   }
 
-  import_botimport(db, user_id, bot_import).await?;
+  bot_import
+}
+
+// Imports one export, or all parts of a split export at once. The files are
+// read with parse_file or upload_archive_media first, so only one of them has
+// to be in memory at a time.
+pub async fn import(
+  db: &DbConn,
+  user_id: String,
+  exports: Vec<ParsedExport>,
+  mode: ImportMode,
+  dry_run: bool,
+  journal_id: i64,
+  report: ImportReport,
+) -> Result<ImportReport> {
+  let export_count = exports.len();
+  let mut combined = ParsedExport::from_items(Vec::new());
+  let mut parts: Vec<ExportPart> = Vec::new();
+
+  for export in exports {
+    combined.items.extend(export.items);
+    combined.deleted.extend(export.deleted);
+    combined.is_delta |= export.is_delta;
+    parts.extend(export.part);
+  }
+
+  // Replace all would wipe everything that is in the missing parts
+  if !parts.is_empty() {
+    if parts.len() != export_count {
+      bail!("Only send the parts of one export together");
+    }
+    check_export_parts(&parts)?;
+  }
+
+  // A delta export holds the current state of everything that changed, so the
+  // changed entities are overwritten
+  let mode = match (combined.is_delta, mode) {
+    (true, ImportMode::ReplaceAll) => {
+      bail!("An export of only the changes can't replace all your entities")
+    }
+    (true, _) => ImportMode::Overwrite,
+    (false, mode) => mode,
+  };
+
+  import_botimport(db, user_id, combined, mode, dry_run, journal_id, report).await
+}
+
+// Reads any file the bot can import, without touching the database. The media
+// of an archive is left out.
+pub fn parse_file(file: &[u8], report: &mut ImportReport) -> Result<ParsedExport> {
+  if archive::is_archive(file) {
+    return archive::read_archive_export(file, report);
+  }
+
+  if file.starts_with(&GZIP_MAGIC) {
+    return parse_export(&decompress(file)?, report);
+  }
+
+  // The readable formats from /export csv and /export json, or a QuickStickBot
+  // export, which is a bare array as well
+  match file.iter().find(|c| !c.is_ascii_whitespace()) {
+    Some(b'[') => {
+      let items: Vec<Value> =
+        serde_json::from_slice(file).context("This is not a valid JSON file")?;
+      if items
+        .first()
+        .is_some_and(|item| item.get("fileId").is_some())
+      {
+        let qs_import: QSBotImport = parse_items(items, "id", report);
+        Ok(ParsedExport::from_items(convert_quickstickbot(
+          qs_import, report,
+        )))
+      } else {
+        Ok(ParsedExport::from_items(formats::from_json(items, report)))
+      }
+    }
+    Some(b'{') => parse_export(file, report),
+    _ => Ok(ParsedExport::from_items(formats::from_csv(file, report)?)),
+  }
+}
+
+// Makes sure these are all parts of one export
+fn check_export_parts(parts: &[ExportPart]) -> Result<()> {
+  let mut indexes: HashSet<u32> = HashSet::new();
+  let mut part_count: Option<u32> = None;
+
+  for part in parts {
+    if part.export_id != parts[0].export_id {
+      bail!("These files are parts of different exports");
+    }
+    if !indexes.insert(part.index) {
+      bail!("Part {} was sent twice", part.index + 1);
+    }
+    if part.last {
+      part_count = Some(part.index + 1);
+    }
+  }
+
+  let Some(part_count) = part_count else {
+    bail!("The last part of this export is missing, please send all parts together");
+  };
+  if indexes.iter().any(|index| *index >= part_count) {
+    bail!("These files are parts of different exports");
+  }
+
+  let missing: Vec<String> = (0..part_count)
+    .filter(|index| !indexes.contains(index))
+    .map(|index| (index + 1).to_string())
+    .collect();
+  if !missing.is_empty() {
+    bail!(
+      "Some parts of this export are missing (part {}), please send all parts together",
+      missing.join(", ")
+    );
+  }
 
   Ok(())
 }
 
-pub async fn import(db: &DbConn, user_id: String, file: Vec<u8>) -> Result<()> {
-  let mut decompressor = GzDecoder::new(file.as_slice());
+fn decompress(file: &[u8]) -> Result<Vec<u8>> {
+  let mut decompressor = GzDecoder::new(file);
   let mut decompressed = Vec::new();
   decompressor.read_to_end(&mut decompressed)?;
 
-  let import: BotImport = serde_json::from_slice(&decompressed)?;
+  Ok(decompressed)
+}
+
+pub struct ParsedExport {
+  pub items: BotImport,
+  pub part: Option<ExportPart>,
+  pub deleted: Vec<String>,
+  pub is_delta: bool,
+}
+
+impl ParsedExport {
+  pub(super) fn from_items(items: BotImport) -> Self {
+    ParsedExport {
+      items,
+      part: None,
+      deleted: Vec::new(),
+      is_delta: false,
+    }
+  }
+}
+
+// Reads both the legacy bare array and the versioned envelope
+pub(super) fn parse_export(data: &[u8], report: &mut ImportReport) -> Result<ParsedExport> {
+  let first_char = data.iter().find(|c| !c.is_ascii_whitespace());
+  if first_char == Some(&b'[') {
+    log::debug!("Importing a legacy export");
+    let items: Vec<Value> = serde_json::from_slice(data)?;
+    return Ok(ParsedExport::from_items(parse_items(items, "id", report)));
+  }
+
+  // Check the version first, newer formats might not parse at all
+  let version: ExportVersion =
+    serde_json::from_slice(data).context("This is not a valid export file")?;
+
+  if version.header.format_version > EXPORT_FORMAT_VERSION {
+    bail!(
+      "This export was made by a newer version of the bot ({}, format version {}), \
+      please wait until this bot is updated",
+      version.header.bot_version.unwrap_or_default(),
+      version.header.format_version
+    );
+  }
+
+  let envelope: RawExportEnvelope = serde_json::from_slice(data)?;
+  let header = envelope.header;
+
+  log::debug!("Importing export {:?}", header);
+
+  let deleted: Vec<String> = match envelope.deleted {
+    Some(deleted) => serde_json::from_str(deleted.get())?,
+    None => Vec::new(),
+  };
+
+  let mut checksum = Sha256::new();
+  checksum.update(envelope.items.get());
+  if let Some(raw_deleted) = envelope.deleted.filter(|_| !deleted.is_empty()) {
+    checksum.update(raw_deleted.get());
+  }
+
+  if format!("{:x}", checksum.finalize()) != header.checksum {
+    bail!("This export file is damaged, the checksum does not match");
+  }
+
+  let items: Vec<Value> = serde_json::from_str(envelope.items.get())?;
+
+  if items.len() != header.item_count {
+    bail!(
+      "This export file is incomplete, expected {} items but found {}",
+      header.item_count,
+      items.len()
+    );
+  }
+
+  Ok(ParsedExport {
+    items: parse_items(items, "id", report),
+    part: header.part,
+    deleted,
+    is_delta: header.since.is_some(),
+  })
+}
+
+// Reads the items of an array one by one, so a single broken item is skipped
+// instead of failing the whole file. id_field names the item in the report.
+pub(super) fn parse_items<T: DeserializeOwned>(
+  items: Vec<Value>,
+  id_field: &str,
+  report: &mut ImportReport,
+) -> Vec<T> {
+  let mut parsed = Vec::new();
+
+  for (index, item) in items.into_iter().enumerate() {
+    let name = match item.get(id_field).and_then(Value::as_str) {
+      Some(id) if !id.is_empty() => id.to_string(),
+      _ => format!("#{}", index + 1),
+    };
+
+    match serde_json::from_value(item) {
+      Ok(item) => parsed.push(item),
+      Err(e) => report.skip(&name, format!("Invalid item: {}", e)),
+    }
+  }
+
+  parsed
+}
+
+// Drops every item that can not be imported, and cleans up the tags of the rest
+fn validate_items(import: BotImport, report: &mut ImportReport) -> BotImport {
+  let mut seen_entity_ids: HashSet<String> = HashSet::new();
+  let mut valid_items: BotImport = Vec::new();
+
+  for (index, mut item) in import.into_iter().enumerate() {
+    if item.entity_id.trim().is_empty() {
+      report.skip(&format!("#{}", index + 1), "Missing entity id".to_string());
+      continue;
+    }
+
+    if item.file_id.trim().is_empty() {
+      report.skip(&item.entity_id, "Missing file id".to_string());
+      continue;
+    }
+
+    // The file id is the source of truth for the type. Telegram may still
+    // accept ids this version can't read, so those keep the type of the export
+    // and are left to /fixentities and the health check.
+    match Entity::file_id_to_type(&item.file_id) {
+      Ok(entity_type) => item.entity_type = entity_type,
+      Err(e) => report.warn(
+        &item.entity_id,
+        format!("Could not read the file id, kept as it is: {}", e),
+      ),
+    }
+
+    if !seen_entity_ids.insert(item.entity_id.clone()) {
+      report.skip(&item.entity_id, "Duplicate of an earlier item".to_string());
+      continue;
+    }
 
-  import_botimport(db, user_id, import).await
+    item.tags = item
+      .tags
+      .iter()
+      .map(|tag| util::normalize_tag(tag))
+      .filter(|tag| !tag.is_empty())
+      .collect::<HashSet<String>>()
+      .into_iter()
+      .collect();
+
+    valid_items.push(item);
+  }
+
+  valid_items
 }
 
-async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Result<()> {
-  log::debug!("Importing {} items for user {}", import.len(), user_id);
+// Dry runs do all the work, but roll it back at the end, so the report is exact
+pub(super) async fn import_botimport(
+  db: &DbConn,
+  user_id: String,
+  export: ParsedExport,
+  mode: ImportMode,
+  dry_run: bool,
+  journal_id: i64,
+  mut report: ImportReport,
+) -> Result<ImportReport> {
+  let import = validate_items(export.items, &mut report);
+  let deleted = export.deleted;
+
+  log::debug!(
+    "Importing {} items for user {} with mode {:?}{}",
+    import.len(),
+    user_id,
+    mode,
+    if dry_run { " (dry run)" } else { "" }
+  );
 
   let mut transaction = db.begin().await?;
 
+  log::debug!("[IMPORT] Comparing with existing entities");
+
+  {
+    let entity_ids: Vec<String> = import
+      .iter()
+      .map(|item| item.entity_id.clone())
+      .collect::<HashSet<String>>()
+      .into_iter()
+      .collect();
+
+    for chunk in entity_ids.chunks(1000) {
+      // Before anything is replaced, so undo only removes what is really new
+      journal_entities_added(transaction.as_mut(), journal_id, &user_id, chunk).await?;
+
+      let mut existing_query =
+        QueryBuilder::new("SELECT COUNT(*) FROM entity_data WHERE user_id = ");
+      existing_query.push_bind(&user_id);
+      existing_query.push(" AND entity_id IN (");
+      let mut seperator = existing_query.separated(", ");
+      chunk.iter().for_each(|entity_id| {
+        seperator.push_bind(entity_id);
+      });
+      existing_query.push(")");
+
+      let existing: i64 = existing_query
+        .build_query_scalar()
+        .fetch_one(transaction.as_mut())
+        .await?;
+
+      report.existing_entities += existing as usize;
+      report.new_entities += chunk.len() - existing as usize;
+    }
+
+    if mode == ImportMode::ReplaceAll {
+      let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM entity_data WHERE user_id = $1")
+        .bind(&user_id)
+        .fetch_one(transaction.as_mut())
+        .await?;
+
+      report.removed_entities = total as usize - report.existing_entities;
+    }
+  }
+
+  if mode == ImportMode::ReplaceAll {
+    log::debug!("[IMPORT] Wiping existing entities");
+
+    let existing_ids: Vec<String> =
+      sqlx::query_scalar("SELECT entity_id FROM entity_data WHERE user_id = $1")
+        .bind(&user_id)
+        .fetch_all(transaction.as_mut())
+        .await?;
+
+    for chunk in existing_ids.chunks(1000) {
+      journal_tags_removed(transaction.as_mut(), journal_id, &user_id, chunk, None).await?;
+    }
+
+    sqlx::query("DELETE FROM entity_data WHERE user_id = $1")
+      .bind(&user_id)
+      .execute(transaction.as_mut())
+      .await?;
+  }
+
+  // Entities that lost all their tags in the other bot only lose their tags
+  // here as well, their statistics are kept like for /untag
+  if !deleted.is_empty() {
+    log::debug!("[IMPORT] Removing the tags of deleted entities");
+
+    for chunk in deleted.chunks(1000) {
+      journal_tags_removed(transaction.as_mut(), journal_id, &user_id, chunk, None).await?;
+
+      let mut tagged_query = QueryBuilder::new(
+        "SELECT COUNT(DISTINCT entity_main.combo_id) FROM entity_main \
+          JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+          WHERE entity_data.user_id = ",
+      );
+      tagged_query.push_bind(&user_id);
+      tagged_query.push(" AND entity_data.entity_id IN (");
+      let mut seperator = tagged_query.separated(", ");
+      chunk.iter().for_each(|entity_id| {
+        seperator.push_bind(entity_id);
+      });
+      tagged_query.push(")");
+      let tagged: i64 = tagged_query
+        .build_query_scalar()
+        .fetch_one(transaction.as_mut())
+        .await?;
+
+      let mut untag_query = QueryBuilder::new(
+        "DELETE FROM entity_main WHERE combo_id IN \
+          (SELECT combo_id FROM entity_data WHERE user_id = ",
+      );
+      untag_query.push_bind(&user_id);
+      untag_query.push(" AND entity_id IN (");
+      let mut seperator = untag_query.separated(", ");
+      chunk.iter().for_each(|entity_id| {
+        seperator.push_bind(entity_id);
+      });
+      untag_query.push("))");
+      untag_query.build().execute(transaction.as_mut()).await?;
+
+      report.removed_entities += tagged as usize;
+    }
+  }
+
   log::debug!("[IMPORT] Inserting tags");
 
   // Collect all unique tags, and split them into sets of 1000 tags
@@ -60,14 +486,6 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
       let unqiue_tags: HashSet<String> = import
         .iter()
         .flat_map(|item| item.tags.to_owned())
-        .map(|tag| {
-          tag
-            .to_lowercase()
-            .replace(",", "")
-            .replace(" ", "")
-            .trim()
-            .to_string()
-        })
         .collect();
       unqiue_tags.into_iter().collect()
     };
@@ -76,10 +494,12 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
       insert_tag_query.push_values(chunk, |mut b, tag_name| {
         b.push_bind(tag_name);
       });
-      insert_tag_query
+      let result = insert_tag_query
         .build()
         .execute(transaction.as_mut())
         .await?;
+
+      report.tags_created += result.rows_affected() as usize;
     }
   }
 
@@ -103,12 +523,18 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
       .collect();
     for chunk in files.chunks(1000) {
       let mut insert_file_query =
-        QueryBuilder::new("INSERT OR IGNORE INTO entity_file (entity_id, file_id, entity_type) ");
+        QueryBuilder::new("INSERT INTO entity_file (entity_id, file_id, entity_type) ");
       insert_file_query.push_values(chunk, |mut b, file| {
         b.push_bind(&file.entity_id);
         b.push_bind(&file.file_id);
         b.push_bind(&file.entity_type);
       });
+      // A quarantined entity comes back with the file id of the import, the
+      // same as when it is sent to the bot again
+      insert_file_query.push(
+        " ON CONFLICT (entity_id) DO UPDATE SET file_id = excluded.file_id, \
+          broken_at = NULL, broken_reason = NULL WHERE entity_file.broken_at IS NOT NULL",
+      );
       insert_file_query
         .build()
         .execute(transaction.as_mut())
@@ -127,6 +553,7 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
       created_at: i64,
     }
 
+    let updated_at = util::get_unix();
     let combos: Vec<ImportEntity> = import
       .iter()
       .map(|item| ImportEntity {
@@ -138,7 +565,7 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
       .collect();
     for chunk in combos.chunks(1000) {
       let mut insert_combo_query = QueryBuilder::new(
-        "INSERT OR IGNORE INTO entity_data (user_id, entity_id, count, last_used, created_at) ",
+        "INSERT INTO entity_data (user_id, entity_id, count, last_used, created_at, updated_at) ",
       );
       insert_combo_query.push_values(chunk, |mut b, combo| {
         b.push_bind(&user_id);
@@ -146,7 +573,27 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
         b.push_bind(&combo.count);
         b.push_bind(&combo.last_used);
         b.push_bind(&combo.created_at);
+        b.push_bind(updated_at);
       });
+      insert_combo_query
+        .push(" ON CONFLICT (user_id, entity_id) DO UPDATE SET updated_at = excluded.updated_at, ");
+      if mode == ImportMode::Merge {
+        // Keep the highest stats, but the earliest time it was added
+        insert_combo_query.push(
+          "count = MAX(entity_data.count, excluded.count), \
+          last_used = MAX(entity_data.last_used, excluded.last_used), \
+          created_at = CASE \
+            WHEN entity_data.created_at = 0 THEN excluded.created_at \
+            WHEN excluded.created_at = 0 THEN entity_data.created_at \
+            ELSE MIN(entity_data.created_at, excluded.created_at) END",
+        );
+      } else {
+        insert_combo_query.push(
+          "count = excluded.count, \
+          last_used = excluded.last_used, \
+          created_at = excluded.created_at",
+        );
+      }
       insert_combo_query
         .build()
         .execute(transaction.as_mut())
@@ -159,6 +606,36 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
   // Insert all tag relations
   {
     for entity in import {
+      if mode == ImportMode::Overwrite {
+        journal_tags_removed(
+          transaction.as_mut(),
+          journal_id,
+          &user_id,
+          std::slice::from_ref(&entity.entity_id),
+          None,
+        )
+        .await?;
+
+        sqlx::query(
+          "DELETE FROM entity_main \
+            WHERE combo_id IN \
+            (SELECT combo_id FROM entity_data WHERE entity_id = $1 AND user_id = $2)",
+        )
+        .bind(&entity.entity_id)
+        .bind(&user_id)
+        .execute(transaction.as_mut())
+        .await?;
+      }
+
+      journal_tags_added(
+        transaction.as_mut(),
+        journal_id,
+        &user_id,
+        std::slice::from_ref(&entity.entity_id),
+        &entity.tags,
+      )
+      .await?;
+
       let mut insert_main_query = QueryBuilder::new(
         "INSERT OR IGNORE INTO entity_main (combo_id, tag_id) \
                     SELECT (SELECT combo_id FROM entity_data WHERE entity_id = ",
@@ -180,11 +657,32 @@ async fn import_botimport(db: &DbConn, user_id: String, import: BotImport) -> Re
     }
   }
 
-  log::debug!("[IMPORT] Committing");
+  let changes: Vec<(bool, i64)> = sqlx::query_as(
+    "SELECT added, COUNT(*) FROM tag_journal_entry WHERE journal_id = $1 GROUP BY added",
+  )
+  .bind(journal_id)
+  .fetch_all(transaction.as_mut())
+  .await?;
 
-  transaction.commit().await?;
+  for (added, count) in changes {
+    if added {
+      report.tags_added = count as usize;
+    } else {
+      report.tags_removed = count as usize;
+    }
+  }
 
-  log::debug!("[IMPORT] Done");
+  if dry_run {
+    log::debug!("[IMPORT] Rolling back dry run");
 
-  Ok(())
+    transaction.rollback().await?;
+  } else {
+    log::debug!("[IMPORT] Committing");
+
+    transaction.commit().await?;
+  }
+
+  log::debug!("[IMPORT] Done: {:?}", report);
+
+  Ok(report)
 }
@@ -1,10 +1,19 @@
+mod archive;
 mod export;
 mod fix;
+mod formats;
 mod import;
+mod progress;
+mod tdesktop;
+mod zip;
 
 pub mod types;
 
-pub use export::export;
-pub use fix::fix;
-pub use import::import;
+pub use archive::{export_archive, is_archive, upload_archive_media};
+pub use export::{export, write_export, EXPORT_PART_SIZE};
+pub use fix::{check_file, fix, FixReport};
+pub use formats::{parse_time, to_csv, to_json};
 pub use import::quickstickbot_import;
+pub use import::{import, parse_file, ParsedExport};
+pub use progress::Progress;
+pub use tdesktop::telegram_desktop_import;
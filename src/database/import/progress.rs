@@ -0,0 +1,24 @@
+use std::future::Future;
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Whatever runs a long import, export or fix, so it can show how far it got and
+// learn when to stop. Jobs implement this, so nothing here depends on them.
+pub trait Progress: Sync {
+  // Shown to the user as plain text
+  fn report(&self, text: String) -> impl Future<Output = ()> + Send;
+
+  fn is_cancelled(&self) -> impl Future<Output = Result<bool>> + Send;
+
+  // Fails when cancelled, for work that can't report what it did so far
+  fn check_cancelled(&self) -> impl Future<Output = Result<()>> + Send;
+
+  // Where the work left off before a restart, the default if it just started
+  fn checkpoint<T: DeserializeOwned + Default>(&self) -> Result<T>;
+
+  fn save_checkpoint<T: Serialize + Sync>(
+    &self,
+    checkpoint: &T,
+  ) -> impl Future<Output = Result<()>> + Send;
+}
@@ -0,0 +1,279 @@
+use std::{
+  collections::{HashMap, HashSet},
+  io::Read,
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+
+use teloxide::types::ChatId;
+
+use crate::{
+  database::entities::EntityType,
+  types::{BotType, DbConn},
+};
+
+use super::{
+  archive::{is_archive, upload_media},
+  import::{import_botimport, ParsedExport},
+  progress::Progress,
+  types::{ImportItem, ImportMode, ImportReport, TDesktopExport, TDesktopMessage},
+  zip,
+};
+
+const RESULT_NAME: &str = "result.json";
+
+// Media the user sent, and how often
+struct SentMedia {
+  entity_type: EntityType,
+  // Only there if a tool added the file ids to result.json
+  file_ids: Option<(String, String)>,
+  path: String,
+  count: i64,
+  first_sent: i64,
+  last_sent: i64,
+}
+
+// Adds everything the user sent in a Telegram Desktop export as untagged
+// entities, counting how often each was sent. Telegram Desktop only exports the
+// files themselves, so they are uploaded again to get a file id, which needs the
+// whole export folder as a zip or tar file.
+pub async fn telegram_desktop_import(
+  db: &DbConn,
+  bot: &BotType,
+  user_id: String,
+  chat_id: ChatId,
+  progress: &impl Progress,
+  file: Vec<u8>,
+  journal_id: i64,
+) -> Result<ImportReport> {
+  let mut files = read_export_folder(file)?;
+
+  // The paths in result.json are relative to it, the folder can have any name
+  let result_path = files
+    .keys()
+    .filter(|path| path.rsplit('/').next() == Some(RESULT_NAME))
+    .min_by_key(|path| path.len())
+    .cloned()
+    .context("This is not a Telegram Desktop export, there is no result.json in it")?;
+  let folder = result_path
+    .strip_suffix(RESULT_NAME)
+    .unwrap_or_default()
+    .to_string();
+  let result = files.remove(&result_path).unwrap_or_default();
+  let has_media = !files.is_empty();
+
+  let td_import: TDesktopExport =
+    serde_json::from_slice(&result).context("This is not a Telegram Desktop export")?;
+
+  let sender_id = format!("user{}", user_id);
+  let messages = td_import
+    .messages
+    .into_iter()
+    .chain(
+      [td_import.chats, td_import.left_chats]
+        .into_iter()
+        .flatten()
+        .flat_map(|chats| chats.list)
+        .flat_map(|chat| chat.messages),
+    )
+    .filter(|message| {
+      message.message_type == "message" && message.from_id.as_deref() == Some(sender_id.as_str())
+    });
+
+  let mut report = ImportReport::default();
+  // By file path, or by entity id if the file ids are known
+  let mut sent: HashMap<String, SentMedia> = HashMap::new();
+  // The same missing file is only reported once
+  let mut missing_files: HashSet<String> = HashSet::new();
+
+  for message in messages {
+    let Some(entity_type) = sent_type(&message) else {
+      continue;
+    };
+
+    let sent_at = message
+      .date_unixtime
+      .as_deref()
+      .and_then(|date| date.parse::<i64>().ok())
+      .map(|date| date * 1000)
+      .unwrap_or(0);
+
+    let path = message.file.or(message.photo).unwrap_or_default();
+    let (key, file_ids) = match (message.file_id, message.file_unique_id) {
+      (Some(file_id), Some(entity_id)) => (entity_id.clone(), Some((file_id, entity_id))),
+      _ if files.contains_key(&format!("{}{}", folder, path)) => (path.clone(), None),
+      _ => {
+        // Media that was not exported has a placeholder instead of a path
+        let name = if path.is_empty() || path.starts_with('(') {
+          format!("message {}", message.id)
+        } else {
+          path
+        };
+        if missing_files.insert(name.clone()) {
+          let reason = if has_media {
+            format!(
+              "This {} is not in the export, turn it on in the export settings of Telegram Desktop",
+              type_name(&entity_type)
+            )
+          } else {
+            "Telegram Desktop does not export file ids, send me the whole export folder as a zip file"
+              .to_string()
+          };
+          report.skip(&name, reason);
+        }
+        continue;
+      }
+    };
+
+    let media = sent.entry(key).or_insert(SentMedia {
+      entity_type,
+      file_ids,
+      path: format!("{}{}", folder, path),
+      count: 0,
+      first_sent: sent_at,
+      last_sent: sent_at,
+    });
+    media.count += 1;
+    media.first_sent = media.first_sent.min(sent_at);
+    media.last_sent = media.last_sent.max(sent_at);
+  }
+
+  log::debug!(
+    "Found {} sent files and {} missing files in the Telegram Desktop export",
+    sent.len(),
+    missing_files.len()
+  );
+
+  let mut items: HashMap<String, ImportItem> = HashMap::new();
+  let sent_count = sent.len();
+
+  for (index, media) in sent.into_values().enumerate() {
+    if index % 50 == 0 {
+      progress.check_cancelled().await?;
+      progress
+        .report(format!(
+          "Uploading files {}-{} of {}",
+          index + 1,
+          (index + 50).min(sent_count),
+          sent_count
+        ))
+        .await;
+    }
+
+    let (file_id, entity_id) = match media.file_ids {
+      // Checked like any other import item, an unreadable id is kept with a warning
+      Some(file_ids) => file_ids,
+      None => {
+        let data = files.remove(&media.path).unwrap_or_default();
+        let file_name = media
+          .path
+          .rsplit('/')
+          .next()
+          .unwrap_or_default()
+          .to_string();
+        match upload_media(bot, chat_id, &media.entity_type, data, file_name).await {
+          Ok(file_ids) => file_ids,
+          Err(e) => {
+            log::warn!("Could not upload {:?}: {:?}", media.path, e);
+            report.skip(&media.path, format!("Could not upload the file: {}", e));
+            continue;
+          }
+        }
+      }
+    };
+
+    // The same file can be in the export more than once
+    let item = items.entry(entity_id.clone()).or_insert(ImportItem {
+      entity_id,
+      file_id,
+      entity_type: media.entity_type,
+      tags: Vec::new(),
+      count: 0,
+      last_used: media.last_sent,
+      created_at: media.first_sent,
+    });
+    item.count += media.count;
+    item.last_used = item.last_used.max(media.last_sent);
+    item.created_at = item.created_at.min(media.first_sent);
+  }
+
+  import_botimport(
+    db,
+    user_id,
+    ParsedExport::from_items(items.into_values().collect()),
+    ImportMode::Merge,
+    false,
+    journal_id,
+    report,
+  )
+  .await
+}
+
+fn sent_type(message: &TDesktopMessage) -> Option<EntityType> {
+  match (message.media_type.as_deref(), &message.photo) {
+    (Some("sticker"), _) => Some(EntityType::Sticker),
+    (Some("animation"), _) => Some(EntityType::Animation),
+    (Some("video_file"), _) => Some(EntityType::Video),
+    (None, Some(_)) => Some(EntityType::Photo),
+    _ => None,
+  }
+}
+
+fn type_name(entity_type: &EntityType) -> &'static str {
+  match entity_type {
+    EntityType::Sticker => "sticker",
+    EntityType::Animation => "GIF",
+    EntityType::Photo => "photo",
+    EntityType::Video => "video",
+  }
+}
+
+// The export folder packed as a zip or tar file, or only its result.json
+fn read_export_folder(file: Vec<u8>) -> Result<HashMap<String, Vec<u8>>> {
+  let files = if zip::is_zip(&file) {
+    zip::read_zip(&file)?
+  } else if is_archive(&file) {
+    read_tar(GzDecoder::new(file.as_slice()))?
+  } else if file.get(257..262) == Some(b"ustar".as_slice()) {
+    read_tar(file.as_slice())?
+  } else {
+    return Ok(HashMap::from([(RESULT_NAME.to_string(), file)]));
+  };
+
+  // Some zip tools on Windows use backslashes
+  Ok(
+    files
+      .into_iter()
+      .map(|(path, data)| {
+        let path = path.replace('\\', "/");
+        (path.trim_start_matches("./").to_string(), data)
+      })
+      .collect(),
+  )
+}
+
+fn read_tar<R: Read>(reader: R) -> Result<HashMap<String, Vec<u8>>> {
+  let mut archive = tar::Archive::new(reader);
+  let mut unpacked_size = 0;
+  let mut files = HashMap::new();
+
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    if !entry.header().entry_type().is_file() {
+      continue;
+    }
+
+    unpacked_size += entry.size();
+    if unpacked_size > zip::MAX_UNPACKED_SIZE {
+      bail!("This file is too large when unpacked");
+    }
+
+    let path = entry.path()?.to_string_lossy().to_string();
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    files.insert(path, data);
+  }
+
+  Ok(files)
+}
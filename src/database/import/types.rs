@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 
 use crate::database::entities::EntityType;
 
@@ -16,8 +17,192 @@ pub struct QSBotImportItem {
   pub _is_animated: Option<bool>,
 }
 
+// The result.json of a Telegram Desktop export, either of a single chat or of
+// the whole account
+#[derive(Deserialize, Debug)]
+pub struct TDesktopExport {
+  #[serde(default)]
+  pub messages: Vec<TDesktopMessage>,
+  pub chats: Option<TDesktopChatList>,
+  pub left_chats: Option<TDesktopChatList>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TDesktopChatList {
+  #[serde(default)]
+  pub list: Vec<TDesktopChat>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TDesktopChat {
+  #[serde(default)]
+  pub messages: Vec<TDesktopMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TDesktopMessage {
+  pub id: i64,
+  #[serde(rename = "type")]
+  pub message_type: String,
+  // "user" followed by the user id
+  pub from_id: Option<String>,
+  pub date_unixtime: Option<String>,
+  pub media_type: Option<String>,
+  pub file: Option<String>,
+  pub photo: Option<String>,
+  // Telegram Desktop itself does not export these, but some tools add them
+  pub file_id: Option<String>,
+  pub file_unique_id: Option<String>,
+}
+
 pub type BotImport = Vec<ImportItem>;
 
+// Bump this whenever the export format changes in a way older versions can't read
+// 2: delta exports, with deleted entities
+pub const EXPORT_FORMAT_VERSION: u32 = 2;
+// Full exports did not change, older versions can still read them
+pub const FULL_EXPORT_FORMAT_VERSION: u32 = 1;
+
+// Only the version of an export, this has to keep parsing in every future version
+#[derive(Deserialize, Debug)]
+pub struct ExportVersion {
+  pub header: ExportVersionHeader,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportVersionHeader {
+  pub format_version: u32,
+  pub bot_version: Option<String>,
+  #[serde(default)]
+  pub part: Option<ExportPart>,
+}
+
+// Large exports are split into several files, each one is a complete export of
+// some of the items
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ExportPart {
+  // Shared by all parts of the same export
+  pub export_id: String,
+  // Starts at 0
+  pub index: u32,
+  pub last: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ExportHeader {
+  pub format_version: u32,
+  pub bot_version: String,
+  pub exported_at: i64,
+  // sha256 of the user id keyed with a secret of the bot, empty if the user is
+  // unknown. Missing from exports of versions that didn't add it.
+  #[serde(default)]
+  pub user_hash: String,
+  pub item_count: usize,
+  // sha256 of the items array exactly as it is in the file, followed by the
+  // deleted entity ids if there are any
+  pub checksum: String,
+  // Only set if the export was split
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub part: Option<ExportPart>,
+  // Only set for delta exports, which contain the changes after this time
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub since: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ExportEnvelope {
+  pub header: ExportHeader,
+  pub items: BotImport,
+  // Entity ids removed after the start of a delta export
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub deleted: Vec<String>,
+}
+
+// The envelope as it is read, the checksum is over the bytes in the file and
+// not over what this version of the bot makes of them
+#[derive(Deserialize, Debug)]
+pub struct RawExportEnvelope<'a> {
+  pub header: ExportHeader,
+  #[serde(borrow)]
+  pub items: &'a RawValue,
+  #[serde(borrow, default)]
+  pub deleted: Option<&'a RawValue>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+  // Gzipped json, the most compact and complete
+  #[default]
+  Stimkerbot,
+  Csv,
+  // Pretty printed json with readable timestamps
+  Json,
+}
+
+impl ExportFormat {
+  pub fn file_name(&self) -> &'static str {
+    match self {
+      ExportFormat::Stimkerbot => "export.stimkerbot",
+      ExportFormat::Csv => "export.csv",
+      ExportFormat::Json => "export.json",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+  // Add the imported tags, and keep the highest stats
+  #[default]
+  Merge,
+  // Replace the tags and stats of the imported entities
+  Overwrite,
+  // Wipe everything, and only keep what was imported
+  ReplaceAll,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+  pub new_entities: usize,
+  pub existing_entities: usize,
+  pub removed_entities: usize,
+  // Uploaded again, but Telegram made them into a different file
+  pub remapped_entities: usize,
+  pub tags_created: usize,
+  pub tags_added: usize,
+  pub tags_removed: usize,
+  pub skipped: Vec<SkippedItem>,
+  // Imported, but something about them looked off
+  pub warnings: Vec<SkippedItem>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SkippedItem {
+  pub item: String,
+  pub reason: String,
+}
+
+impl ImportReport {
+  pub fn skip(&mut self, item: &str, reason: String) {
+    log::debug!("Skipping import item {:?}: {}", item, reason);
+
+    self.skipped.push(SkippedItem {
+      item: item.to_string(),
+      reason,
+    });
+  }
+
+  pub fn warn(&mut self, item: &str, reason: String) {
+    log::debug!("Import item {:?} looks off: {}", item, reason);
+
+    self.warnings.push(SkippedItem {
+      item: item.to_string(),
+      reason,
+    });
+  }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ImportItem {
   #[serde(rename = "id")]
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, io::Read};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::DeflateDecoder;
+
+// Reads zip files, which is how most people pack a folder. Only what the zip
+// tools of Windows, macOS and Linux create is supported, so no zip64, encryption
+// or compression methods besides deflate.
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+// The end record is 22 bytes, followed by a comment of atmost 65535 bytes
+const MAX_END_OFFSET: usize = 22 + 65_535;
+
+// Bots can only download 20MB, but deflate can pack a lot more into that
+pub const MAX_UNPACKED_SIZE: u64 = 200_000_000;
+
+pub fn is_zip(file: &[u8]) -> bool {
+  file.starts_with(&LOCAL_HEADER.to_le_bytes())
+}
+
+// Returns every file by its path, directories are left out
+pub fn read_zip(file: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+  let end = (0..=file.len().saturating_sub(22))
+    .rev()
+    .take(MAX_END_OFFSET)
+    .find(|offset| read_u32(file, *offset).ok() == Some(END_OF_CENTRAL_DIRECTORY))
+    .context("This zip file is damaged")?;
+
+  let entry_count = read_u16(file, end + 10)?;
+  let mut offset = read_u32(file, end + 16)? as usize;
+  let mut unpacked_size: u64 = 0;
+  let mut files = HashMap::new();
+
+  for _ in 0..entry_count {
+    if read_u32(file, offset)? != CENTRAL_HEADER {
+      bail!("This zip file is damaged");
+    }
+
+    let flags = read_u16(file, offset + 8)?;
+    let method = read_u16(file, offset + 10)?;
+    let compressed_size = read_u32(file, offset + 20)?;
+    let size = read_u32(file, offset + 24)?;
+    let name_length = read_u16(file, offset + 28)? as usize;
+    let extra_length = read_u16(file, offset + 30)? as usize;
+    let comment_length = read_u16(file, offset + 32)? as usize;
+    let local_offset = read_u32(file, offset + 42)?;
+    let name = String::from_utf8_lossy(slice(file, offset + 46, name_length)?).to_string();
+
+    offset += 46 + name_length + extra_length + comment_length;
+
+    if name.ends_with('/') {
+      continue;
+    }
+    if flags & 1 != 0 {
+      bail!("Encrypted zip files are not supported");
+    }
+    if [compressed_size, size, local_offset].contains(&u32::MAX) {
+      bail!("This zip file is too large, zip64 is not supported");
+    }
+
+    unpacked_size += size as u64;
+    if unpacked_size > MAX_UNPACKED_SIZE {
+      bail!("This zip file is too large when unpacked");
+    }
+
+    let local_offset = local_offset as usize;
+    if read_u32(file, local_offset)? != LOCAL_HEADER {
+      bail!("This zip file is damaged");
+    }
+    let data_offset = local_offset
+      + 30
+      + read_u16(file, local_offset + 26)? as usize
+      + read_u16(file, local_offset + 28)? as usize;
+    let compressed = slice(file, data_offset, compressed_size as usize)?;
+
+    let data = match method {
+      0 => compressed.to_vec(),
+      8 => {
+        let mut data = Vec::with_capacity(size as usize);
+        DeflateDecoder::new(compressed)
+          .take(size as u64)
+          .read_to_end(&mut data)?;
+        data
+      }
+      _ => bail!("{} uses a compression method that is not supported", name),
+    };
+
+    files.insert(name, data);
+  }
+
+  Ok(files)
+}
+
+fn slice(file: &[u8], offset: usize, length: usize) -> Result<&[u8]> {
+  file
+    .get(offset..offset + length)
+    .context("This zip file is damaged")
+}
+
+fn read_u16(file: &[u8], offset: usize) -> Result<u16> {
+  Ok(u16::from_le_bytes(slice(file, offset, 2)?.try_into()?))
+}
+
+fn read_u32(file: &[u8], offset: usize) -> Result<u32> {
+  Ok(u32::from_le_bytes(slice(file, offset, 4)?.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use flate2::{write::DeflateEncoder, Compression};
+
+  use super::*;
+
+  // Packs the files the way zip tools do, deflating every other one
+  fn write_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut central = Vec::new();
+
+    for (index, (name, data)) in files.iter().enumerate() {
+      let (method, packed) = if index % 2 == 1 {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        (8u16, encoder.finish().unwrap())
+      } else {
+        (0u16, data.to_vec())
+      };
+
+      let mut fields = Vec::new();
+      fields.extend(method.to_le_bytes());
+      fields.extend([0; 8]);
+      fields.extend((packed.len() as u32).to_le_bytes());
+      fields.extend((data.len() as u32).to_le_bytes());
+      fields.extend((name.len() as u16).to_le_bytes());
+      fields.extend([0; 2]);
+
+      central.extend(CENTRAL_HEADER.to_le_bytes());
+      central.extend([20, 0, 20, 0, 0, 0]);
+      central.extend(&fields);
+      central.extend([0; 10]);
+      central.extend((zip.len() as u32).to_le_bytes());
+      central.extend(name.as_bytes());
+
+      zip.extend(LOCAL_HEADER.to_le_bytes());
+      zip.extend([20, 0, 0, 0]);
+      zip.extend(&fields);
+      zip.extend(name.as_bytes());
+      zip.extend(&packed);
+    }
+
+    let central_offset = zip.len() as u32;
+    zip.extend(&central);
+    zip.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    zip.extend([0; 4]);
+    zip.extend((files.len() as u16).to_le_bytes());
+    zip.extend((files.len() as u16).to_le_bytes());
+    zip.extend((central.len() as u32).to_le_bytes());
+    zip.extend(central_offset.to_le_bytes());
+    // A comment after the end record
+    zip.extend(6u16.to_le_bytes());
+    zip.extend(b"packed");
+
+    zip
+  }
+
+  #[test]
+  fn read() {
+    let sticker = [b"RIFF".as_slice(), &[0; 100], b"WEBP"].concat();
+    let zip = write_zip(&[
+      ("export/", b""),
+      ("export/result.json", b"{\"messages\": []}"),
+      ("export/stickers/sticker.webp", &sticker),
+    ]);
+
+    assert!(is_zip(&zip));
+    let files = read_zip(&zip).unwrap();
+
+    assert_eq!(files.len(), 2);
+    assert_eq!(files["export/result.json"], b"{\"messages\": []}");
+    assert_eq!(files["export/stickers/sticker.webp"], sticker);
+  }
+
+  #[test]
+  fn damaged() {
+    let zip = write_zip(&[("result.json", b"{}")]);
+
+    assert!(read_zip(&zip[..zip.len() / 2]).is_err());
+    assert!(read_zip(b"PK\x03\x04 not a zip").is_err());
+  }
+}
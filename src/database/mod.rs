@@ -1,4 +1,5 @@
 mod entities;
+pub mod file_id;
 pub mod import;
 pub mod queries;
 
@@ -1,6 +1,17 @@
 use anyhow::Result;
 
-use crate::types::DbConn;
+use crate::{database::EntityType, types::DbConn, util};
+
+use super::journal_tags_removed;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct QuarantinedEntity {
+  pub entity_id: String,
+  pub entity_type: EntityType,
+  pub tags: Option<String>,
+  pub broken_at: i64,
+  pub broken_reason: Option<String>,
+}
 
 pub async fn update_file_id(db: &DbConn, entity_id: String, file_id: String) -> Result<()> {
   log::debug!(
@@ -17,3 +28,255 @@ pub async fn update_file_id(db: &DbConn, entity_id: String, file_id: String) ->
 
   Ok(())
 }
+
+// Hides an entity whose file does not work anymore, for every user
+pub async fn quarantine_entity(db: &DbConn, entity_id: String, reason: String) -> Result<()> {
+  log::debug!(
+    "quarantine_entity for entity_id: {:?} because: {:?}",
+    entity_id,
+    reason
+  );
+
+  sqlx::query("UPDATE entity_file SET broken_at = $2, broken_reason = $3 WHERE entity_id = $1")
+    .bind(entity_id)
+    .bind(util::get_unix())
+    .bind(reason)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// Gives a quarantined entity a working file again, returns false if it was not
+// quarantined
+pub async fn relink_entity(db: &DbConn, entity_id: String, file_id: String) -> Result<bool> {
+  log::debug!(
+    "relink_entity for entity_id: {:?} and file_id: {:?}",
+    entity_id,
+    file_id
+  );
+
+  let result = sqlx::query(
+    "UPDATE entity_file SET file_id = $2, broken_at = NULL, broken_reason = NULL \
+      WHERE entity_id = $1 AND broken_at IS NOT NULL",
+  )
+  .bind(entity_id)
+  .bind(file_id)
+  .execute(db)
+  .await?;
+
+  Ok(result.rows_affected() > 0)
+}
+
+pub async fn list_quarantined_entities(
+  db: &DbConn,
+  user_id: String,
+) -> Result<Vec<QuarantinedEntity>> {
+  log::debug!("list_quarantined_entities for user_id: {:?}", user_id);
+
+  let entities: Vec<QuarantinedEntity> = sqlx::query_as(
+    "SELECT entity_data.entity_id, entity_file.entity_type, entity_file.broken_at, entity_file.broken_reason, \
+      (SELECT group_concat(entity_tag.tag_name, ' ') FROM entity_main \
+        JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
+        WHERE entity_main.combo_id = entity_data.combo_id) AS tags \
+      FROM active_entity_data AS entity_data \
+      JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+      WHERE entity_data.user_id = $1 AND entity_file.broken_at IS NOT NULL \
+      ORDER BY entity_file.broken_at DESC",
+  )
+  .bind(user_id)
+  .fetch_all(db)
+  .await?;
+
+  Ok(entities)
+}
+
+// Removes the quarantined entities of a user, the tags can still be brought back
+// with the journal. Returns how many were removed.
+pub async fn purge_quarantine(db: &DbConn, user_id: String, journal_id: i64) -> Result<usize> {
+  log::debug!("purge_quarantine for user_id: {:?}", user_id);
+
+  let mut transaction = db.begin().await?;
+
+  let entity_ids: Vec<String> = sqlx::query_scalar(
+    "SELECT entity_data.entity_id FROM entity_data \
+      JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+      WHERE entity_data.user_id = $1 AND entity_file.broken_at IS NOT NULL",
+  )
+  .bind(&user_id)
+  .fetch_all(transaction.as_mut())
+  .await?;
+
+  for chunk in entity_ids.chunks(1000) {
+    journal_tags_removed(transaction.as_mut(), journal_id, &user_id, chunk, None).await?;
+  }
+
+  sqlx::query(
+    "DELETE FROM entity_data WHERE user_id = $1 AND entity_id IN \
+      (SELECT entity_id FROM entity_file WHERE broken_at IS NOT NULL)",
+  )
+  .bind(&user_id)
+  .execute(transaction.as_mut())
+  .await?;
+
+  // Nobody can relink a broken file that no user has anymore, but /undo needs
+  // it until the journal entry expires
+  sqlx::query(
+    "DELETE FROM entity_file WHERE broken_at IS NOT NULL \
+      AND entity_id NOT IN (SELECT entity_id FROM entity_data) \
+      AND entity_id NOT IN (SELECT entity_id FROM tag_journal_entry)",
+  )
+  .execute(transaction.as_mut())
+  .await?;
+
+  transaction.commit().await?;
+
+  Ok(entity_ids.len())
+}
+
+// The file that was checked the longest ago, if that was before checked_before
+pub async fn get_file_to_check(
+  db: &DbConn,
+  checked_before: i64,
+) -> Result<Option<(String, String)>> {
+  log::debug!("get_file_to_check checked before: {:?}", checked_before);
+
+  let file: Option<(String, String)> = sqlx::query_as(
+    "SELECT entity_id, file_id FROM entity_file WHERE last_checked < $1 \
+      ORDER BY last_checked ASC LIMIT 1",
+  )
+  .bind(checked_before)
+  .fetch_optional(db)
+  .await?;
+
+  Ok(file)
+}
+
+// The next entities of a user to check, in the order of their ids
+pub async fn get_entities_to_fix(
+  db: &DbConn,
+  user_id: String,
+  after_entity_id: String,
+  limit: i64,
+) -> Result<Vec<(String, String)>> {
+  log::debug!(
+    "get_entities_to_fix for user_id: {:?} after {:?}",
+    user_id,
+    after_entity_id
+  );
+
+  let entities: Vec<(String, String)> = sqlx::query_as(
+    "SELECT entity_data.entity_id, entity_file.file_id FROM active_entity_data AS entity_data \
+      JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+      WHERE entity_data.user_id = $1 AND entity_data.entity_id > $2 \
+      AND entity_file.broken_at IS NULL \
+      ORDER BY entity_data.entity_id LIMIT $3",
+  )
+  .bind(user_id)
+  .bind(after_entity_id)
+  .bind(limit)
+  .fetch_all(db)
+  .await?;
+
+  Ok(entities)
+}
+
+pub async fn count_entities_to_fix(db: &DbConn, user_id: String) -> Result<i64> {
+  log::debug!("count_entities_to_fix for user_id: {:?}", user_id);
+
+  let count: i64 = sqlx::query_scalar(
+    "SELECT COUNT(*) FROM active_entity_data AS entity_data \
+      JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+      WHERE entity_data.user_id = $1 AND entity_file.broken_at IS NULL",
+  )
+  .bind(user_id)
+  .fetch_one(db)
+  .await?;
+
+  Ok(count)
+}
+
+// What the last check of a file with Telegram found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+  Ok,
+  Broken,
+  // Telegram could not answer, e.g. the file is too big to get
+  Error,
+}
+
+impl FileStatus {
+  fn as_str(&self) -> &'static str {
+    match self {
+      FileStatus::Ok => "ok",
+      FileStatus::Broken => "broken",
+      FileStatus::Error => "error",
+    }
+  }
+}
+
+pub async fn set_file_checked(db: &DbConn, entity_id: String, status: FileStatus) -> Result<()> {
+  log::debug!(
+    "set_file_checked for entity_id: {:?} status: {:?}",
+    entity_id,
+    status
+  );
+
+  sqlx::query("UPDATE entity_file SET last_checked = $2, check_status = $3 WHERE entity_id = $1")
+    .bind(entity_id)
+    .bind(util::get_unix())
+    .bind(status.as_str())
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+pub async fn get_file_id(db: &DbConn, entity_id: String) -> Result<Option<String>> {
+  log::debug!("get_file_id for entity_id: {:?}", entity_id);
+
+  let file_id: Option<String> = sqlx::query_scalar(
+    "SELECT file_id FROM entity_file WHERE entity_id = $1 AND broken_at IS NULL",
+  )
+  .bind(entity_id)
+  .fetch_optional(db)
+  .await?;
+
+  Ok(file_id)
+}
+
+// Forget an entity for a user entirely, including its usage stats
+pub async fn delete_entity(
+  db: &DbConn,
+  user_id: String,
+  entity_id: String,
+  journal_id: i64,
+) -> Result<()> {
+  log::debug!(
+    "delete_entity for entity_id: {:?} and user_id: {:?}",
+    entity_id,
+    user_id
+  );
+
+  let mut transaction = db.begin().await?;
+
+  // Only the tags can be brought back, the usage statistics are gone for good
+  journal_tags_removed(
+    transaction.as_mut(),
+    journal_id,
+    &user_id,
+    std::slice::from_ref(&entity_id),
+    None,
+  )
+  .await?;
+
+  sqlx::query("DELETE FROM entity_data WHERE entity_id = $1 AND user_id = $2")
+    .bind(&entity_id)
+    .bind(&user_id)
+    .execute(transaction.as_mut())
+    .await?;
+
+  transaction.commit().await?;
+
+  Ok(())
+}
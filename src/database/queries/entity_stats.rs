@@ -44,7 +44,7 @@ pub async fn get_entity_usage(
   );
 
   let result: Option<EntityStat> = sqlx::query_as(
-    "SELECT * FROM entity_data \
+    "SELECT * FROM active_entity_data \
       WHERE user_id = $1 AND entity_id = $2",
   )
   .bind(user_id.clone())
@@ -1,16 +1,20 @@
 use anyhow::Result;
 
 use crate::{
-  database::{queries::GlobalStats, EntityType},
+  database::{
+    queries::{DetailedStats, GlobalStats},
+    EntityType,
+  },
   types::DbConn,
 };
 
 pub async fn get_global_stats(db: &DbConn) -> Result<GlobalStats> {
   log::debug!("get_global_stats");
 
-  let total_users: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT user_id) FROM entity_data")
-    .fetch_one(db)
-    .await?;
+  let total_users: i64 =
+    sqlx::query_scalar("SELECT COUNT(DISTINCT user_id) FROM active_entity_data")
+      .fetch_one(db)
+      .await?;
 
   // count rows in entity_file
   let (total_stickers, total_animations, total_videos, total_photos): (i64, i64, i64, i64) =
@@ -32,7 +36,7 @@ pub async fn get_global_stats(db: &DbConn) -> Result<GlobalStats> {
     .fetch_one(db)
     .await?;
 
-  let total_entities_sent: i64 = sqlx::query_scalar("SELECT SUM(count) FROM entity_data")
+  let total_entities_sent: i64 = sqlx::query_scalar("SELECT SUM(count) FROM active_entity_data")
     .fetch_one(db)
     .await?;
 
@@ -50,3 +54,77 @@ pub async fn get_global_stats(db: &DbConn) -> Result<GlobalStats> {
 
   Ok(result)
 }
+
+// Everything an admin wants to know about the bot, users are active when they
+// used or changed something after active_since
+pub async fn get_detailed_stats(db: &DbConn, active_since: i64) -> Result<DetailedStats> {
+  log::debug!("get_detailed_stats active since: {:?}", active_since);
+
+  let (total_users, active_users, inactive_users, pending_deletion_users): (i64, i64, i64, i64) =
+    sqlx::query_as(
+      "SELECT \
+        (SELECT COUNT(DISTINCT user_id) FROM active_entity_data), \
+        (SELECT COUNT(DISTINCT user_id) FROM active_entity_data \
+          WHERE last_used >= $1 OR updated_at >= $1), \
+        (SELECT COUNT(*) FROM user_data WHERE inactive_at IS NOT NULL), \
+        (SELECT COUNT(*) FROM user_data WHERE deleted_at IS NOT NULL)",
+    )
+    .bind(active_since)
+    .fetch_one(db)
+    .await?;
+
+  let (total_entities, total_files, quarantined_files, untagged_entities): (i64, i64, i64, i64) =
+    sqlx::query_as(
+      "SELECT \
+        (SELECT COUNT(*) FROM entity_data), \
+        (SELECT COUNT(*) FROM entity_file), \
+        (SELECT COUNT(*) FROM entity_file WHERE broken_at IS NOT NULL), \
+        (SELECT COUNT(*) FROM entity_data WHERE NOT EXISTS \
+          (SELECT 1 FROM entity_main WHERE entity_main.combo_id = entity_data.combo_id))",
+    )
+    .fetch_one(db)
+    .await?;
+
+  let (total_tags, total_tag_links): (i64, i64) =
+    sqlx::query_as("SELECT (SELECT COUNT(*) FROM entity_tag), (SELECT COUNT(*) FROM entity_main)")
+      .fetch_one(db)
+      .await?;
+
+  let (queued_jobs, running_jobs, failed_jobs): (i64, i64, i64) = sqlx::query_as(
+    "SELECT \
+      (SELECT COUNT(*) FROM job WHERE status = 'queued'), \
+      (SELECT COUNT(*) FROM job WHERE status = 'running'), \
+      (SELECT COUNT(*) FROM job WHERE status = 'failed')",
+  )
+  .fetch_one(db)
+  .await?;
+
+  let (database_size, database_free): (i64, i64) = sqlx::query_as(
+    "SELECT page_count * page_size, freelist_count * page_size \
+      FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+  )
+  .fetch_one(db)
+  .await?;
+
+  let result = DetailedStats {
+    total_users,
+    active_users,
+    inactive_users,
+    pending_deletion_users,
+    total_entities,
+    total_files,
+    quarantined_files,
+    untagged_entities,
+    total_tags,
+    total_tag_links,
+    queued_jobs,
+    running_jobs,
+    failed_jobs,
+    database_size,
+    database_free,
+  };
+
+  log::debug!("get_detailed_stats result: {:?}", result);
+
+  Ok(result)
+}
@@ -0,0 +1,40 @@
+use anyhow::Result;
+
+use crate::types::DbConn;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct TagHistoryEntry {
+  pub tag_name: String,
+  pub added: bool,
+  pub source: String,
+  pub created_at: i64,
+}
+
+pub async fn get_entity_history(
+  db: &DbConn,
+  user_id: String,
+  entity_id: String,
+  limit: i64,
+) -> Result<Vec<TagHistoryEntry>> {
+  log::debug!(
+    "get_entity_history for entity_id: {:?} and user_id: {:?}",
+    entity_id,
+    user_id
+  );
+
+  let result: Vec<TagHistoryEntry> = sqlx::query_as(
+    "SELECT tag_name, added, source, created_at FROM tag_history \
+      WHERE user_id = $1 AND entity_id = $2 \
+      ORDER BY history_id DESC \
+      LIMIT $3",
+  )
+  .bind(user_id)
+  .bind(entity_id)
+  .bind(limit)
+  .fetch_all(db)
+  .await?;
+
+  log::debug!("get_entity_history result: {:?}", result);
+
+  Ok(result)
+}
@@ -0,0 +1,246 @@
+use anyhow::Result;
+use sqlx::QueryBuilder;
+
+use crate::{types::DbConn, util};
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Job {
+  pub job_id: i64,
+  pub user_id: String,
+  pub chat_id: i64,
+  pub message_id: i32,
+  pub kind: String,
+  pub payload: String,
+  pub status: String,
+  pub progress: String,
+  pub checkpoint: Option<String>,
+  pub created_at: i64,
+  pub finished_at: Option<i64>,
+}
+
+const JOB_COLUMNS: &str = "job_id, user_id, chat_id, message_id, kind, payload, status, progress, \
+  checkpoint, created_at, finished_at";
+
+pub async fn create_job(
+  db: &DbConn,
+  user_id: String,
+  chat_id: i64,
+  message_id: i32,
+  kind: &str,
+  payload: String,
+) -> Result<i64> {
+  log::debug!("create_job {:?} for user_id: {:?}", kind, user_id);
+
+  let job_id: i64 = sqlx::query_scalar(
+    "INSERT INTO job (user_id, chat_id, message_id, kind, payload, created_at) \
+      VALUES ($1, $2, $3, $4, $5, $6) RETURNING job_id",
+  )
+  .bind(user_id)
+  .bind(chat_id)
+  .bind(message_id)
+  .bind(kind)
+  .bind(payload)
+  .bind(util::get_unix())
+  .fetch_one(db)
+  .await?;
+
+  Ok(job_id)
+}
+
+// Marks the oldest queued job as running, skipping users that already have
+// max_running_per_user jobs running. With only_users, the jobs of everyone
+// else stay queued. Jobs of separate_kind have a worker of their own, they are
+// neither claimed here nor counted against the limit.
+pub async fn claim_job(
+  db: &DbConn,
+  max_running_per_user: i64,
+  only_users: Option<&[String]>,
+  separate_kind: &str,
+) -> Result<Option<Job>> {
+  log::trace!("claim_job");
+
+  let mut query = QueryBuilder::new(
+    "UPDATE job SET status = 'running' WHERE job_id = \
+      (SELECT job_id FROM job AS queued WHERE status = 'queued' AND kind != ",
+  );
+  query.push_bind(separate_kind);
+  query.push(
+    " AND (SELECT COUNT(*) FROM job AS running \
+      WHERE running.user_id = queued.user_id AND running.status = 'running' \
+        AND running.kind != ",
+  );
+  query.push_bind(separate_kind);
+  query.push(") < ");
+  query.push_bind(max_running_per_user);
+  if let Some(only_users) = only_users {
+    query.push(" AND queued.user_id IN (");
+    let mut seperator = query.separated(", ");
+    only_users.iter().for_each(|user_id| {
+      seperator.push_bind(user_id);
+    });
+    query.push(")");
+  }
+  query.push(" ORDER BY job_id LIMIT 1) RETURNING ");
+  query.push(JOB_COLUMNS);
+
+  let job = query.build_query_as::<Job>().fetch_optional(db).await?;
+
+  Ok(job)
+}
+
+// Marks the oldest queued job of a kind as running, for the worker of that kind
+pub async fn claim_job_of_kind(db: &DbConn, kind: &str) -> Result<Option<Job>> {
+  log::trace!("claim_job_of_kind {:?}", kind);
+
+  let job = sqlx::query_as::<_, Job>(&format!(
+    "UPDATE job SET status = 'running' WHERE job_id = \
+      (SELECT job_id FROM job WHERE status = 'queued' AND kind = $1 ORDER BY job_id LIMIT 1) \
+      RETURNING {}",
+    JOB_COLUMNS
+  ))
+  .bind(kind)
+  .fetch_optional(db)
+  .await?;
+
+  Ok(job)
+}
+
+// Jobs that were running when the bot stopped have to start again
+pub async fn requeue_running_jobs(db: &DbConn) -> Result<u64> {
+  log::debug!("requeue_running_jobs");
+
+  let result = sqlx::query("UPDATE job SET status = 'queued' WHERE status = 'running'")
+    .execute(db)
+    .await?;
+
+  Ok(result.rows_affected())
+}
+
+// The queued and running jobs of a user, and the ones that finished recently
+pub async fn list_jobs(db: &DbConn, user_id: String, finished_limit: i64) -> Result<Vec<Job>> {
+  log::debug!("list_jobs for user_id: {:?}", user_id);
+
+  let jobs = sqlx::query_as::<_, Job>(&format!(
+    "SELECT {0} FROM job WHERE user_id = $1 AND status IN ('queued', 'running') \
+      UNION ALL \
+      SELECT * FROM (SELECT {0} FROM job WHERE user_id = $1 AND status NOT IN ('queued', 'running') \
+        ORDER BY job_id DESC LIMIT $2) \
+      ORDER BY job_id DESC",
+    JOB_COLUMNS
+  ))
+  .bind(user_id)
+  .bind(finished_limit)
+  .fetch_all(db)
+  .await?;
+
+  Ok(jobs)
+}
+
+pub async fn count_active_jobs(db: &DbConn, user_id: String, kind: Option<&str>) -> Result<i64> {
+  log::debug!("count_active_jobs {:?} for user_id: {:?}", kind, user_id);
+
+  let count: i64 = sqlx::query_scalar(
+    "SELECT COUNT(*) FROM job WHERE user_id = $1 AND status IN ('queued', 'running') \
+      AND ($2 IS NULL OR kind = $2)",
+  )
+  .bind(user_id)
+  .bind(kind)
+  .fetch_one(db)
+  .await?;
+
+  Ok(count)
+}
+
+pub async fn is_job_cancelled(db: &DbConn, job_id: i64) -> Result<bool> {
+  log::trace!("is_job_cancelled for job_id: {:?}", job_id);
+
+  let cancelled: Option<bool> = sqlx::query_scalar("SELECT cancelled FROM job WHERE job_id = $1")
+    .bind(job_id)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(cancelled.unwrap_or(true))
+}
+
+pub async fn set_job_progress(db: &DbConn, job_id: i64, progress: &str) -> Result<()> {
+  log::debug!("set_job_progress for job_id: {:?}", job_id);
+
+  sqlx::query("UPDATE job SET progress = $2 WHERE job_id = $1")
+    .bind(job_id)
+    .bind(progress)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// Saves how far the job got, so it can continue from here after a restart
+pub async fn set_job_checkpoint(db: &DbConn, job_id: i64, checkpoint: String) -> Result<()> {
+  log::debug!("set_job_checkpoint for job_id: {:?}", job_id);
+
+  sqlx::query("UPDATE job SET checkpoint = $2 WHERE job_id = $1")
+    .bind(job_id)
+    .bind(checkpoint)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+pub async fn finish_job(db: &DbConn, job_id: i64, status: &str, progress: &str) -> Result<()> {
+  log::debug!(
+    "finish_job for job_id: {:?} with status {:?}",
+    job_id,
+    status
+  );
+
+  sqlx::query(
+    "UPDATE job SET status = $2, progress = $3, checkpoint = NULL, finished_at = $4 \
+      WHERE job_id = $1",
+  )
+  .bind(job_id)
+  .bind(status)
+  .bind(progress)
+  .bind(util::get_unix())
+  .execute(db)
+  .await?;
+
+  Ok(())
+}
+
+// Queued jobs are cancelled right away, running jobs stop at their next
+// checkpoint. Cancels every job of the user without a job_id, returns how many
+// were cancelled.
+pub async fn cancel_jobs(db: &DbConn, user_id: String, job_id: Option<i64>) -> Result<u64> {
+  log::debug!(
+    "cancel_jobs for user_id: {:?} job_id: {:?}",
+    user_id,
+    job_id
+  );
+
+  let result = sqlx::query(
+    "UPDATE job SET cancelled = 1, \
+      finished_at = CASE status WHEN 'queued' THEN $3 ELSE finished_at END, \
+      status = CASE status WHEN 'queued' THEN 'cancelled' ELSE status END \
+      WHERE user_id = $1 AND ($2 IS NULL OR job_id = $2) \
+      AND status IN ('queued', 'running') AND cancelled = 0",
+  )
+  .bind(user_id)
+  .bind(job_id)
+  .bind(util::get_unix())
+  .execute(db)
+  .await?;
+
+  Ok(result.rows_affected())
+}
+
+pub async fn delete_jobs_finished_before(db: &DbConn, before: i64) -> Result<u64> {
+  log::debug!("delete_jobs_finished_before {:?}", before);
+
+  let result = sqlx::query("DELETE FROM job WHERE finished_at < $1")
+    .bind(before)
+    .execute(db)
+    .await?;
+
+  Ok(result.rows_affected())
+}
@@ -0,0 +1,334 @@
+use anyhow::Result;
+use sqlx::{QueryBuilder, SqliteConnection};
+
+use crate::{types::DbConn, util};
+
+// How many operations per user can be undone
+const JOURNAL_DEPTH: i64 = 10;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct JournalOperation {
+  pub journal_id: i64,
+  pub action: String,
+  pub created_at: i64,
+}
+
+// Starts a new undoable operation, all tag changes made with the returned id are
+// reverted together
+pub async fn start_journal(db: &DbConn, user_id: String, action: &str) -> Result<i64> {
+  log::debug!("start_journal: {:?} for user_id: {:?}", action, user_id);
+
+  let mut transaction = db.begin().await?;
+
+  // Operations that never changed anything are not worth keeping, unless they
+  // are recent, a job could still be about to make its changes
+  sqlx::query(
+    "DELETE FROM tag_journal WHERE user_id = $1 AND created_at < $2 \
+      AND journal_id NOT IN (SELECT journal_id FROM tag_journal_entry) \
+      AND journal_id NOT IN (SELECT journal_id FROM tag_journal_entity)",
+  )
+  .bind(&user_id)
+  .bind(util::get_unix() - 24 * 60 * 60 * 1000)
+  .execute(transaction.as_mut())
+  .await?;
+
+  // Keep only the newest operations
+  sqlx::query(
+    "DELETE FROM tag_journal WHERE journal_id IN \
+      (SELECT journal_id FROM tag_journal WHERE user_id = $1 \
+      ORDER BY journal_id DESC LIMIT -1 OFFSET $2)",
+  )
+  .bind(&user_id)
+  .bind(JOURNAL_DEPTH - 1)
+  .execute(transaction.as_mut())
+  .await?;
+
+  let journal_id: i64 = sqlx::query_scalar(
+    "INSERT INTO tag_journal (user_id, action, created_at) \
+      VALUES ($1, $2, $3) RETURNING journal_id",
+  )
+  .bind(&user_id)
+  .bind(action)
+  .bind(util::get_unix())
+  .fetch_one(transaction.as_mut())
+  .await?;
+
+  transaction.commit().await?;
+
+  Ok(journal_id)
+}
+
+// Records which of these tags the entities do not have yet, must be called right
+// before the tags are actually added
+pub async fn journal_tags_added(
+  conn: &mut SqliteConnection,
+  journal_id: i64,
+  user_id: &str,
+  entity_ids: &[String],
+  tag_names: &[String],
+) -> Result<()> {
+  if entity_ids.is_empty() || tag_names.is_empty() {
+    return Ok(());
+  }
+
+  record_changes(conn, journal_id, user_id, entity_ids, Some(tag_names), true).await
+}
+
+// Records which of these tags the entities currently have, must be called right
+// before the tags are actually removed. Passing no tag names records all tags.
+pub async fn journal_tags_removed(
+  conn: &mut SqliteConnection,
+  journal_id: i64,
+  user_id: &str,
+  entity_ids: &[String],
+  tag_names: Option<&[String]>,
+) -> Result<()> {
+  if entity_ids.is_empty() || tag_names.is_some_and(|tag_names| tag_names.is_empty()) {
+    return Ok(());
+  }
+
+  record_changes(conn, journal_id, user_id, entity_ids, tag_names, false).await
+}
+
+// Records which of these entities the user does not have yet, must be called
+// right before they are added. Undoing the operation removes them again.
+pub async fn journal_entities_added(
+  conn: &mut SqliteConnection,
+  journal_id: i64,
+  user_id: &str,
+  entity_ids: &[String],
+) -> Result<()> {
+  if entity_ids.is_empty() {
+    return Ok(());
+  }
+
+  let mut query_builder = QueryBuilder::new("WITH new_entity (entity_id) AS (VALUES ");
+  let mut seperator = query_builder.separated(", ");
+  entity_ids.iter().for_each(|entity_id| {
+    seperator.push("(");
+    seperator.push_bind_unseparated(entity_id);
+    seperator.push_unseparated(")");
+  });
+  query_builder.push(") INSERT OR IGNORE INTO tag_journal_entity (journal_id, entity_id) SELECT ");
+  query_builder.push_bind(journal_id);
+  query_builder.push(
+    ", entity_id FROM new_entity \
+      WHERE entity_id NOT IN (SELECT entity_id FROM entity_data WHERE user_id = ",
+  );
+  query_builder.push_bind(user_id);
+  query_builder.push(")");
+  query_builder.build().execute(&mut *conn).await?;
+
+  Ok(())
+}
+
+async fn record_changes(
+  conn: &mut SqliteConnection,
+  journal_id: i64,
+  user_id: &str,
+  entity_ids: &[String],
+  tag_names: Option<&[String]>,
+  added: bool,
+) -> Result<()> {
+  // The history keeps every change, even those that cancel out within one operation
+  let mut history_query = QueryBuilder::new(
+    "INSERT INTO tag_history (user_id, entity_id, tag_name, added, source, created_at) \
+      SELECT entity_data.user_id, entity_data.entity_id, entity_tag.tag_name, ",
+  );
+  history_query.push_bind(added);
+  history_query.push(", (SELECT action FROM tag_journal WHERE journal_id = ");
+  history_query.push_bind(journal_id);
+  history_query.push("), ");
+  history_query.push_bind(util::get_unix());
+  push_changed_pairs(&mut history_query, user_id, entity_ids, tag_names, added);
+  history_query.build().execute(&mut *conn).await?;
+
+  let mut record_query = QueryBuilder::new(
+    "INSERT OR IGNORE INTO tag_journal_entry (journal_id, entity_id, tag_name, added) SELECT ",
+  );
+  record_query.push_bind(journal_id);
+  record_query.push(", entity_data.entity_id, entity_tag.tag_name, ");
+  record_query.push_bind(added);
+  push_changed_pairs(&mut record_query, user_id, entity_ids, tag_names, added);
+  record_query.build().execute(&mut *conn).await?;
+
+  // A tag that was removed earlier in this operation and now added back, or the
+  // other way around, is no change at all
+  let mut cancel_query = QueryBuilder::new("DELETE FROM tag_journal_entry WHERE added = ");
+  cancel_query.push_bind(!added);
+  cancel_query.push(" AND journal_id = ");
+  cancel_query.push_bind(journal_id);
+  cancel_query.push(" AND entity_id IN (");
+  let mut seperator = cancel_query.separated(", ");
+  entity_ids.iter().for_each(|entity_id| {
+    seperator.push_bind(entity_id);
+  });
+  cancel_query.push(")");
+  if let Some(tag_names) = tag_names {
+    cancel_query.push(" AND tag_name IN (");
+    let mut seperator = cancel_query.separated(", ");
+    tag_names.iter().for_each(|tag_name| {
+      seperator.push_bind(tag_name);
+    });
+    cancel_query.push(")");
+  }
+  cancel_query.build().execute(&mut *conn).await?;
+
+  Ok(())
+}
+
+// Selects the entity/tag pairs that are about to change, in the current state
+// of the database
+fn push_changed_pairs<'a>(
+  query_builder: &mut QueryBuilder<'a, sqlx::Sqlite>,
+  user_id: &'a str,
+  entity_ids: &'a [String],
+  tag_names: Option<&'a [String]>,
+  added: bool,
+) {
+  if added {
+    query_builder.push(
+      " FROM entity_data, entity_tag \
+        WHERE NOT EXISTS (SELECT 1 FROM entity_main \
+        WHERE entity_main.combo_id = entity_data.combo_id AND entity_main.tag_id = entity_tag.tag_id) \
+        AND entity_data.user_id = ",
+    );
+  } else {
+    query_builder.push(
+      " FROM entity_main \
+        JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
+        JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+        WHERE entity_data.user_id = ",
+    );
+  }
+  query_builder.push_bind(user_id);
+  push_entity_tag_filter(query_builder, entity_ids, tag_names);
+}
+
+fn push_entity_tag_filter<'a>(
+  query_builder: &mut QueryBuilder<'a, sqlx::Sqlite>,
+  entity_ids: &'a [String],
+  tag_names: Option<&'a [String]>,
+) {
+  query_builder.push(" AND entity_data.entity_id IN (");
+  let mut seperator = query_builder.separated(", ");
+  entity_ids.iter().for_each(|entity_id| {
+    seperator.push_bind(entity_id);
+  });
+  query_builder.push(")");
+
+  if let Some(tag_names) = tag_names {
+    query_builder.push(" AND entity_tag.tag_name IN (");
+    let mut seperator = query_builder.separated(", ");
+    tag_names.iter().for_each(|tag_name| {
+      seperator.push_bind(tag_name);
+    });
+    query_builder.push(")");
+  }
+}
+
+// Reverts the newest operation of a user, returns None if there is nothing left to undo
+pub async fn undo_journal(db: &DbConn, user_id: String) -> Result<Option<JournalOperation>> {
+  log::debug!("undo_journal for user_id: {:?}", user_id);
+
+  let mut transaction = db.begin().await?;
+
+  let operation: Option<JournalOperation> = sqlx::query_as(
+    "SELECT journal_id, action, created_at FROM tag_journal \
+      WHERE user_id = $1 AND (journal_id IN (SELECT journal_id FROM tag_journal_entry) \
+        OR journal_id IN (SELECT journal_id FROM tag_journal_entity)) \
+      ORDER BY journal_id DESC LIMIT 1",
+  )
+  .bind(&user_id)
+  .fetch_optional(transaction.as_mut())
+  .await?;
+
+  let Some(operation) = operation else {
+    return Ok(None);
+  };
+
+  log::debug!("undo_journal: reverting {:?}", operation);
+
+  // Remove everything that was added
+  sqlx::query(
+    "DELETE FROM entity_main WHERE EXISTS (SELECT 1 FROM tag_journal_entry \
+      JOIN entity_data ON entity_data.entity_id = tag_journal_entry.entity_id \
+      JOIN entity_tag ON entity_tag.tag_name = tag_journal_entry.tag_name \
+      WHERE tag_journal_entry.journal_id = $1 AND tag_journal_entry.added = 1 \
+      AND entity_data.user_id = $2 \
+      AND entity_data.combo_id = entity_main.combo_id AND entity_tag.tag_id = entity_main.tag_id)",
+  )
+  .bind(operation.journal_id)
+  .bind(&user_id)
+  .execute(transaction.as_mut())
+  .await?;
+
+  // Entities that were new are removed completely, before anything removed is
+  // added back, as replacing everything can remove and add the same entity
+  sqlx::query(
+    "DELETE FROM entity_data WHERE user_id = $2 \
+      AND entity_id IN (SELECT entity_id FROM tag_journal_entity WHERE journal_id = $1)",
+  )
+  .bind(operation.journal_id)
+  .bind(&user_id)
+  .execute(transaction.as_mut())
+  .await?;
+
+  // Add back everything that was removed, the entity or tag could be gone by now
+  sqlx::query(
+    "INSERT OR IGNORE INTO entity_data (user_id, entity_id, created_at) \
+      SELECT DISTINCT $2, entity_id, $3 FROM tag_journal_entry \
+      WHERE journal_id = $1 AND added = 0 \
+      AND entity_id IN (SELECT entity_id FROM entity_file)",
+  )
+  .bind(operation.journal_id)
+  .bind(&user_id)
+  .bind(util::get_unix())
+  .execute(transaction.as_mut())
+  .await?;
+
+  sqlx::query(
+    "INSERT OR IGNORE INTO entity_tag (tag_name) \
+      SELECT DISTINCT tag_name FROM tag_journal_entry \
+      WHERE journal_id = $1 AND added = 0",
+  )
+  .bind(operation.journal_id)
+  .execute(transaction.as_mut())
+  .await?;
+
+  sqlx::query(
+    "INSERT OR IGNORE INTO entity_main (combo_id, tag_id) \
+      SELECT entity_data.combo_id, entity_tag.tag_id FROM tag_journal_entry \
+      JOIN entity_data ON entity_data.entity_id = tag_journal_entry.entity_id \
+      JOIN entity_tag ON entity_tag.tag_name = tag_journal_entry.tag_name \
+      WHERE tag_journal_entry.journal_id = $1 AND tag_journal_entry.added = 0 \
+      AND entity_data.user_id = $2",
+  )
+  .bind(operation.journal_id)
+  .bind(&user_id)
+  .execute(transaction.as_mut())
+  .await?;
+
+  sqlx::query(
+    "INSERT INTO tag_history (user_id, entity_id, tag_name, added, source, created_at) \
+      SELECT $2, entity_id, tag_name, 1 - added, $3, $4 FROM tag_journal_entry \
+      WHERE journal_id = $1",
+  )
+  .bind(operation.journal_id)
+  .bind(&user_id)
+  .bind(format!("Undo of {}", operation.action.to_lowercase()))
+  .bind(util::get_unix())
+  .execute(transaction.as_mut())
+  .await?;
+
+  sqlx::query("DELETE FROM tag_journal WHERE journal_id = $1")
+    .bind(operation.journal_id)
+    .execute(transaction.as_mut())
+    .await?;
+
+  transaction.commit().await?;
+
+  log::debug!("undo_journal: reverted {:?}", operation);
+
+  Ok(Some(operation))
+}
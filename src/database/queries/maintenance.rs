@@ -0,0 +1,368 @@
+use std::{
+  collections::{BTreeMap, HashSet},
+  fmt,
+};
+
+use anyhow::Result;
+use sqlx::{QueryBuilder, SqliteConnection};
+
+use crate::{types::DbConn, util};
+
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+  // Each tag that others were merged into, with the tags that were merged
+  pub merged_tags: Vec<(String, Vec<String>)>,
+  pub orphan_tag_links: u64,
+  pub unused_tags: u64,
+  pub unused_files: u64,
+}
+
+impl IntegrityReport {
+  pub fn is_clean(&self) -> bool {
+    self.merged_tags.is_empty()
+      && self.orphan_tag_links == 0
+      && self.unused_tags == 0
+      && self.unused_files == 0
+  }
+}
+
+impl fmt::Display for IntegrityReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "Tags merged by normalization: {}",
+      self.merged_tags.len()
+    )?;
+    for (tag, merged) in self.merged_tags.iter().take(20) {
+      // Quoted, the difference is often whitespace
+      let merged: Vec<String> = merged.iter().map(|tag| format!("{:?}", tag)).collect();
+      if tag.is_empty() {
+        writeln!(f, "- removed {}", merged.join(", "))?;
+      } else {
+        writeln!(f, "- {} <= {}", tag, merged.join(", "))?;
+      }
+    }
+    if self.merged_tags.len() > 20 {
+      writeln!(f, "and {} more", self.merged_tags.len() - 20)?;
+    }
+    writeln!(
+      f,
+      "Tag links to missing entities or tags: {}",
+      self.orphan_tag_links
+    )?;
+    writeln!(f, "Tags nobody uses: {}", self.unused_tags)?;
+    write!(f, "Files nobody has: {}", self.unused_files)
+  }
+}
+
+// Tags that normalize to the same name become one, the one that is kept
+struct TagMerge {
+  normalized: String,
+  keep_id: i64,
+  keep_name: String,
+  // Removed, their links to entities go to the kept tag
+  merged: Vec<(i64, String)>,
+}
+
+impl TagMerge {
+  // Everything that is not there anymore afterwards, by the name it had
+  fn merged_names(&self) -> Vec<String> {
+    let mut merged: Vec<String> = self
+      .merged
+      .iter()
+      .map(|(_, tag_name)| tag_name.clone())
+      .collect();
+    if self.keep_name != self.normalized {
+      merged.push(self.keep_name.clone());
+    }
+
+    merged
+  }
+}
+
+// Tags from before they were normalized, or from imports that did it differently
+async fn plan_tag_merges(conn: &mut SqliteConnection) -> Result<Vec<TagMerge>> {
+  let tags: Vec<(i64, String)> = sqlx::query_as("SELECT tag_id, tag_name FROM entity_tag")
+    .fetch_all(conn)
+    .await?;
+
+  let mut groups: BTreeMap<String, Vec<(i64, String)>> = BTreeMap::new();
+  for (tag_id, tag_name) in tags {
+    groups
+      .entry(util::normalize_tag(&tag_name))
+      .or_default()
+      .push((tag_id, tag_name));
+  }
+
+  let mut merges = Vec::new();
+  for (normalized, mut group) in groups {
+    if group.len() == 1 && group[0].1 == normalized {
+      continue;
+    }
+
+    // Keep the tag that is already normalized, or the oldest one
+    group.sort_by_key(|(tag_id, tag_name)| (*tag_name != normalized, *tag_id));
+    let (keep_id, keep_name) = group.remove(0);
+
+    merges.push(TagMerge {
+      normalized,
+      keep_id,
+      keep_name,
+      merged: group,
+    });
+  }
+
+  Ok(merges)
+}
+
+// Finds and removes everything that is left behind when entities, tags and users
+// are removed. A dry run only reports what would change.
+pub async fn check_integrity(db: &DbConn, dry_run: bool) -> Result<IntegrityReport> {
+  log::debug!("check_integrity dry run: {:?}", dry_run);
+
+  if dry_run {
+    return find_integrity_problems(db).await;
+  }
+
+  let mut report = IntegrityReport::default();
+  let mut transaction = db.begin().await?;
+
+  for merge in plan_tag_merges(transaction.as_mut()).await? {
+    for (tag_id, _) in &merge.merged {
+      sqlx::query(
+        "INSERT OR IGNORE INTO entity_main (combo_id, tag_id) \
+          SELECT combo_id, $2 FROM entity_main WHERE tag_id = $1",
+      )
+      .bind(tag_id)
+      .bind(merge.keep_id)
+      .execute(transaction.as_mut())
+      .await?;
+
+      // Takes its links to entities with it
+      sqlx::query("DELETE FROM entity_tag WHERE tag_id = $1")
+        .bind(tag_id)
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    if merge.normalized.is_empty() {
+      sqlx::query("DELETE FROM entity_tag WHERE tag_id = $1")
+        .bind(merge.keep_id)
+        .execute(transaction.as_mut())
+        .await?;
+    } else if merge.keep_name != merge.normalized {
+      sqlx::query("UPDATE entity_tag SET tag_name = $2 WHERE tag_id = $1")
+        .bind(merge.keep_id)
+        .bind(&merge.normalized)
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    let merged = merge.merged_names();
+
+    // So undo brings back the tag that is left, if any
+    for tag_name in &merged {
+      if !merge.normalized.is_empty() {
+        sqlx::query("UPDATE OR IGNORE tag_journal_entry SET tag_name = $2 WHERE tag_name = $1")
+          .bind(tag_name)
+          .bind(&merge.normalized)
+          .execute(transaction.as_mut())
+          .await?;
+      }
+      sqlx::query("DELETE FROM tag_journal_entry WHERE tag_name = $1")
+        .bind(tag_name)
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    report.merged_tags.push((merge.normalized, merged));
+  }
+
+  // The foreign keys prevent these, but databases from before them can still
+  // have some
+  report.orphan_tag_links = sqlx::query(
+    "DELETE FROM entity_main WHERE combo_id NOT IN (SELECT combo_id FROM entity_data) \
+      OR tag_id NOT IN (SELECT tag_id FROM entity_tag)",
+  )
+  .execute(transaction.as_mut())
+  .await?
+  .rows_affected();
+
+  report.unused_tags = sqlx::query(
+    "DELETE FROM entity_tag WHERE NOT EXISTS \
+      (SELECT 1 FROM entity_main WHERE entity_main.tag_id = entity_tag.tag_id)",
+  )
+  .execute(transaction.as_mut())
+  .await?
+  .rows_affected();
+
+  // Files that can still come back with /undo are kept
+  report.unused_files = sqlx::query(
+    "DELETE FROM entity_file WHERE entity_id NOT IN (SELECT entity_id FROM entity_data) \
+      AND entity_id NOT IN (SELECT entity_id FROM tag_journal_entry)",
+  )
+  .execute(transaction.as_mut())
+  .await?
+  .rows_affected();
+
+  transaction.commit().await?;
+
+  log::debug!("check_integrity: {:?}", report);
+
+  Ok(report)
+}
+
+// The same report as check_integrity, but only reading, so the bot keeps
+// working normally while it runs
+async fn find_integrity_problems(db: &DbConn) -> Result<IntegrityReport> {
+  let mut report = IntegrityReport::default();
+  let mut conn = db.acquire().await?;
+
+  let merges = plan_tag_merges(conn.as_mut()).await?;
+
+  report.orphan_tag_links = sqlx::query_scalar::<_, i64>(
+    "SELECT COUNT(*) FROM entity_main WHERE combo_id NOT IN (SELECT combo_id FROM entity_data) \
+      OR tag_id NOT IN (SELECT tag_id FROM entity_tag)",
+  )
+  .fetch_one(conn.as_mut())
+  .await? as u64;
+
+  // Merged tags share their links, so a kept tag is used if any of them is
+  let used_tags: HashSet<i64> = sqlx::query_scalar(
+    "SELECT DISTINCT tag_id FROM entity_main \
+      WHERE combo_id IN (SELECT combo_id FROM entity_data)",
+  )
+  .fetch_all(conn.as_mut())
+  .await?
+  .into_iter()
+  .collect();
+  let tag_ids: Vec<i64> = sqlx::query_scalar("SELECT tag_id FROM entity_tag")
+    .fetch_all(conn.as_mut())
+    .await?;
+
+  let mut removed_tags = HashSet::new();
+  let mut used_kept_tags = HashSet::new();
+  // Journal entries of tags that are removed entirely go with them
+  let mut dropped_journal_tags = Vec::new();
+  for merge in &merges {
+    removed_tags.extend(merge.merged.iter().map(|(tag_id, _)| *tag_id));
+    if merge.normalized.is_empty() {
+      removed_tags.insert(merge.keep_id);
+      dropped_journal_tags.extend(merge.merged_names());
+    } else if used_tags.contains(&merge.keep_id)
+      || merge
+        .merged
+        .iter()
+        .any(|(tag_id, _)| used_tags.contains(tag_id))
+    {
+      used_kept_tags.insert(merge.keep_id);
+    }
+  }
+
+  report.unused_tags = tag_ids
+    .iter()
+    .filter(|tag_id| {
+      !removed_tags.contains(tag_id)
+        && !used_tags.contains(tag_id)
+        && !used_kept_tags.contains(tag_id)
+    })
+    .count() as u64;
+
+  let mut unused_files_query = QueryBuilder::new(
+    "SELECT COUNT(*) FROM entity_file WHERE entity_id NOT IN (SELECT entity_id FROM entity_data) \
+      AND entity_id NOT IN (SELECT entity_id FROM tag_journal_entry WHERE tag_name NOT IN (",
+  );
+  let mut seperator = unused_files_query.separated(", ");
+  // Empty tags are always removed, and the list can not be empty
+  seperator.push_bind("");
+  dropped_journal_tags.iter().for_each(|tag_name| {
+    seperator.push_bind(tag_name);
+  });
+  unused_files_query.push("))");
+  report.unused_files = unused_files_query
+    .build_query_scalar::<i64>()
+    .fetch_one(conn.as_mut())
+    .await? as u64;
+
+  report.merged_tags = merges
+    .into_iter()
+    .map(|merge| {
+      let merged = merge.merged_names();
+      (merge.normalized, merged)
+    })
+    .collect();
+
+  log::debug!("check_integrity: {:?}", report);
+
+  Ok(report)
+}
+
+// The migration that adds the foreign keys, it only copies the statistics of
+// entities that have a file
+const FOREIGN_KEYS_MIGRATION: i64 = 20261019180000;
+
+// How many statistics rows the foreign key migration is about to drop, None if
+// it already ran or the database is new
+pub async fn count_rows_dropped_by_migration(db: &DbConn) -> Result<Option<i64>> {
+  log::debug!("count_rows_dropped_by_migration");
+
+  let has_migrations: bool = sqlx::query_scalar(
+    "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+  )
+  .fetch_one(db)
+  .await?;
+  if !has_migrations {
+    return Ok(None);
+  }
+
+  let migrated: bool =
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM _sqlx_migrations WHERE version = $1)")
+      .bind(FOREIGN_KEYS_MIGRATION)
+      .fetch_one(db)
+      .await?;
+  if migrated {
+    return Ok(None);
+  }
+
+  let dropped: i64 = sqlx::query_scalar(
+    "SELECT COUNT(*) FROM entity_data \
+      WHERE entity_id NOT IN (SELECT entity_id FROM entity_file)",
+  )
+  .fetch_one(db)
+  .await?;
+
+  Ok(Some(dropped))
+}
+
+// Lets SQLite update the statistics it plans queries with, cheap enough to run
+// often
+pub async fn optimize(db: &DbConn) -> Result<()> {
+  log::debug!("optimize");
+
+  sqlx::query("PRAGMA optimize").execute(db).await?;
+
+  Ok(())
+}
+
+// Rewrites the whole database to give back the space of deleted rows, this
+// blocks every write until it is done
+pub async fn vacuum(db: &DbConn) -> Result<()> {
+  log::debug!("vacuum");
+
+  sqlx::query("VACUUM").execute(db).await?;
+  // The WAL file grew to the size of the database while vacuuming
+  sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// Writes a consistent copy of the database while it is in use
+pub async fn backup_into(db: &DbConn, path: &str) -> Result<()> {
+  log::debug!("backup_into {:?}", path);
+
+  sqlx::query("VACUUM INTO $1").bind(path).execute(db).await?;
+
+  Ok(())
+}
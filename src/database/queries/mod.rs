@@ -1,7 +1,12 @@
 mod entities;
 mod entity_stats;
 mod global_stats;
+mod history;
+mod jobs;
+mod journal;
+mod maintenance;
 mod search;
+mod settings;
 mod tags;
 mod types;
 mod users;
@@ -9,7 +14,12 @@ mod users;
 pub use entities::*;
 pub use entity_stats::*;
 pub use global_stats::*;
+pub use history::*;
+pub use jobs::*;
+pub use journal::*;
+pub use maintenance::*;
 pub use search::*;
+pub use settings::*;
 pub use tags::*;
 pub use types::*;
 pub use users::*;
@@ -2,7 +2,7 @@ use anyhow::Result;
 use sqlx::QueryBuilder;
 
 use crate::{
-  database::Entity,
+  database::{Entity, EntityType, UntaggedEntity},
   types::{DbConn, EntitySort, InlineSearchQuery},
   util,
 };
@@ -13,6 +13,10 @@ pub async fn find_entities(
   query: InlineSearchQuery,
   page: i32,
 ) -> Result<Vec<Entity>> {
+  if query.untagged {
+    return list_untagged_entities(db, user_id, query.entity_type, query.sort, page).await;
+  }
+
   if query.get_all {
     return list_entities(db, user_id, query.sort, page).await;
   }
@@ -30,11 +34,11 @@ pub async fn find_entities(
   let mut query_builder = QueryBuilder::new(
       "SELECT entity_data.entity_id, entity_data.user_id, entity_file.file_id, entity_file.entity_type FROM entity_main \
       JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
-      JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+      JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
       JOIN entity_file ON entity_file.entity_id = entity_data.entity_id",
   );
   query_builder
-    .push(" WHERE entity_data.user_id = ")
+    .push(" WHERE entity_file.broken_at IS NULL AND entity_data.user_id = ")
     .push_bind(user_id.to_owned());
 
   if let Some(entity_type) = query.entity_type {
@@ -49,7 +53,7 @@ pub async fn find_entities(
         "AND entity_data.entity_id NOT IN ( \
               SELECT entity_data.entity_id FROM entity_main \
               JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
-            JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+            JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
               WHERE entity_data.user_id = ",
       )
       .push_bind(user_id.to_owned())
@@ -97,6 +101,8 @@ pub async fn find_entities(
     .push_bind(tags_len)
     .push(" ORDER BY ")
     .push(query.sort.to_sql())
+    // Without a unique tiebreaker, rows with the same sort key can move between pages
+    .push(", entity_data.combo_id ASC")
     .push(" LIMIT 50 OFFSET ")
     .push_bind(page * 50);
 
@@ -110,6 +116,36 @@ pub async fn find_entities(
   Ok(result)
 }
 
+// Walks every page of find_entities, used for bulk operations over a whole search
+pub async fn find_all_entities(
+  db: &DbConn,
+  user_id: String,
+  mut query: InlineSearchQuery,
+) -> Result<Vec<Entity>> {
+  log::debug!("find_all_entities: {:?} for user_id: {:?}", query, user_id);
+
+  // Random sorting would make the pages overlap
+  if query.sort == EntitySort::Random {
+    query.sort = EntitySort::LastAdded;
+  }
+
+  let mut result: Vec<Entity> = Vec::new();
+  let mut page = 0;
+  loop {
+    let entities = find_entities(db, user_id.to_owned(), query.to_owned(), page).await?;
+    if entities.is_empty() {
+      break;
+    }
+
+    result.extend(entities);
+    page += 1;
+  }
+
+  log::debug!("find_all_entities found {} entities", result.len());
+
+  Ok(result)
+}
+
 async fn list_entities(
   db: &DbConn,
   user_id: String,
@@ -121,11 +157,11 @@ async fn list_entities(
   let result: Vec<Entity> = sqlx::query_as(
       format!(
           "SELECT entity_data.entity_id, entity_data.user_id, entity_file.file_id, entity_file.entity_type FROM entity_main \
-          JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+          JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
           JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
-          WHERE entity_data.user_id = $1 \
+          WHERE entity_data.user_id = $1 AND entity_file.broken_at IS NULL \
           GROUP BY entity_main.combo_id \
-          ORDER BY {} \
+          ORDER BY {}, entity_data.combo_id ASC \
           LIMIT 50 OFFSET $2",
           sort.to_sql()
       ).as_str()
@@ -139,3 +175,90 @@ async fn list_entities(
 
   Ok(result)
 }
+
+// Entities that the user still has data for, but no tags
+async fn list_untagged_entities(
+  db: &DbConn,
+  user_id: String,
+  entity_type: Option<EntityType>,
+  sort: EntitySort,
+  page: i32,
+) -> Result<Vec<Entity>> {
+  log::debug!("list_untagged_entities for user_id: {:?}", user_id);
+
+  let mut query_builder = QueryBuilder::new(
+    "SELECT entity_data.entity_id, entity_data.user_id, entity_file.file_id, entity_file.entity_type FROM active_entity_data AS entity_data \
+    JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+    WHERE entity_data.combo_id NOT IN (SELECT combo_id FROM entity_main) \
+    AND entity_file.broken_at IS NULL \
+    AND entity_data.user_id = ",
+  );
+  query_builder.push_bind(user_id);
+
+  if let Some(entity_type) = entity_type {
+    query_builder
+      .push(" AND entity_file.entity_type = ")
+      .push_bind(entity_type);
+  }
+
+  query_builder
+    .push(" ORDER BY ")
+    .push(sort.to_sql())
+    .push(", entity_data.combo_id ASC")
+    .push(" LIMIT 50 OFFSET ")
+    .push_bind(page * 50);
+
+  let result: Vec<Entity> = query_builder.build_query_as().fetch_all(db).await?;
+
+  log::debug!("list_untagged_entities result: {:?}", result);
+
+  Ok(result)
+}
+
+pub async fn count_untagged_entities(db: &DbConn, user_id: String) -> Result<i64> {
+  log::debug!("count_untagged_entities for user_id: {:?}", user_id);
+
+  let result: i64 = sqlx::query_scalar(
+    "SELECT COUNT(*) FROM active_entity_data AS entity_data \
+    WHERE entity_data.combo_id NOT IN (SELECT combo_id FROM entity_main) \
+    AND entity_data.entity_id NOT IN (SELECT entity_id FROM entity_file WHERE broken_at IS NOT NULL) \
+    AND entity_data.user_id = $1",
+  )
+  .bind(user_id)
+  .fetch_one(db)
+  .await?;
+
+  Ok(result)
+}
+
+// Also returns entities without a known file, so they can still be removed
+pub async fn get_untagged_entity(
+  db: &DbConn,
+  user_id: String,
+  offset: i64,
+) -> Result<Option<UntaggedEntity>> {
+  log::debug!(
+    "get_untagged_entity for user_id: {:?} at offset: {:?}",
+    user_id,
+    offset
+  );
+
+  let result: Option<UntaggedEntity> = sqlx::query_as(
+    "SELECT entity_data.entity_id, entity_file.file_id, entity_file.entity_type, \
+    entity_data.count, entity_data.last_used, entity_data.created_at FROM active_entity_data AS entity_data \
+    LEFT JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+    WHERE entity_data.combo_id NOT IN (SELECT combo_id FROM entity_main) \
+    AND entity_file.broken_at IS NULL \
+    AND entity_data.user_id = $1 \
+    ORDER BY entity_data.count DESC, entity_data.combo_id ASC \
+    LIMIT 1 OFFSET $2",
+  )
+  .bind(user_id)
+  .bind(offset)
+  .fetch_optional(db)
+  .await?;
+
+  log::debug!("get_untagged_entity result: {:?}", result);
+
+  Ok(result)
+}
@@ -0,0 +1,60 @@
+use anyhow::Result;
+
+use crate::{types::DbConn, util};
+
+pub const MAINTENANCE_MODE: &str = "maintenance_mode";
+
+// Keys the user hash in exports, so the hash can't be reversed by hashing every
+// possible user id
+const USER_HASH_SECRET: &str = "user_hash_secret";
+
+pub async fn get_setting(db: &DbConn, key: &str) -> Result<Option<String>> {
+  log::debug!("get_setting for key: {:?}", key);
+
+  let result: Option<String> =
+    sqlx::query_scalar("SELECT setting_value FROM bot_setting WHERE setting_key = $1")
+      .bind(key)
+      .fetch_optional(db)
+      .await?;
+
+  Ok(result)
+}
+
+pub async fn set_setting(db: &DbConn, key: &str, value: &str) -> Result<()> {
+  log::debug!("set_setting for key: {:?} and value: {:?}", key, value);
+
+  sqlx::query(
+    "INSERT INTO bot_setting (setting_key, setting_value) VALUES ($1, $2) \
+      ON CONFLICT (setting_key) DO UPDATE SET setting_value = $2",
+  )
+  .bind(key)
+  .bind(value)
+  .execute(db)
+  .await?;
+
+  Ok(())
+}
+
+// Made on first use, and kept so exports of the same user keep the same hash
+pub async fn get_user_hash_secret(db: &DbConn) -> Result<String> {
+  let secret = format!("{}{}", util::random_id(), util::random_id());
+  sqlx::query(
+    "INSERT INTO bot_setting (setting_key, setting_value) VALUES ($1, $2) \
+      ON CONFLICT (setting_key) DO NOTHING",
+  )
+  .bind(USER_HASH_SECRET)
+  .bind(secret)
+  .execute(db)
+  .await?;
+
+  let secret = get_setting(db, USER_HASH_SECRET).await?.unwrap_or_default();
+  Ok(secret)
+}
+
+pub async fn is_maintenance_mode(db: &DbConn) -> Result<bool> {
+  Ok(get_setting(db, MAINTENANCE_MODE).await?.as_deref() == Some("on"))
+}
+
+pub async fn set_maintenance_mode(db: &DbConn, enabled: bool) -> Result<()> {
+  set_setting(db, MAINTENANCE_MODE, if enabled { "on" } else { "off" }).await
+}
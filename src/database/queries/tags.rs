@@ -3,7 +3,7 @@ use sqlx::{QueryBuilder, Sqlite};
 
 use crate::{database::EntityType, types::DbConn, util};
 
-use super::InsertEntity;
+use super::{journal_tags_added, journal_tags_removed, InsertEntity};
 
 pub struct TagUsage {
   pub tag_name: String,
@@ -16,6 +16,7 @@ pub async fn insert_tags(
   entities: Vec<InsertEntity>,
   entity_type: EntityType,
   tag_names: Vec<String>,
+  journal_id: i64,
 ) -> Result<()> {
   if tag_names.len() == 0 || entities.len() == 0 {
     return Ok(());
@@ -44,14 +45,21 @@ pub async fn insert_tags(
 
   log::debug!("insert_tags: tag inserted");
 
-  // Insert the file into the entity table if it doesn't exist
+  // Insert the file into the entity table, or start over with the file that was
+  // just sent. A REPLACE would delete the row, and with it everything that
+  // references it.
   let mut insert_file_query =
-    QueryBuilder::new("INSERT OR REPLACE INTO entity_file (entity_id, file_id, entity_type) ");
+    QueryBuilder::new("INSERT INTO entity_file (entity_id, file_id, entity_type) ");
   insert_file_query.push_values(&entities, |mut b, entity| {
     b.push_bind(entity.entity_id.clone());
     b.push_bind(entity.file_id.clone());
     b.push_bind(entity_type.clone());
   });
+  insert_file_query.push(
+    " ON CONFLICT (entity_id) DO UPDATE SET file_id = excluded.file_id, \
+      entity_type = excluded.entity_type, broken_at = NULL, broken_reason = NULL, \
+      last_checked = 0, check_status = NULL",
+  );
   insert_file_query
     .build()
     .execute(transaction.as_mut())
@@ -72,6 +80,16 @@ pub async fn insert_tags(
     .execute(transaction.as_mut())
     .await?;
 
+  let entity_ids: Vec<String> = entities.iter().map(|e| e.entity_id.clone()).collect();
+  journal_tags_added(
+    transaction.as_mut(),
+    journal_id,
+    &user_id,
+    &entity_ids,
+    &tag_names,
+  )
+  .await?;
+
   for entity in entities {
     // Insert a relation between the entity and the tag
     let mut insert_main_query: QueryBuilder<'_, Sqlite> = QueryBuilder::new(
@@ -108,6 +126,7 @@ pub async fn remove_tags(
   user_id: String,
   entity_ids: Vec<String>,
   tag_names: Vec<String>,
+  journal_id: i64,
 ) -> Result<()> {
   if tag_names.len() == 0 || entity_ids.len() == 0 {
     return Ok(());
@@ -120,6 +139,17 @@ pub async fn remove_tags(
     user_id
   );
 
+  let mut transaction = db.begin().await?;
+
+  journal_tags_removed(
+    transaction.as_mut(),
+    journal_id,
+    &user_id,
+    &entity_ids,
+    Some(&tag_names),
+  )
+  .await?;
+
   let mut query_builder = QueryBuilder::new(
     "DELETE FROM entity_main \
       WHERE combo_id IN (",
@@ -140,7 +170,9 @@ pub async fn remove_tags(
   });
   query_builder.push("))");
 
-  query_builder.build().execute(db).await?;
+  query_builder.build().execute(transaction.as_mut()).await?;
+
+  transaction.commit().await?;
 
   Ok(())
 }
@@ -159,7 +191,7 @@ pub async fn get_tags_for_entity(
   let temp_result: Vec<(String,)> = sqlx::query_as(
     "SELECT tag_name FROM entity_main \
       JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
-      JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+      JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
       WHERE entity_data.entity_id = $1 AND entity_data.user_id = $2",
   )
   .bind(entity_id)
@@ -183,7 +215,7 @@ pub async fn get_tags_and_usage(db: &DbConn, user_id: String) -> Result<Vec<TagU
   let result: Vec<(String, i32)> = sqlx::query_as(
     "SELECT tag_name, COUNT(entity_main.combo_id) as usage FROM entity_main \
       JOIN entity_tag ON entity_tag.tag_id = entity_main.tag_id \
-      JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+      JOIN active_entity_data AS entity_data ON entity_data.combo_id = entity_main.combo_id \
       WHERE entity_data.user_id = $1 \
       GROUP BY entity_main.tag_id \
       ORDER BY usage DESC \
@@ -201,13 +233,29 @@ pub async fn get_tags_and_usage(db: &DbConn, user_id: String) -> Result<Vec<TagU
   Ok(result)
 }
 
-pub async fn wipe_tags(db: &DbConn, user_id: String, entity_id: String) -> Result<()> {
+pub async fn wipe_tags(
+  db: &DbConn,
+  user_id: String,
+  entity_id: String,
+  journal_id: i64,
+) -> Result<()> {
   log::debug!(
     "wipe_tags for entity_id: {:?} and user_id: {:?}",
     entity_id,
     user_id
   );
 
+  let mut transaction = db.begin().await?;
+
+  journal_tags_removed(
+    transaction.as_mut(),
+    journal_id,
+    &user_id,
+    std::slice::from_ref(&entity_id),
+    None,
+  )
+  .await?;
+
   sqlx::query(
     "DELETE FROM entity_main \
       WHERE combo_id IN \
@@ -215,8 +263,10 @@ pub async fn wipe_tags(db: &DbConn, user_id: String, entity_id: String) -> Resul
   )
   .bind(entity_id)
   .bind(user_id)
-  .execute(db)
+  .execute(transaction.as_mut())
   .await?;
 
+  transaction.commit().await?;
+
   Ok(())
 }
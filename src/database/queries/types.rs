@@ -16,3 +16,48 @@ pub struct GlobalStats {
 
   pub total_entities_sent: i64,
 }
+
+// What admins see with /adminstats
+#[derive(Debug, Clone)]
+pub struct DetailedStats {
+  pub total_users: i64,
+  pub active_users: i64,
+  // Blocked the bot, or their account is gone
+  pub inactive_users: i64,
+  pub pending_deletion_users: i64,
+
+  pub total_entities: i64,
+  pub total_files: i64,
+  pub quarantined_files: i64,
+  pub untagged_entities: i64,
+
+  pub total_tags: i64,
+  pub total_tag_links: i64,
+
+  pub queued_jobs: i64,
+  pub running_jobs: i64,
+  pub failed_jobs: i64,
+
+  pub database_size: i64,
+  pub database_free: i64,
+}
+
+// What admins see when looking up a user
+#[derive(Debug, Clone)]
+pub struct UserStats {
+  pub total_stickers: i64,
+  pub total_animations: i64,
+  pub total_videos: i64,
+  pub total_photos: i64,
+  pub quarantined: i64,
+
+  pub total_tags: i64,
+  pub total_entities_sent: i64,
+
+  pub first_added: i64,
+  pub last_used: i64,
+  pub last_export_time: i64,
+  pub deleted_at: Option<i64>,
+  pub inactive_at: Option<i64>,
+  pub active_jobs: i64,
+}
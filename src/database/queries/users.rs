@@ -1,6 +1,9 @@
 use anyhow::Result;
 
-use crate::types::DbConn;
+use crate::{
+  database::{queries::UserStats, EntityType},
+  types::DbConn,
+};
 
 pub async fn get_last_fix_time(db: &DbConn, user_id: String) -> Result<i64> {
   log::debug!("get_last_fix_time for user_id: {:?}", user_id);
@@ -44,37 +47,296 @@ pub async fn set_last_fix_time(db: &DbConn, user_id: String, time: i64) -> Resul
   Ok(())
 }
 
-pub async fn wipe_user(db: &DbConn, user_id: String) -> Result<()> {
-  log::debug!("wipe_user for user_id: {:?}", user_id);
+pub async fn get_last_export_time(db: &DbConn, user_id: String) -> Result<i64> {
+  log::debug!("get_last_export_time for user_id: {:?}", user_id);
 
-  let mut transaction = db.begin().await?;
+  let result: Option<i64> =
+    sqlx::query_scalar("SELECT last_export_time FROM user_data WHERE user_id = $1")
+      .bind(user_id)
+      .fetch_optional(db)
+      .await?;
+
+  Ok(result.unwrap_or(0))
+}
+
+pub async fn set_last_export_time(db: &DbConn, user_id: String, time: i64) -> Result<()> {
+  log::debug!(
+    "set_last_export_time for user_id: {:?} and time: {:?}",
+    user_id,
+    time
+  );
 
   sqlx::query(
-    "DELETE FROM entity_main \
-      WHERE combo_id IN \
-      (SELECT combo_id FROM entity_data WHERE user_id = $1)",
+    "INSERT INTO user_data (user_id, last_export_time) \
+      VALUES ($1, $2) \
+      ON CONFLICT (user_id) DO UPDATE SET last_export_time = $2",
   )
-  .bind(user_id.clone())
-  .execute(transaction.as_mut())
+  .bind(user_id)
+  .bind(time)
+  .execute(db)
   .await?;
 
+  Ok(())
+}
+
+pub async fn soft_delete_user(db: &DbConn, user_id: String, time: i64) -> Result<()> {
+  log::debug!("soft_delete_user for user_id: {:?}", user_id);
+
   sqlx::query(
-    "DELETE FROM entity_data \
-      WHERE user_id = $1",
+    "INSERT INTO user_data (user_id, deleted_at) \
+      VALUES ($1, $2) \
+      ON CONFLICT (user_id) DO UPDATE SET deleted_at = $2",
   )
-  .bind(user_id.clone())
-  .execute(transaction.as_mut())
+  .bind(user_id)
+  .bind(time)
+  .execute(db)
   .await?;
 
+  Ok(())
+}
+
+pub async fn restore_user(db: &DbConn, user_id: String) -> Result<()> {
+  log::debug!("restore_user for user_id: {:?}", user_id);
+
+  sqlx::query("UPDATE user_data SET deleted_at = NULL WHERE user_id = $1")
+    .bind(user_id)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// Broadcasts skip the user until they use /start again
+pub async fn set_user_inactive(db: &DbConn, user_id: String, time: i64) -> Result<()> {
+  log::debug!("set_user_inactive for user_id: {:?}", user_id);
+
   sqlx::query(
-    "DELETE FROM user_data \
-      WHERE user_id = $1",
+    "INSERT INTO user_data (user_id, inactive_at) \
+      VALUES ($1, $2) \
+      ON CONFLICT (user_id) DO UPDATE SET inactive_at = $2",
+  )
+  .bind(user_id)
+  .bind(time)
+  .execute(db)
+  .await?;
+
+  Ok(())
+}
+
+pub async fn set_user_active(db: &DbConn, user_id: String) -> Result<()> {
+  log::debug!("set_user_active for user_id: {:?}", user_id);
+
+  sqlx::query("UPDATE user_data SET inactive_at = NULL WHERE user_id = $1")
+    .bind(user_id)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+// Everyone the bot knows, except users that are inactive or used /stop
+const BROADCAST_RECIPIENTS: &str = "SELECT user_id FROM \
+  (SELECT user_id FROM entity_data UNION SELECT user_id FROM user_data) \
+  WHERE user_id > $1 AND user_id NOT IN (SELECT user_id FROM user_data \
+    WHERE deleted_at IS NOT NULL OR inactive_at IS NOT NULL)";
+
+// The next users to send a broadcast to, in order so a broadcast can continue
+// after the last user it reached
+pub async fn get_broadcast_recipients(
+  db: &DbConn,
+  after_user_id: String,
+  limit: i64,
+) -> Result<Vec<String>> {
+  log::debug!(
+    "get_broadcast_recipients after user_id: {:?} limit: {:?}",
+    after_user_id,
+    limit
+  );
+
+  let result: Vec<String> = sqlx::query_scalar(&format!(
+    "{} ORDER BY user_id LIMIT $2",
+    BROADCAST_RECIPIENTS
+  ))
+  .bind(after_user_id)
+  .bind(limit)
+  .fetch_all(db)
+  .await?;
+
+  Ok(result)
+}
+
+pub async fn count_broadcast_recipients(db: &DbConn, after_user_id: String) -> Result<i64> {
+  log::debug!(
+    "count_broadcast_recipients after user_id: {:?}",
+    after_user_id
+  );
+
+  let result: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM ({})", BROADCAST_RECIPIENTS))
+    .bind(after_user_id)
+    .fetch_one(db)
+    .await?;
+
+  Ok(result)
+}
+
+// When the user used /stop, None if the data of the user is not scheduled for deletion
+pub async fn get_deleted_at(db: &DbConn, user_id: String) -> Result<Option<i64>> {
+  log::debug!("get_deleted_at for user_id: {:?}", user_id);
+
+  let result: Option<Option<i64>> =
+    sqlx::query_scalar("SELECT deleted_at FROM user_data WHERE user_id = $1")
+      .bind(user_id)
+      .fetch_optional(db)
+      .await?;
+
+  Ok(result.flatten())
+}
+
+pub async fn get_deleted_users(db: &DbConn) -> Result<Vec<String>> {
+  log::debug!("get_deleted_users");
+
+  let result: Vec<String> =
+    sqlx::query_scalar("SELECT user_id FROM user_data WHERE deleted_at IS NOT NULL")
+      .fetch_all(db)
+      .await?;
+
+  Ok(result)
+}
+
+pub async fn get_users_deleted_before(db: &DbConn, time: i64) -> Result<Vec<String>> {
+  log::debug!("get_users_deleted_before: {:?}", time);
+
+  let result: Vec<String> = sqlx::query_scalar(
+    "SELECT user_id FROM user_data \
+      WHERE deleted_at IS NOT NULL AND deleted_at < $1",
   )
-  .bind(user_id.clone())
-  .execute(transaction.as_mut())
+  .bind(time)
+  .fetch_all(db)
   .await?;
 
+  Ok(result)
+}
+
+// The entities of the user take their tags with them, and the journals their
+// entries
+pub async fn wipe_user(db: &DbConn, user_id: String) -> Result<()> {
+  log::debug!("wipe_user for user_id: {:?}", user_id);
+
+  let mut transaction = db.begin().await?;
+
+  sqlx::query("DELETE FROM entity_data WHERE user_id = $1")
+    .bind(&user_id)
+    .execute(transaction.as_mut())
+    .await?;
+
+  sqlx::query("DELETE FROM tag_journal WHERE user_id = $1")
+    .bind(&user_id)
+    .execute(transaction.as_mut())
+    .await?;
+
+  sqlx::query("DELETE FROM tag_history WHERE user_id = $1")
+    .bind(&user_id)
+    .execute(transaction.as_mut())
+    .await?;
+
+  sqlx::query("DELETE FROM job WHERE user_id = $1")
+    .bind(&user_id)
+    .execute(transaction.as_mut())
+    .await?;
+
+  sqlx::query("DELETE FROM user_data WHERE user_id = $1")
+    .bind(&user_id)
+    .execute(transaction.as_mut())
+    .await?;
+
   transaction.commit().await?;
 
   Ok(())
 }
+
+// Everything an admin can look up about a user, None if the bot knows nothing
+// about them
+pub async fn get_user_stats(db: &DbConn, user_id: String) -> Result<Option<UserStats>> {
+  log::debug!("get_user_stats for user_id: {:?}", user_id);
+
+  let known: bool = sqlx::query_scalar(
+    "SELECT EXISTS (SELECT 1 FROM entity_data WHERE user_id = $1) \
+      OR EXISTS (SELECT 1 FROM user_data WHERE user_id = $1)",
+  )
+  .bind(&user_id)
+  .fetch_one(db)
+  .await?;
+  if !known {
+    return Ok(None);
+  }
+
+  let (total_stickers, total_animations, total_videos, total_photos, quarantined): (
+    i64,
+    i64,
+    i64,
+    i64,
+    i64,
+  ) = sqlx::query_as(
+    "SELECT \
+      COUNT(*) FILTER (WHERE entity_file.entity_type = $2), \
+      COUNT(*) FILTER (WHERE entity_file.entity_type = $3), \
+      COUNT(*) FILTER (WHERE entity_file.entity_type = $4), \
+      COUNT(*) FILTER (WHERE entity_file.entity_type = $5), \
+      COUNT(*) FILTER (WHERE entity_file.broken_at IS NOT NULL) \
+      FROM entity_data \
+      INNER JOIN entity_file ON entity_file.entity_id = entity_data.entity_id \
+      WHERE entity_data.user_id = $1",
+  )
+  .bind(&user_id)
+  .bind(EntityType::Sticker)
+  .bind(EntityType::Animation)
+  .bind(EntityType::Video)
+  .bind(EntityType::Photo)
+  .fetch_one(db)
+  .await?;
+
+  let (total_tags, total_entities_sent, first_added, last_used): (i64, i64, i64, i64) =
+    sqlx::query_as(
+      "SELECT \
+        (SELECT COUNT(DISTINCT entity_main.tag_id) FROM entity_main \
+          INNER JOIN entity_data ON entity_data.combo_id = entity_main.combo_id \
+          WHERE entity_data.user_id = $1), \
+        COALESCE(SUM(count), 0), \
+        COALESCE(MIN(created_at), 0), \
+        COALESCE(MAX(last_used), 0) \
+        FROM entity_data WHERE user_id = $1",
+    )
+    .bind(&user_id)
+    .fetch_one(db)
+    .await?;
+
+  let user_data: Option<(i64, Option<i64>, Option<i64>)> = sqlx::query_as(
+    "SELECT last_export_time, deleted_at, inactive_at FROM user_data WHERE user_id = $1",
+  )
+  .bind(&user_id)
+  .fetch_optional(db)
+  .await?;
+  let (last_export_time, deleted_at, inactive_at) = user_data.unwrap_or((0, None, None));
+
+  let active_jobs = super::count_active_jobs(db, user_id.clone(), None).await?;
+
+  let result = UserStats {
+    total_stickers,
+    total_animations,
+    total_videos,
+    total_photos,
+    quarantined,
+    total_tags,
+    total_entities_sent,
+    first_added,
+    last_used,
+    last_export_time,
+    deleted_at,
+    inactive_at,
+    active_jobs,
+  };
+
+  log::debug!("get_user_stats result: {:?}", result);
+
+  Ok(Some(result))
+}
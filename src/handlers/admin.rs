@@ -0,0 +1,581 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use teloxide::{
+  requests::Requester,
+  types::{ChosenInlineResult, InlineQuery, Message, User},
+  utils::html::escape as html_escape,
+};
+
+use crate::{
+  database::{import::types::ExportFormat, queries},
+  tasks::{
+    backup,
+    jobs::{self, JobKind},
+  },
+  types::{
+    BotType, ConversationState, DbConn, DbType, DialogueWithState, MaintenanceMode,
+    PendingDeletions,
+  },
+  util,
+};
+
+use super::{inline::send_text_result, send_message::BetterSendMessage};
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+// Commands only the users in ADMIN_IDS can use, everyone else gets the same
+// answer as for an unknown command
+pub fn is_admin(msg: &Message) -> bool {
+  msg
+    .from
+    .as_ref()
+    .map(|user| util::is_admin(&user.id.to_string()))
+    .unwrap_or(false)
+}
+
+// Handles /maintenance [repair], a dry run unless repair is given
+pub async fn send_maintenance(
+  db: &DbConn,
+  bot: &BotType,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let dry_run = match args.trim().to_lowercase().as_str() {
+    "" => true,
+    "repair" => false,
+    _ => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Usage: <code>/maintenance</code> or <code>/maintenance repair</code>",
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let report = queries::check_integrity(db, dry_run).await?;
+
+  let title = if report.is_clean() {
+    "Nothing to repair"
+  } else if dry_run {
+    "Dry run, use /maintenance repair to fix this"
+  } else {
+    "Repaired"
+  };
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!("<b>{}</b>\n{}", title, html_escape(&report.to_string())),
+    )
+    .await?;
+
+  Ok(())
+}
+
+// Handles /backup [list], makes a backup right away unless list is given
+pub async fn send_backup(db: &DbType, bot: &BotType, msg: &Message, args: String) -> Result<()> {
+  let Some(directory) = backup::backup_directory() else {
+    bot
+      .send_message_easy(msg.chat.id, "Backups are disabled, set BACKUP_DIRECTORY")
+      .await?;
+    return Ok(());
+  };
+
+  match args.trim().to_lowercase().as_str() {
+    "" => {
+      let backup = backup::create_backup(db, &directory).await?;
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          format!(
+            "Backed up to <code>{}</code> (<code>{}</code> MB)",
+            html_escape(&backup.path.display().to_string()),
+            backup.size / 1_000_000
+          ),
+        )
+        .await?;
+    }
+    "list" => {
+      let backups = backup::list_backups(&directory)?;
+      if backups.is_empty() {
+        bot
+          .send_message_easy(msg.chat.id, "There are no backups yet")
+          .await?;
+        return Ok(());
+      }
+
+      let mut message = format!(
+        "<b>Backups in</b> <code>{}</code>",
+        html_escape(&directory.display().to_string())
+      );
+      for backup in backups {
+        message += &format!(
+          "\n<code>{}</code> (<code>{}</code> MB)",
+          backup.created_at.format("%Y-%m-%d %H:%M UTC"),
+          backup.size / 1_000_000
+        );
+      }
+
+      bot.send_message_easy(msg.chat.id, message).await?;
+    }
+    _ => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Usage: <code>/backup</code> or <code>/backup list</code>",
+        )
+        .await?;
+    }
+  }
+
+  Ok(())
+}
+
+// Handles /admin, the admin commands are hidden from /help
+pub async fn send_admin_help(bot: &BotType, msg: &Message) -> Result<()> {
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      "<b>Admin commands</b>\n\
+      /adminstats - Detailed statistics about the bot\n\
+      /user id - What a user has stored\n\
+      /exportuser id [csv|json] - Export the data of a user to this chat\n\
+      /wipeuser id - Permanently wipe the data of a user\n\
+      /maintenance [repair] - Check the database for leftovers\n\
+      /maintenancemode [on|off] - Only let admins use the bot\n\
+      /backup [list] - Back up the database\n\
+      /broadcast message - Send a message to every user",
+    )
+    .await?;
+
+  Ok(())
+}
+
+// Handles /adminstats
+pub async fn send_admin_stats(db: &DbConn, bot: &BotType, msg: &Message) -> Result<()> {
+  let stats = queries::get_detailed_stats(db, util::get_unix() - 30 * DAY).await?;
+  let maintenance_mode = queries::is_maintenance_mode(db).await?;
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!(
+        "<b>Users</b>\n\
+        Total: <code>{}</code>\n\
+        Active in the last 30 days: <code>{}</code>\n\
+        Blocked the bot: <code>{}</code>\n\
+        Waiting to be wiped: <code>{}</code>\n\
+        \n\
+        <b>Entities</b>\n\
+        Saved by users: <code>{}</code>\n\
+        Untagged: <code>{}</code>\n\
+        Unique files: <code>{}</code>\n\
+        Quarantined files: <code>{}</code>\n\
+        \n\
+        <b>Tags</b>\n\
+        Unique tags: <code>{}</code>\n\
+        Tags on entities: <code>{}</code>\n\
+        \n\
+        <b>Jobs</b>\n\
+        Queued: <code>{}</code>\n\
+        Running: <code>{}</code>\n\
+        Failed: <code>{}</code>\n\
+        \n\
+        <b>Database</b>\n\
+        Size: <code>{}</code> MB\n\
+        Free space: <code>{}</code> MB\n\
+        Maintenance mode: <code>{}</code>",
+        stats.total_users,
+        stats.active_users,
+        stats.inactive_users,
+        stats.pending_deletion_users,
+        stats.total_entities,
+        stats.untagged_entities,
+        stats.total_files,
+        stats.quarantined_files,
+        stats.total_tags,
+        stats.total_tag_links,
+        stats.queued_jobs,
+        stats.running_jobs,
+        stats.failed_jobs,
+        stats.database_size / 1_000_000,
+        stats.database_free / 1_000_000,
+        if maintenance_mode { "on" } else { "off" },
+      ),
+    )
+    .await?;
+
+  Ok(())
+}
+
+// Telegram user ids are numbers, anything else is a typo
+fn parse_user_id(args: &str) -> Option<String> {
+  let user_id = args.trim();
+  user_id.parse::<u64>().ok().map(|_| user_id.to_string())
+}
+
+// Handles /user id
+pub async fn send_user_info(db: &DbConn, bot: &BotType, msg: &Message, args: String) -> Result<()> {
+  let Some(user_id) = parse_user_id(&args) else {
+    bot
+      .send_message_easy(msg.chat.id, "Usage: <code>/user id</code>")
+      .await?;
+    return Ok(());
+  };
+
+  let Some(stats) = queries::get_user_stats(db, user_id.clone()).await? else {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!("User <code>{}</code> has no data", user_id),
+      )
+      .await?;
+    return Ok(());
+  };
+
+  let deleted = match stats.deleted_at {
+    Some(deleted_at) => format!("used /stop {}", util::unix_to_humantime(deleted_at)),
+    None => "no".to_string(),
+  };
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!(
+        "<b>User</b> <code>{}</code>\n\
+        <i>Total</i>: <code>{}</code>\n\
+        Stickers: <code>{}</code>\n\
+        Animations: <code>{}</code>\n\
+        Photos: <code>{}</code>\n\
+        Videos: <code>{}</code>\n\
+        Quarantined: <code>{}</code>\n\
+        Tags: <code>{}</code>\n\
+        Sent: <code>{}</code>\n\
+        \n\
+        First added: {}\n\
+        Last used: {}\n\
+        Last export: {}\n\
+        Active jobs: <code>{}</code>\n\
+        Scheduled for wiping: {}\n\
+        Blocked the bot: {}",
+        user_id,
+        stats.total_stickers + stats.total_animations + stats.total_photos + stats.total_videos,
+        stats.total_stickers,
+        stats.total_animations,
+        stats.total_photos,
+        stats.total_videos,
+        stats.quarantined,
+        stats.total_tags,
+        stats.total_entities_sent,
+        util::unix_to_humantime(stats.first_added),
+        util::unix_to_humantime(stats.last_used),
+        util::unix_to_humantime(stats.last_export_time),
+        stats.active_jobs,
+        deleted,
+        stats
+          .inactive_at
+          .map(util::unix_to_humantime)
+          .unwrap_or_else(|| "no".to_string()),
+      ),
+    )
+    .await?;
+
+  Ok(())
+}
+
+// Handles /exportuser id [csv|json], for users that can no longer reach the bot
+// themselves
+pub async fn send_user_export(
+  db: &DbConn,
+  bot: &BotType,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let args = args.trim().to_lowercase();
+  let (user_id, format) = args.split_once(' ').unwrap_or((&args, ""));
+
+  let format = match format.trim() {
+    "" => Some(ExportFormat::Stimkerbot),
+    "csv" => Some(ExportFormat::Csv),
+    "json" => Some(ExportFormat::Json),
+    _ => None,
+  };
+  let (Some(user_id), Some(format)) = (parse_user_id(user_id), format) else {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "Usage: <code>/exportuser id</code>, <code>/exportuser id csv</code> or <code>/exportuser id json</code>",
+      )
+      .await?;
+    return Ok(());
+  };
+
+  if queries::get_user_stats(db, user_id.clone())
+    .await?
+    .is_none()
+  {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!("User <code>{}</code> has no data", user_id),
+      )
+      .await?;
+    return Ok(());
+  }
+
+  log::info!("Admin export of user {:?}", user_id);
+
+  // The job belongs to the admin, the user never sees it in /jobs
+  let admin_id = msg.from.as_ref().unwrap().id.to_string();
+  jobs::submit(
+    db,
+    bot,
+    msg.chat.id,
+    admin_id,
+    JobKind::UserExport { user_id, format },
+  )
+  .await?;
+
+  Ok(())
+}
+
+// Handles /wipeuser id [confirm], nothing is wiped without confirm
+pub async fn send_user_wipe(
+  db: &DbConn,
+  pending_deletions: &PendingDeletions,
+  bot: &BotType,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let args = args.trim().to_lowercase();
+  let (user_id, confirm) = args.split_once(' ').unwrap_or((&args, ""));
+
+  let Some(user_id) = parse_user_id(user_id) else {
+    bot
+      .send_message_easy(msg.chat.id, "Usage: <code>/wipeuser id</code>")
+      .await?;
+    return Ok(());
+  };
+
+  let Some(stats) = queries::get_user_stats(db, user_id.clone()).await? else {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!("User <code>{}</code> has no data", user_id),
+      )
+      .await?;
+    return Ok(());
+  };
+
+  if confirm.trim() != "confirm" {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!(
+          "This permanently wipes the <code>{}</code> entities of user <code>{}</code>, without an export or a way to restore it\n\
+          Use <code>/wipeuser {} confirm</code> to continue, or <code>/exportuser {}</code> first",
+          stats.total_stickers + stats.total_animations + stats.total_photos + stats.total_videos,
+          user_id,
+          user_id,
+          user_id
+        ),
+      )
+      .await?;
+    return Ok(());
+  }
+
+  log::info!("Admin wipe of user {:?}", user_id);
+
+  // Their jobs would otherwise write the data back
+  queries::cancel_jobs(db, user_id.clone(), None).await?;
+  queries::wipe_user(db, user_id.clone()).await?;
+  pending_deletions.remove(&user_id);
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!("Wiped all data of user <code>{}</code>", user_id),
+    )
+    .await?;
+
+  Ok(())
+}
+
+// Handles /maintenancemode [on|off], shows the current mode without arguments
+pub async fn send_maintenance_mode(
+  db: &DbConn,
+  maintenance_mode: &MaintenanceMode,
+  bot: &BotType,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let enabled = match args.trim().to_lowercase().as_str() {
+    "" => {
+      let enabled = maintenance_mode.is_on();
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          format!(
+            "Maintenance mode is <code>{}</code>, use <code>/maintenancemode on</code> or <code>/maintenancemode off</code> to change it",
+            if enabled { "on" } else { "off" }
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+    "on" => true,
+    "off" => false,
+    _ => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Usage: <code>/maintenancemode on</code> or <code>/maintenancemode off</code>",
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  queries::set_maintenance_mode(db, enabled).await?;
+  maintenance_mode.set(enabled);
+  log::info!(
+    "Maintenance mode turned {}",
+    if enabled { "on" } else { "off" }
+  );
+
+  let message = if enabled {
+    "Maintenance mode is on, only admins can use the bot and only their jobs run until <code>/maintenancemode off</code>"
+  } else {
+    "Maintenance mode is off, everyone can use the bot again"
+  };
+  bot.send_message_easy(msg.chat.id, message).await?;
+
+  Ok(())
+}
+
+// Everyone but the admins is turned away while the bot is in maintenance mode
+pub fn is_under_maintenance(maintenance_mode: MaintenanceMode, msg: Message) -> bool {
+  turns_away(&maintenance_mode, msg.from.as_ref())
+}
+
+pub fn is_inline_query_under_maintenance(
+  maintenance_mode: MaintenanceMode,
+  query: InlineQuery,
+) -> bool {
+  turns_away(&maintenance_mode, Some(&query.from))
+}
+
+pub fn is_inline_choice_under_maintenance(
+  maintenance_mode: MaintenanceMode,
+  result: ChosenInlineResult,
+) -> bool {
+  turns_away(&maintenance_mode, Some(&result.from))
+}
+
+fn turns_away(maintenance_mode: &MaintenanceMode, user: Option<&User>) -> bool {
+  if !maintenance_mode.is_on() {
+    return false;
+  }
+
+  !user.is_some_and(|user| util::is_admin(&user.id.to_string()))
+}
+
+pub async fn receive_while_under_maintenance(bot: BotType, msg: Message) -> Result<()> {
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      "The bot is under maintenance right now, please try again later",
+    )
+    .await?;
+
+  Ok(())
+}
+
+pub async fn receive_inline_query_while_under_maintenance(
+  bot: BotType,
+  query: InlineQuery,
+) -> Result<()> {
+  send_text_result(&bot, query.id, "The bot is under maintenance right now").await
+}
+
+// Handles /broadcast message, shows the message exactly as users will get it
+// before anything is sent
+pub async fn send_broadcast_preview(
+  db: &DbConn,
+  bot: &BotType,
+  dialogue: &DialogueWithState,
+  msg: &Message,
+  text: String,
+) -> Result<()> {
+  let text = text.trim().to_string();
+  if text.is_empty() {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "Usage: <code>/broadcast message</code>, the message can use HTML formatting",
+      )
+      .await?;
+    return Ok(());
+  }
+
+  // Broken formatting would otherwise fail for every single user
+  if let Err(e) = bot.send_message(msg.chat.id, text.clone()).await {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!(
+          "This message can not be sent, check the HTML formatting\n{}",
+          html_escape(&e.to_string())
+        ),
+      )
+      .await?;
+    return Ok(());
+  }
+
+  let recipients = queries::count_broadcast_recipients(db, String::new()).await?;
+
+  bot
+    .send_message_buttons(
+      msg.chat.id,
+      format!(
+        "This sends the message above to <code>{}</code> users\n\
+        Send <code>yes</code> to send it",
+        recipients
+      ),
+      vec!["yes", "/cancel"],
+    )
+    .await?;
+
+  dialogue
+    .update(ConversationState::VerifyBroadcast { text })
+    .await?;
+
+  Ok(())
+}
+
+pub async fn verify_broadcast(
+  db: Arc<DbConn>,
+  bot: BotType,
+  dialogue: DialogueWithState,
+  msg: Message,
+  text: String,
+) -> Result<()> {
+  dialogue.update(ConversationState::ReceiveEntityId).await?;
+
+  if msg.text().map(|t| t.trim().to_lowercase()) != Some("yes".to_string()) {
+    bot
+      .send_message_easy(msg.chat.id, "Broadcast cancelled")
+      .await?;
+    return Ok(());
+  }
+
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+  log::info!("Broadcast started by {:?}", user_id);
+
+  jobs::submit(&db, &bot, msg.chat.id, user_id, JobKind::Broadcast { text }).await?;
+
+  Ok(())
+}
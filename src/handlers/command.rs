@@ -7,11 +7,26 @@ use teloxide::{
   utils::command::BotCommands as _,
 };
 
-use super::import::send_bot_export;
-use super::{import::send_fix_entities, send_message::BetterSendMessage, tags::send_tags_usage};
+use super::admin::{
+  is_admin, send_admin_help, send_admin_stats, send_backup, send_broadcast_preview,
+  send_maintenance, send_maintenance_mode, send_user_export, send_user_info, send_user_wipe,
+};
+use super::import::send_export_command;
+use super::jobs::{send_cancel, send_jobs};
+use super::quarantine::send_quarantine;
+use super::retag::send_retag_preview;
+use super::untagged::send_next_untagged;
+use super::{
+  import::send_fix_entities,
+  send_message::BetterSendMessage,
+  tags::{send_tags_usage, send_undo},
+};
 use crate::{
   database::queries,
-  types::{BotType, ConversationState, DbConn, DialogueWithState},
+  tasks::purge,
+  types::{
+    BotType, ConversationState, DbConn, DialogueWithState, MaintenanceMode, PendingDeletions,
+  },
 };
 
 #[derive(BotCommands)]
@@ -26,14 +41,30 @@ pub enum Command {
   #[command(description = "Add or remove tags to an entire stickerpack")]
   Pack,
 
+  #[command(
+    description = "Add or remove tags on everything matching a search, e.g. /retag cat -dog => +animal -pet"
+  )]
+  Retag(String),
+
+  #[command(description = "Go through everything that has no tags left, and tag or delete it")]
+  Untagged,
+
+  #[command(description = "Undo your last tag changes, e.g. /undo or /undo 3")]
+  Undo(String),
+
   #[command(description = "List all your used tags and how many times they were used")]
   Tags,
 
-  #[command(description = "Stop whatever you are doing")]
-  Cancel,
+  #[command(description = "Stop whatever you are doing, or a job with /cancel id")]
+  Cancel(String),
+
+  #[command(description = "Show how your imports, exports and fixes are going")]
+  Jobs,
 
-  #[command(description = "Export your data")]
-  Export,
+  #[command(
+    description = "Export your data, use /export csv or /export json for a spreadsheet friendly file, or /export archive to move to another bot"
+  )]
+  Export(String),
 
   #[command(description = "Import your data")]
   Import,
@@ -41,11 +72,21 @@ pub enum Command {
   #[command(description = "Import your data from a QuickStickBot or QuickGifBot export")]
   QSImport,
 
+  #[command(
+    description = "Import what you sent from a Telegram Desktop chat export, to tag it with /untagged"
+  )]
+  TDImport,
+
   #[command(
     description = "If stickerbot is not longer working, try this. (This is a slow operation, use sparingly)"
   )]
   FixEntities,
 
+  #[command(
+    description = "Review what stopped working and is hidden, use /quarantine purge to remove it"
+  )]
+  Quarantine(String),
+
   #[command(description = "Shows global statistics about this bot")]
   Stats,
 
@@ -54,10 +95,42 @@ pub enum Command {
 
   #[command(description = "DANGEROUS! Wipes your data")]
   Stop,
+
+  #[command(description = "Undo a /stop, before your data is permanently wiped")]
+  Restore,
+
+  #[command(hide)]
+  Maintenance(String),
+
+  #[command(hide)]
+  Backup(String),
+
+  #[command(hide)]
+  Admin,
+
+  #[command(hide)]
+  AdminStats,
+
+  #[command(hide)]
+  User(String),
+
+  #[command(hide)]
+  ExportUser(String),
+
+  #[command(hide)]
+  WipeUser(String),
+
+  #[command(hide)]
+  MaintenanceMode(String),
+
+  #[command(hide)]
+  Broadcast(String),
 }
 
 pub async fn receive_command(
   db: Arc<DbConn>,
+  pending_deletions: PendingDeletions,
+  maintenance_mode: MaintenanceMode,
   bot: BotType,
   me: Me,
   dialogue: DialogueWithState,
@@ -83,6 +156,7 @@ pub async fn receive_command(
                     You can negate tags  by appending <code>-</code> to a tag. \
                     You can use some special filters to narrow down your search.\n\
                     - <code>all</code> will show all your stickers, gifs, photos and videos\n\
+                    - <code>untagged</code> will show everything that has no tags left\n\
                     - <code>sticker</code> or <code>stk</code> will only show stickers\n\
                     - <code>animation</code> or <code>gif</code> will only show gifs\n\
                     - <code>photo</code> or <code>pic</code> will only show photos\n\
@@ -104,6 +178,10 @@ pub async fn receive_command(
             .await?;
     }
     Ok(Command::Start) => {
+      // Unblocking the bot sends /start, so broadcasts reach them again
+      let user_id = msg.from.as_ref().unwrap().id.to_string();
+      queries::set_user_active(&db, user_id).await?;
+
       bot
         .send_message_easy(
           msg.chat.id,
@@ -131,17 +209,40 @@ pub async fn receive_command(
         .update(ConversationState::RecieveEntitiesId)
         .await?;
     }
+    Ok(Command::Retag(input)) => {
+      if dialogue.get().await?.unwrap() != ConversationState::ReceiveEntityId {
+        bot
+          .send_message_easy(msg.chat.id, "Please finish your action, or /cancel")
+          .await?;
+        return Ok(());
+      }
+
+      send_retag_preview(&db, &bot, &dialogue, &msg, input).await?;
+    }
+    Ok(Command::Untagged) => {
+      if dialogue.get().await?.unwrap() != ConversationState::ReceiveEntityId {
+        bot
+          .send_message_easy(msg.chat.id, "Please finish your action, or /cancel")
+          .await?;
+        return Ok(());
+      }
+
+      send_next_untagged(&db, &bot, &dialogue, &msg, 0).await?;
+    }
+    Ok(Command::Undo(levels)) => {
+      send_undo(&db, &bot, &msg, levels).await?;
+    }
     Ok(Command::Tags) => {
       send_tags_usage(db, bot, msg).await?;
     }
-    Ok(Command::Export) => {
-      send_bot_export(&db, &bot, &msg).await?;
+    Ok(Command::Export(args)) => {
+      send_export_command(&db, &bot, &msg, args).await?;
     }
     Ok(Command::Import) => {
       bot
         .send_message_buttons(
           msg.chat.id,
-          "Ready to import, please send me the file you got from /export",
+          "Ready to import, please send me the file you got from /export, /export csv, /export json or /export archive",
           vec!["/cancel"],
         )
         .await?;
@@ -158,12 +259,30 @@ pub async fn receive_command(
 
       dialogue.update(ConversationState::ReceiveQSImport).await?;
     }
+    Ok(Command::TDImport) => {
+      bot
+        .send_message_buttons(
+          msg.chat.id,
+          "Ready to import, please send me a Telegram Desktop chat export, with the folder packed as a zip file\n\
+          Every sticker, GIF, photo and video you sent is added without tags, so you can tag them with /untagged. \
+          Bots can only download files up to 20 MB, export a shorter time span if it is larger",
+          vec!["/cancel"],
+        )
+        .await?;
+
+      dialogue.update(ConversationState::ReceiveTDImport).await?;
+    }
     Ok(Command::FixEntities) => {
       send_fix_entities(&db, &bot, &msg).await?;
     }
-    Ok(Command::Cancel) => {
-      dialogue.update(ConversationState::ReceiveEntityId).await?;
-      bot.send_message_easy(msg.chat.id, "Cancelled").await?;
+    Ok(Command::Quarantine(args)) => {
+      send_quarantine(&db, &bot, &msg, args).await?;
+    }
+    Ok(Command::Cancel(args)) => {
+      send_cancel(&db, &bot, &dialogue, &msg, args).await?;
+    }
+    Ok(Command::Jobs) => {
+      send_jobs(&db, &bot, &msg).await?;
     }
     Ok(Command::Stats) => {
       let stats = queries::get_global_stats(&db).await?;
@@ -210,13 +329,67 @@ pub async fn receive_command(
       bot
         .send_message_buttons(
           msg.chat.id,
-          "Please send 'I WANT TO DELETE EVERYTHING' to confirm",
+          format!(
+            "Please send 'I WANT TO DELETE EVERYTHING' to confirm\n\
+            You will receive an export of your data first, and can use /restore within <code>{}</code> days",
+            purge::grace_period() / (24 * 60 * 60 * 1000)
+          ),
           vec!["/cancel"],
         )
         .await?;
       dialogue.update(ConversationState::VerifyStop).await?;
     }
-    Err(_) => {
+    Ok(Command::Restore) => {
+      bot
+        .send_message_easy(msg.chat.id, "Your data is not scheduled to be wiped")
+        .await?;
+    }
+    Ok(Command::Maintenance(args)) if is_admin(&msg) => {
+      send_maintenance(&db, &bot, &msg, args).await?;
+    }
+    Ok(Command::Backup(args)) if is_admin(&msg) => {
+      send_backup(&db, &bot, &msg, args).await?;
+    }
+    Ok(Command::Admin) if is_admin(&msg) => {
+      send_admin_help(&bot, &msg).await?;
+    }
+    Ok(Command::AdminStats) if is_admin(&msg) => {
+      send_admin_stats(&db, &bot, &msg).await?;
+    }
+    Ok(Command::User(args)) if is_admin(&msg) => {
+      send_user_info(&db, &bot, &msg, args).await?;
+    }
+    Ok(Command::ExportUser(args)) if is_admin(&msg) => {
+      send_user_export(&db, &bot, &msg, args).await?;
+    }
+    Ok(Command::WipeUser(args)) if is_admin(&msg) => {
+      send_user_wipe(&db, &pending_deletions, &bot, &msg, args).await?;
+    }
+    Ok(Command::MaintenanceMode(args)) if is_admin(&msg) => {
+      send_maintenance_mode(&db, &maintenance_mode, &bot, &msg, args).await?;
+    }
+    Ok(Command::Broadcast(text)) if is_admin(&msg) => {
+      if dialogue.get().await?.unwrap() != ConversationState::ReceiveEntityId {
+        bot
+          .send_message_easy(msg.chat.id, "Please finish your action, or /cancel")
+          .await?;
+        return Ok(());
+      }
+
+      send_broadcast_preview(&db, &bot, &dialogue, &msg, text).await?;
+    }
+    Ok(
+      Command::Maintenance(_)
+      | Command::Backup(_)
+      | Command::Admin
+      | Command::AdminStats
+      | Command::User(_)
+      | Command::ExportUser(_)
+      | Command::WipeUser(_)
+      | Command::MaintenanceMode(_)
+      | Command::Broadcast(_),
+    )
+    | Err(_) => {
       bot
         .send_message_easy(msg.chat.id, "Unknown command")
         .await?;
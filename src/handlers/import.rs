@@ -1,14 +1,17 @@
 use std::sync::Arc;
 
 use anyhow::{bail, Result};
-use teloxide::{
-  net::Download,
-  requests::Requester,
-  types::{InputFile, Message},
-};
+use teloxide::types::Message;
 
 use crate::{
-  database::{import, queries},
+  database::{
+    import::{
+      self,
+      types::{ExportFormat, ImportMode},
+    },
+    queries,
+  },
+  tasks::jobs::{self, JobKind},
   types::{BotType, ConversationState, DbConn, DialogueWithState},
   util::get_unix,
 };
@@ -23,33 +26,23 @@ pub async fn receive_qs_import(
 ) -> Result<()> {
   dialogue.update(ConversationState::ReceiveEntityId).await?;
 
-  let file_data = extract_file(&bot, &msg).await?;
+  let file_id = extract_file_id(&bot, &msg).await?;
 
   let user_id = msg.from.as_ref().unwrap().id.to_string();
 
-  bot
-    .send_message_easy(msg.chat.id, format!("Importing your entities..."))
-    .await?;
-
-  let result = import::quickstickbot_import(&db, user_id, file_data).await;
-  match result {
-    Ok(_) => {
-      bot
-        .send_message_easy(msg.chat.id, format!("Imported your entities!"))
-        .await?;
-    }
-    Err(e) => {
-      bot
-        .send_message_easy(msg.chat.id, format!("Failed to import your entities"))
-        .await?;
-      log::error!("Failed to import entities: {:?}", e);
-    }
-  };
+  jobs::submit(
+    &db,
+    &bot,
+    msg.chat.id,
+    user_id,
+    JobKind::QuickStickBotImport { file_id },
+  )
+  .await?;
 
   Ok(())
 }
 
-pub async fn receive_bot_import(
+pub async fn receive_td_import(
   db: Arc<DbConn>,
   bot: BotType,
   dialogue: DialogueWithState,
@@ -57,51 +50,240 @@ pub async fn receive_bot_import(
 ) -> Result<()> {
   dialogue.update(ConversationState::ReceiveEntityId).await?;
 
-  let file_data = extract_file(&bot, &msg).await?;
+  let file_id = extract_file_id(&bot, &msg).await?;
 
   let user_id = msg.from.as_ref().unwrap().id.to_string();
 
+  jobs::submit(
+    &db,
+    &bot,
+    msg.chat.id,
+    user_id,
+    JobKind::TelegramDesktopImport { file_id },
+  )
+  .await?;
+
+  Ok(())
+}
+
+pub async fn receive_bot_import(
+  bot: BotType,
+  dialogue: DialogueWithState,
+  msg: Message,
+) -> Result<()> {
+  dialogue.update(ConversationState::ReceiveEntityId).await?;
+
+  let file_id = extract_file_id(&bot, &msg).await?;
+
   bot
-    .send_message_easy(msg.chat.id, format!("Importing your entities..."))
+    .send_message_keyboard(
+      msg.chat.id,
+      "How do you want to import this?\n\
+      - <code>merge</code> adds the imported tags, and keeps the highest usage statistics\n\
+      - <code>overwrite</code> replaces the tags and statistics of everything in the import\n\
+      - <code>replace all</code> wipes your current data, and only keeps the import\n\
+      Prefix the mode with <code>dry run</code> to only see what would change.\n\
+      If your export was split into parts, send the remaining parts first",
+      import_mode_keyboard(),
+    )
     .await?;
 
-  let result = import::import(&db, user_id, file_data).await;
-  match result {
-    Ok(_) => {
-      bot
-        .send_message_easy(msg.chat.id, format!("Imported your entities!"))
-        .await?;
-    }
-    Err(e) => {
+  dialogue
+    .update(ConversationState::ReceiveBotImportMode {
+      file_ids: vec![file_id],
+    })
+    .await?;
+
+  Ok(())
+}
+
+pub async fn receive_bot_import_mode(
+  db: Arc<DbConn>,
+  bot: BotType,
+  dialogue: DialogueWithState,
+  msg: Message,
+  mut file_ids: Vec<String>,
+) -> Result<()> {
+  // The other parts of a split export come before the mode, they are only
+  // downloaded once the import runs
+  if msg.document().is_some() {
+    // A part that can't be used doesn't throw away the ones received so far
+    let file_id = match document_file_id(&msg) {
+      Ok(file_id) => file_id,
+      Err(problem) => {
+        bot
+          .send_message_keyboard(
+            msg.chat.id,
+            format!(
+              "{}, the other <code>{}</code> files are kept. Send the remaining parts or pick how to import them",
+              problem,
+              file_ids.len()
+            ),
+            import_mode_keyboard(),
+          )
+          .await?;
+        return Ok(());
+      }
+    };
+    file_ids.push(file_id);
+
+    bot
+      .send_message_keyboard(
+        msg.chat.id,
+        format!(
+          "Received <code>{}</code> files, send the remaining parts or pick how to import them",
+          file_ids.len()
+        ),
+        import_mode_keyboard(),
+      )
+      .await?;
+
+    dialogue
+      .update(ConversationState::ReceiveBotImportMode { file_ids })
+      .await?;
+    return Ok(());
+  }
+
+  let text = msg.text().unwrap_or_default().trim().to_lowercase();
+  let (dry_run, mode) = match text.strip_prefix("dry run") {
+    Some(mode) => (true, mode.trim()),
+    None => (false, text.as_str()),
+  };
+
+  let mode = match mode {
+    "merge" => ImportMode::Merge,
+    "overwrite" => ImportMode::Overwrite,
+    "replace all" => ImportMode::ReplaceAll,
+    _ => {
       bot
-        .send_message_easy(msg.chat.id, format!("Failed to import your entities"))
+        .send_message_easy(
+          msg.chat.id,
+          "Please pick one of the import modes, or /cancel",
+        )
         .await?;
-      log::error!("Failed to import entities: {:?}", e);
+      return Ok(());
     }
   };
 
+  dialogue.update(ConversationState::ReceiveEntityId).await?;
+
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  jobs::submit(
+    &db,
+    &bot,
+    msg.chat.id,
+    user_id,
+    JobKind::Import {
+      file_ids,
+      mode,
+      dry_run,
+    },
+  )
+  .await?;
+
   Ok(())
 }
 
-pub async fn send_bot_export(db: &DbConn, bot: &BotType, msg: &Message) -> Result<()> {
-  bot
-    .send_message_easy(msg.chat.id, "Exporting your entities...")
-    .await?;
+// Handles /export [csv|json|archive] [since:<date>|since last export]
+pub async fn send_export_command(
+  db: &DbConn,
+  bot: &BotType,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let args = args.trim().to_lowercase();
+  let (format, since) = match args.split_once("since") {
+    Some((format, since)) => (format.trim(), Some(since.trim_start_matches(':').trim())),
+    None => (args.as_str(), None),
+  };
 
-  let user_id = msg.from.as_ref().unwrap().id.to_string();
+  if format == "archive" {
+    if since.is_some() {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Archives are always complete, use /export archive without a date",
+        )
+        .await?;
+      return Ok(());
+    }
 
-  let data = import::export(&db, user_id).await?;
+    let user_id = msg.from.as_ref().unwrap().id.to_string();
+    jobs::submit(db, bot, msg.chat.id, user_id, JobKind::ArchiveExport).await?;
+    return Ok(());
+  }
 
-  bot
-    .send_document(
-      msg.chat.id,
-      InputFile::memory(data).file_name("export.stimkerbot"),
-    )
-    .await?;
+  let format = match format {
+    "" => ExportFormat::Stimkerbot,
+    "csv" => ExportFormat::Csv,
+    "json" => ExportFormat::Json,
+    _ => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Unknown export format, use /export, /export csv, /export json or /export archive",
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let since = match since {
+    None => None,
+    Some(_) if format != ExportFormat::Stimkerbot => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Only /export can contain just the changes, csv and json are always complete",
+        )
+        .await?;
+      return Ok(());
+    }
+    Some("last export" | "last") => {
+      let user_id = msg.from.as_ref().unwrap().id.to_string();
+      match queries::get_last_export_time(db, user_id).await? {
+        0 => {
+          bot
+            .send_message_easy(
+              msg.chat.id,
+              "You haven't exported anything yet, so this will be a full export",
+            )
+            .await?;
+          None
+        }
+        last_export_time => Some(last_export_time),
+      }
+    }
+    Some(date) => match import::parse_time(date) {
+      Ok(since) => Some(since),
+      Err(_) => {
+        bot
+          .send_message_easy(
+            msg.chat.id,
+            "Invalid date, use something like <code>/export since:2024-05-01</code> \
+            or <code>/export since last export</code>, times are in UTC",
+          )
+          .await?;
+        return Ok(());
+      }
+    },
+  };
+
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+  jobs::submit(
+    db,
+    bot,
+    msg.chat.id,
+    user_id,
+    JobKind::Export { format, since },
+  )
+  .await?;
 
   Ok(())
 }
 
+// Checks the files in the background, the dialogue stays usable
 pub async fn send_fix_entities(db: &DbConn, bot: &BotType, msg: &Message) -> Result<()> {
   let user_id = msg.from.as_ref().unwrap().id.to_string();
 
@@ -116,54 +298,56 @@ pub async fn send_fix_entities(db: &DbConn, bot: &BotType, msg: &Message) -> Res
     return Ok(());
   }
 
-  bot
-    .send_message_easy(msg.chat.id, "Fixing your entities...")
-    .await?;
-
-  let progress_message = bot.send_message(msg.chat.id, "Starting...").await?;
-
-  let result = import::fix(db, &bot, &progress_message, user_id.to_owned()).await;
-
-  if let Err(e) = result {
+  let fix_jobs =
+    queries::count_active_jobs(db, user_id.clone(), Some(JobKind::FixEntities.name())).await?;
+  if fix_jobs > 0 {
     bot
-      .send_message_easy(msg.chat.id, format!("Failed to fix your entities"))
+      .send_message_easy(
+        msg.chat.id,
+        "Your entities are already being fixed, see /jobs",
+      )
       .await?;
-    log::error!("Failed to fix entities: {:?}", e);
     return Ok(());
-  } else if let Ok(result) = result {
-    queries::set_last_fix_time(db, user_id.to_owned(), get_unix()).await?;
-
-    bot
-      .send_message_easy(msg.chat.id, format!("Fixed {} entities!", result))
-      .await?;
   }
 
+  jobs::submit(db, bot, msg.chat.id, user_id, JobKind::FixEntities).await?;
+
   Ok(())
 }
 
 // ===========================================================
 
-async fn extract_file(bot: &BotType, msg: &Message) -> Result<Vec<u8>> {
-  // Check if message has a json attachment
-  if msg.document().is_none() {
-    bot
-      .send_message_easy(msg.chat.id, "No file sent, operation cancelled")
-      .await?;
-    bail!("No file sent");
+fn import_mode_keyboard() -> Vec<Vec<&'static str>> {
+  vec![
+    vec!["merge", "overwrite", "replace all"],
+    vec!["dry run merge", "dry run overwrite", "dry run replace all"],
+    vec!["/cancel"],
+  ]
+}
+
+async fn extract_file_id(bot: &BotType, msg: &Message) -> Result<String> {
+  match document_file_id(msg) {
+    Ok(file_id) => Ok(file_id),
+    Err(problem) => {
+      bot
+        .send_message_easy(msg.chat.id, format!("{}, operation cancelled", problem))
+        .await?;
+      bail!(problem);
+    }
   }
+}
 
-  let doc = msg.document().unwrap();
+// The file id of the attachment, or why it can't be imported
+fn document_file_id(msg: &Message) -> Result<String, &'static str> {
+  // Check if message has a json attachment
+  let Some(doc) = msg.document() else {
+    return Err("No file sent");
+  };
 
-  if doc.file.size > 50_000_000 {
-    bot
-      .send_message_easy(msg.chat.id, "File too large, operation cancelled")
-      .await?;
-    bail!("File too large");
+  // Bots can't download anything bigger
+  if doc.file.size > 20_000_000 {
+    return Err("File too large, files can be atmost 20 MB");
   }
 
-  let doc_data = bot.get_file(&doc.file.id).await?;
-  let mut file_data = Vec::new();
-  bot.download_file(&doc_data.path, &mut file_data).await?;
-
-  Ok(file_data)
+  Ok(doc.file.id.clone())
 }
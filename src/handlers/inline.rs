@@ -38,7 +38,7 @@ pub async fn handler_inline_query(db: Arc<DbConn>, bot: BotType, query: InlineQu
   log::debug!("Parsed search query: {:?}", search_query);
 
   // Check if atleast 1 tag is present
-  if search_query.tags.len() == 0 && !search_query.get_all {
+  if search_query.tags.len() == 0 && !search_query.get_all && !search_query.untagged {
     send_text_result(&bot, query.id, "Please enter atleast 1 normal tag").await?;
     return Ok(());
   }
@@ -69,7 +69,7 @@ pub async fn handler_inline_query(db: Arc<DbConn>, bot: BotType, query: InlineQu
   Ok(())
 }
 
-fn parse_search(input: &String) -> InlineSearchQuery {
+pub fn parse_search(input: &str) -> InlineSearchQuery {
   let mut query = InlineSearchQuery::default();
 
   let tags_all: Vec<String> = input
@@ -83,6 +83,10 @@ fn parse_search(input: &String) -> InlineSearchQuery {
         query.get_all = true;
         false
       }
+      "untagged" => {
+        query.untagged = true;
+        false
+      }
       "sticker" | "stk" => {
         query.entity_type = Some(EntityType::Sticker);
         false
@@ -185,7 +189,7 @@ where
   Ok(())
 }
 
-async fn send_text_result<I, R>(bot: &BotType, inline_query_id: I, text: R) -> Result<()>
+pub(super) async fn send_text_result<I, R>(bot: &BotType, inline_query_id: I, text: R) -> Result<()>
 where
   I: Into<String>,
   R: Into<String>,
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use teloxide::{types::Message, utils::html::escape as html_escape};
+
+use crate::{
+  database::queries,
+  types::{BotType, ConversationState, DbConn, DialogueWithState},
+  util::unix_to_humantime,
+};
+
+use super::send_message::BetterSendMessage;
+
+// How many finished jobs /jobs shows
+const FINISHED_JOBS_SHOWN: i64 = 5;
+
+pub async fn send_jobs(db: &DbConn, bot: &BotType, msg: &Message) -> Result<()> {
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  let jobs = queries::list_jobs(db, user_id, FINISHED_JOBS_SHOWN).await?;
+
+  if jobs.is_empty() {
+    bot
+      .send_message_easy(msg.chat.id, "You have no jobs")
+      .await?;
+    return Ok(());
+  }
+
+  let mut message = "<b>Your jobs</b>".to_string();
+  for job in jobs {
+    message += &format!(
+      "\n<code>{}</code> {} - <i>{}</i>, started {}",
+      job.job_id,
+      job.kind.replace('_', " "),
+      job.status,
+      unix_to_humantime(job.created_at)
+    );
+    if !job.progress.is_empty() {
+      message += &format!("\n  {}", html_escape(&job.progress));
+    }
+  }
+  message += "\nUse <code>/cancel id</code> to stop a job";
+
+  bot.send_message_easy(msg.chat.id, message).await?;
+
+  Ok(())
+}
+
+// Handles /cancel [job id]. Without an id it leaves the current action, or
+// stops all jobs when there is nothing else to cancel.
+pub async fn send_cancel(
+  db: &DbConn,
+  bot: &BotType,
+  dialogue: &DialogueWithState,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+  let args = args.trim();
+
+  if !args.is_empty() {
+    let Ok(job_id) = args.parse::<i64>() else {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Usage: <code>/cancel</code> or <code>/cancel id</code>, see /jobs",
+        )
+        .await?;
+      return Ok(());
+    };
+
+    if queries::cancel_jobs(db, user_id, Some(job_id)).await? > 0 {
+      bot
+        .send_message_easy(msg.chat.id, "Cancelled, the job will stop")
+        .await?;
+    } else {
+      bot
+        .send_message_easy(msg.chat.id, "There is no such job running, see /jobs")
+        .await?;
+    }
+    return Ok(());
+  }
+
+  if dialogue.get().await?.unwrap_or_default() != ConversationState::ReceiveEntityId {
+    dialogue.update(ConversationState::ReceiveEntityId).await?;
+    bot.send_message_easy(msg.chat.id, "Cancelled").await?;
+    return Ok(());
+  }
+
+  match queries::cancel_jobs(db, user_id, None).await? {
+    0 => bot.send_message_easy(msg.chat.id, "Cancelled").await?,
+    cancelled => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          format!("Cancelled, <code>{}</code> jobs will stop", cancelled),
+        )
+        .await?
+    }
+  };
+
+  Ok(())
+}
@@ -1,7 +1,12 @@
+pub mod admin;
 pub mod command;
 pub mod import;
 pub mod inline;
+pub mod jobs;
+pub mod quarantine;
+pub mod retag;
 pub mod stop;
 pub mod tags;
+pub mod untagged;
 
 mod send_message;
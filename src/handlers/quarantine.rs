@@ -0,0 +1,84 @@
+use anyhow::Result;
+use teloxide::{types::Message, utils::html::escape as html_escape};
+
+use crate::{
+  database::queries,
+  types::{BotType, DbConn},
+  util::unix_to_humantime,
+};
+
+use super::send_message::BetterSendMessage;
+
+// Handles /quarantine [purge]
+pub async fn send_quarantine(
+  db: &DbConn,
+  bot: &BotType,
+  msg: &Message,
+  args: String,
+) -> Result<()> {
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  match args.trim().to_lowercase().as_str() {
+    "" => {}
+    "purge" => {
+      let journal_id =
+        queries::start_journal(db, user_id.clone(), "Purging the quarantine").await?;
+      let purged = queries::purge_quarantine(db, user_id, journal_id).await?;
+
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          format!(
+            "Removed <code>{}</code> entities from the quarantine, use /undo to get their tags back",
+            purged
+          ),
+        )
+        .await?;
+      return Ok(());
+    }
+    _ => {
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          "Usage: <code>/quarantine</code> or <code>/quarantine purge</code>",
+        )
+        .await?;
+      return Ok(());
+    }
+  }
+
+  let entities = queries::list_quarantined_entities(db, user_id).await?;
+
+  if entities.is_empty() {
+    bot
+      .send_message_easy(msg.chat.id, "Nothing is in the quarantine")
+      .await?;
+    return Ok(());
+  }
+
+  let mut message = format!(
+    "<b>{} entities stopped working</b>\n\
+    They are hidden from search, and come back with their tags when you send them to me again\n",
+    entities.len()
+  );
+
+  for entity in entities.iter().take(20) {
+    message += &format!(
+      "\n- {:?} <code>{}</code>: <b>{}</b> <i>({}, {})</i>",
+      entity.entity_type,
+      html_escape(&entity.entity_id),
+      html_escape(entity.tags.as_deref().unwrap_or("no tags")),
+      html_escape(entity.broken_reason.as_deref().unwrap_or("unknown reason")),
+      unix_to_humantime(entity.broken_at)
+    );
+  }
+  if entities.len() > 20 {
+    message += &format!("\nand {} more", entities.len() - 20);
+  }
+
+  message += "\n\nUse <code>/quarantine purge</code> to remove all of them";
+
+  bot.send_message_easy(msg.chat.id, message).await?;
+
+  Ok(())
+}
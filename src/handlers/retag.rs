@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use teloxide::types::Message;
+
+use crate::{
+  database::{
+    queries::{self, InsertEntity},
+    EntityType,
+  },
+  handlers::{inline::parse_search, send_message::BetterSendMessage as _},
+  types::{BotType, ConversationState, DbConn, DialogueWithState, InlineSearchQuery},
+  util,
+};
+
+pub async fn send_retag_preview(
+  db: &DbConn,
+  bot: &BotType,
+  dialogue: &DialogueWithState,
+  msg: &Message,
+  input: String,
+) -> Result<()> {
+  let Some((search, changes)) = input.split_once("=>") else {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "Usage: <code>/retag search tags => +tag -tag</code>\n\
+        - The left side is a normal search, like you would type inline\n\
+        - The right side lists the tags to add, start a tag with <code>-</code> to remove it",
+      )
+      .await?;
+    return Ok(());
+  };
+
+  let query = parse_search(search);
+  if query.tags.is_empty() && !query.get_all && !query.untagged {
+    bot
+      .send_message_easy(msg.chat.id, "Please search for atleast 1 normal tag")
+      .await?;
+    return Ok(());
+  }
+
+  let tags: Vec<String> = changes
+    .to_lowercase()
+    .replace(",", " ")
+    .split(" ")
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect();
+
+  // split the tags into add and remove, only the leading - or + is an operator
+  let remove_tags = tags
+    .iter()
+    .filter_map(|tag| tag.strip_prefix('-'))
+    .map(util::normalize_tag)
+    .filter(|tag| !tag.is_empty())
+    .collect::<Vec<String>>();
+
+  let add_tags = tags
+    .iter()
+    .filter(|tag| !tag.starts_with('-'))
+    .map(|tag| tag.strip_prefix('+').unwrap_or(tag))
+    .map(util::normalize_tag)
+    .filter(|tag| !tag.is_empty())
+    .collect::<Vec<String>>();
+
+  if add_tags.is_empty() && remove_tags.is_empty() {
+    bot
+      .send_message_easy(msg.chat.id, "No tags to add or remove provided")
+      .await?;
+    return Ok(());
+  }
+
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+  let entities = queries::find_all_entities(db, user_id.clone(), query.clone()).await?;
+
+  log::debug!(
+    "Retag preview for {:?}: {} entities, adding {:?}, removing {:?}",
+    user_id,
+    entities.len(),
+    add_tags,
+    remove_tags
+  );
+
+  if entities.is_empty() {
+    bot
+      .send_message_easy(msg.chat.id, "No entities match this search")
+      .await?;
+    return Ok(());
+  }
+
+  bot
+    .send_message_buttons(
+      msg.chat.id,
+      format!(
+        "This will change <code>{}</code> entities\n\
+        Adding tags: <b>{}</b>\n\
+        Removing tags: <b>{}</b>\n\
+        Send <code>yes</code> to apply these changes",
+        entities.len(),
+        add_tags.join(", "),
+        remove_tags.join(", ")
+      ),
+      vec!["yes", "/cancel"],
+    )
+    .await?;
+
+  dialogue
+    .update(ConversationState::VerifyRetag {
+      query,
+      add_tags,
+      remove_tags,
+    })
+    .await?;
+
+  Ok(())
+}
+
+pub async fn verify_retag(
+  db: Arc<DbConn>,
+  bot: BotType,
+  dialogue: DialogueWithState,
+  msg: Message,
+  (query, add_tags, remove_tags): (InlineSearchQuery, Vec<String>, Vec<String>),
+) -> Result<()> {
+  dialogue.update(ConversationState::ReceiveEntityId).await?;
+
+  if msg.text().map(|t| t.trim().to_lowercase()) != Some("yes".to_string()) {
+    bot
+      .send_message_easy(msg.chat.id, "Retag action cancelled")
+      .await?;
+    return Ok(());
+  }
+
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  // Search again, the library could have changed since the preview
+  let entities = queries::find_all_entities(&db, user_id.clone(), query).await?;
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!("Processing <code>{}</code> entities...", entities.len()),
+    )
+    .await?;
+
+  let journal_id = queries::start_journal(&db, user_id.clone(), "Retagging a search").await?;
+
+  for chunk in entities.chunks(1000) {
+    // insert_tags expects all entities to be of the same type
+    for entity_type in [
+      EntityType::Sticker,
+      EntityType::Animation,
+      EntityType::Photo,
+      EntityType::Video,
+    ] {
+      let insert_entities: Vec<InsertEntity> = chunk
+        .iter()
+        .filter(|entity| entity.entity_type == entity_type)
+        .map(|entity| InsertEntity {
+          entity_id: entity.entity_id.clone(),
+          file_id: entity.file_id.clone(),
+        })
+        .collect();
+
+      queries::insert_tags(
+        &db,
+        user_id.clone(),
+        insert_entities,
+        entity_type,
+        add_tags.clone(),
+        journal_id,
+      )
+      .await?;
+    }
+
+    queries::remove_tags(
+      &db,
+      user_id.clone(),
+      chunk.iter().map(|e| e.entity_id.clone()).collect(),
+      remove_tags.clone(),
+      journal_id,
+    )
+    .await?;
+  }
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!(
+        "Success! Retagged <code>{}</code> entities\n\
+        Added tags: <b>{}</b>\n\
+        Removed tags: <b>{}</b>",
+        entities.len(),
+        add_tags.join(", "),
+        remove_tags.join(", ")
+      ),
+    )
+    .await?;
+
+  Ok(())
+}
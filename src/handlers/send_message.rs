@@ -7,6 +7,17 @@ use teloxide::{
 use crate::types::BotType;
 
 pub trait BetterSendMessage {
+  fn send_message_keyboard<C, T, S>(
+    &self,
+    chat_id: C,
+    text: T,
+    rows: Vec<Vec<S>>,
+  ) -> <BotType as Requester>::SendMessage
+  where
+    C: Into<Recipient>,
+    T: Into<String>,
+    S: Into<String>;
+
   fn send_message_buttons<C, T, S>(
     &self,
     chat_id: C,
@@ -16,7 +27,10 @@ pub trait BetterSendMessage {
   where
     C: Into<Recipient>,
     T: Into<String>,
-    S: Into<String>;
+    S: Into<String>,
+  {
+    self.send_message_keyboard(chat_id, text, vec![buttons])
+  }
 
   fn send_message_easy<C, T>(&self, chat_id: C, text: T) -> <BotType as Requester>::SendMessage
   where
@@ -28,11 +42,11 @@ pub trait BetterSendMessage {
 }
 
 impl BetterSendMessage for BotType {
-  fn send_message_buttons<C, T, S>(
+  fn send_message_keyboard<C, T, S>(
     &self,
     chat_id: C,
     text: T,
-    buttons: Vec<S>,
+    rows: Vec<Vec<S>>,
   ) -> <BotType as Requester>::SendMessage
   where
     C: Into<Recipient>,
@@ -41,16 +55,22 @@ impl BetterSendMessage for BotType {
   {
     let mut message = self.send_message(chat_id, text);
 
-    if buttons.is_empty() {
+    let rows = rows
+      .into_iter()
+      .filter(|row| !row.is_empty())
+      .map(|row| {
+        row
+          .into_iter()
+          .map(|b| KeyboardButton::new(b.into()))
+          .collect::<Vec<KeyboardButton>>()
+      })
+      .collect::<Vec<Vec<KeyboardButton>>>();
+
+    if rows.is_empty() {
       message = message.reply_markup(ReplyMarkup::KeyboardRemove(KeyboardRemove::new()));
     } else {
-      let buttons = buttons
-        .into_iter()
-        .map(|b| KeyboardButton::new(b.into()))
-        .collect::<Vec<KeyboardButton>>();
-
       message = message.reply_markup(ReplyMarkup::Keyboard(
-        KeyboardMarkup::new(vec![buttons])
+        KeyboardMarkup::new(rows)
           .resize_keyboard()
           .one_time_keyboard(),
       ));
@@ -1,16 +1,22 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use teloxide::types::Message;
+use teloxide::{
+  types::{Me, Message},
+  utils::command::BotCommands as _,
+};
 
 use crate::{
-  database::queries,
-  handlers::send_message::BetterSendMessage as _,
-  types::{BotType, ConversationState, DbConn, DialogueWithState},
+  database::{import::types::ExportFormat, queries},
+  handlers::{command::Command, send_message::BetterSendMessage as _},
+  tasks::{export::send_bot_export, purge},
+  types::{BotType, ConversationState, DbConn, DialogueWithState, PendingDeletions},
+  util,
 };
 
 pub async fn verify_stop(
   db: Arc<DbConn>,
+  pending_deletions: PendingDeletions,
   bot: BotType,
   dialogue: DialogueWithState,
   msg: Message,
@@ -26,12 +32,99 @@ pub async fn verify_stop(
 
   let user_id = msg.from.as_ref().unwrap().id.to_string();
 
-  log::debug!("Wiping user {:?}", user_id);
+  // Give the user a last copy of their data, in case they regret this later.
+  // Nothing is wiped before the grace period, so a failed export is no reason
+  // to refuse the /stop.
+  let export = send_bot_export(
+    &db,
+    &bot,
+    msg.chat.id,
+    user_id.clone(),
+    ExportFormat::default(),
+    None,
+  )
+  .await;
+  if let Err(e) = export {
+    log::error!("Failed to export user {:?} before /stop: {:?}", user_id, e);
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "Your export could not be sent, use /restore and /export if you want a copy of your data",
+      )
+      .await?;
+  }
+
+  log::debug!("Scheduling user {:?} for deletion", user_id);
+
+  queries::soft_delete_user(&db, user_id.clone(), util::get_unix()).await?;
+  pending_deletions.insert(user_id.clone());
+  // Their jobs would work on data that is about to be wiped
+  queries::cancel_jobs(&db, user_id.clone(), None).await?;
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!(
+        "All your data has been hidden, and will be permanently wiped in <code>{}</code> days\n\
+        Use /restore before then if you want to keep it",
+        purge::grace_period() / (24 * 60 * 60 * 1000)
+      ),
+    )
+    .await?;
+
+  Ok(())
+}
+
+pub fn is_pending_deletion(pending_deletions: PendingDeletions, msg: Message) -> bool {
+  msg
+    .from
+    .as_ref()
+    .is_some_and(|user| pending_deletions.contains(&user.id.to_string()))
+}
+
+// Users that used /stop can only restore their data, anything else would mix
+// new data with the data that is about to be wiped
+pub async fn receive_while_deleted(
+  db: Arc<DbConn>,
+  pending_deletions: PendingDeletions,
+  bot: BotType,
+  me: Me,
+  dialogue: DialogueWithState,
+  msg: Message,
+) -> Result<()> {
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  let is_restore = msg
+    .text()
+    .map(|text| matches!(Command::parse(text, me.username()), Ok(Command::Restore)))
+    .unwrap_or(false);
+
+  if !is_restore {
+    let deleted_at = queries::get_deleted_at(&db, user_id).await?.unwrap_or(0);
+    let hours_left =
+      (deleted_at + purge::grace_period() - util::get_unix()).max(0) / (60 * 60 * 1000);
 
-  queries::wipe_user(&db, user_id.clone()).await?;
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!(
+          "Your data is scheduled to be wiped in <code>{}</code> hours\n\
+          Use /restore to get it back, or wait until it is wiped to start over",
+          hours_left,
+        ),
+      )
+      .await?;
+    return Ok(());
+  }
+
+  log::debug!("Restoring user {:?}", user_id);
+
+  queries::restore_user(&db, user_id.clone()).await?;
+  pending_deletions.remove(&user_id);
+  dialogue.update(ConversationState::ReceiveEntityId).await?;
 
   bot
-    .send_message_easy(msg.chat.id, "All your data has been wiped")
+    .send_message_easy(msg.chat.id, "All your data has been restored")
     .await?;
 
   Ok(())
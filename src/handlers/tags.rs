@@ -2,6 +2,7 @@ use anyhow::Result;
 use std::sync::Arc;
 use teloxide::prelude::*;
 use teloxide::types::FileMeta;
+use teloxide::utils::html::escape as html_escape;
 
 use crate::database::queries::{self, InsertEntity};
 use crate::database::EntityType;
@@ -130,6 +131,8 @@ pub async fn receive_entities_tags(
     )
     .await?;
 
+  let journal_id = queries::start_journal(&db, user_id.clone(), "Tagging a stickerpack").await?;
+
   let insert_entities: Vec<InsertEntity> = entities
     .iter()
     .map(|entity| InsertEntity {
@@ -144,6 +147,7 @@ pub async fn receive_entities_tags(
     insert_entities,
     entity_type.clone(),
     add_tags.clone(),
+    journal_id,
   )
   .await?;
 
@@ -152,6 +156,7 @@ pub async fn receive_entities_tags(
     user_id.clone(),
     entities.iter().map(|e| e.unique_id.clone()).collect(),
     remove_tags.clone(),
+    journal_id,
   )
   .await?;
 
@@ -210,6 +215,16 @@ pub async fn receive_entity_id(
     user_id
   );
 
+  // A quarantined entity works again with the file that was just sent
+  if queries::relink_entity(&db, entity.unique_id.to_owned(), entity.id.to_owned()).await? {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "This was in the quarantine because Telegram lost its file, it works again and all its tags are back",
+      )
+      .await?;
+  }
+
   queries::update_file_id(&db, entity.unique_id.to_owned(), entity.id.to_owned()).await?;
 
   let mut current_tags =
@@ -221,6 +236,9 @@ pub async fn receive_entity_id(
   let entity_usage =
     queries::get_entity_usage(&db, user_id.clone(), entity.unique_id.clone()).await?;
 
+  let history =
+    queries::get_entity_history(&db, user_id.clone(), entity.unique_id.clone(), 10).await?;
+
   if current_tags.len() > 0 {
     bot
       .send_message_easy(
@@ -230,7 +248,7 @@ pub async fn receive_entity_id(
                 You've used this <code>{}</code> times\n\
                 You've last used this <code>{}</code>\n\
                 You've added this sticker <code>{}</code>",
-          current_tags.join(", "),
+          html_escape(&current_tags.join(", ")),
           entity_usage.count,
           unix_to_humantime(entity_usage.last_used),
           unix_to_humantime(entity_usage.created_at),
@@ -239,6 +257,28 @@ pub async fn receive_entity_id(
       .await?;
   }
 
+  if !history.is_empty() {
+    let changes: Vec<String> = history
+      .iter()
+      .map(|change| {
+        format!(
+          "{} <b>{}</b> <i>({}, {})</i>",
+          if change.added { "+" } else { "-" },
+          html_escape(&change.tag_name),
+          html_escape(&change.source),
+          unix_to_humantime(change.created_at)
+        )
+      })
+      .collect();
+
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        format!("Recent tag changes:\n{}", changes.join("\n")),
+      )
+      .await?;
+  }
+
   bot
     .send_message_buttons(
       msg.chat.id,
@@ -287,13 +327,19 @@ pub async fn receive_entity_tags(
 
   log::debug!("Got tags: {:?} from {:?}", tags, user_id);
 
+  let journal_id = queries::start_journal(&db, user_id.clone(), "Tagging").await?;
+
   if tags[0] == "replace" || tags[0] == "clear" {
     log::debug!("Wiping tags");
-    queries::wipe_tags(&db, user_id.clone(), entity.unique_id.clone()).await?;
+    queries::wipe_tags(&db, user_id.clone(), entity.unique_id.clone(), journal_id).await?;
 
     if tags[0] == "clear" {
       bot
-        .send_message_easy(msg.chat.id, "Cleared all tags for this")
+        .send_message_easy(
+          msg.chat.id,
+          "Cleared all tags for this, use /undo if this was a mistake\n\
+          To forget it and its usage entirely, delete it in /untagged",
+        )
         .await?;
       dialogue.update(ConversationState::ReceiveEntityId).await?;
       return Ok(());
@@ -334,6 +380,7 @@ pub async fn receive_entity_tags(
     }],
     entity_type.clone(),
     add_tags,
+    journal_id,
   )
   .await?;
 
@@ -342,6 +389,7 @@ pub async fn receive_entity_tags(
     user_id.clone(),
     vec![entity.unique_id.clone()],
     remove_tags,
+    journal_id,
   )
   .await?;
 
@@ -389,3 +437,50 @@ pub async fn send_tags_usage(db: Arc<DbConn>, bot: BotType, msg: Message) -> Res
 
   Ok(())
 }
+
+pub async fn send_undo(db: &DbConn, bot: &BotType, msg: &Message, levels: String) -> Result<()> {
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  let levels = if levels.trim().is_empty() {
+    1
+  } else if let Ok(levels) = levels.trim().parse::<u32>() {
+    levels
+  } else {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "Usage: <code>/undo</code> or <code>/undo 3</code> to undo the last 3 changes",
+      )
+      .await?;
+    return Ok(());
+  };
+
+  let mut undone: Vec<String> = vec![];
+  for _ in 0..levels {
+    let Some(operation) = queries::undo_journal(db, user_id.clone()).await? else {
+      break;
+    };
+
+    undone.push(format!(
+      "- {} <i>({})</i>",
+      operation.action,
+      unix_to_humantime(operation.created_at)
+    ));
+  }
+
+  if undone.is_empty() {
+    bot
+      .send_message_easy(msg.chat.id, "There is nothing left to undo")
+      .await?;
+    return Ok(());
+  }
+
+  bot
+    .send_message_easy(
+      msg.chat.id,
+      format!("Undid the following changes:\n{}", undone.join("\n")),
+    )
+    .await?;
+
+  Ok(())
+}
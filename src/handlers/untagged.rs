@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use teloxide::{
+  prelude::*,
+  types::{InputFile, Message},
+};
+
+use crate::{
+  database::{
+    queries::{self, InsertEntity},
+    EntityType, UntaggedEntity,
+  },
+  handlers::send_message::BetterSendMessage as _,
+  types::{BotType, ConversationState, DbConn, DialogueWithState},
+  util::unix_to_humantime,
+};
+
+pub async fn send_next_untagged(
+  db: &DbConn,
+  bot: &BotType,
+  dialogue: &DialogueWithState,
+  msg: &Message,
+  skipped: i64,
+) -> Result<()> {
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+
+  let Some(entity) = queries::get_untagged_entity(db, user_id.clone(), skipped).await? else {
+    bot
+      .send_message_easy(msg.chat.id, "You have no more untagged entities to review")
+      .await?;
+    dialogue.update(ConversationState::ReceiveEntityId).await?;
+    return Ok(());
+  };
+
+  let remaining = queries::count_untagged_entities(db, user_id.clone()).await? - skipped;
+
+  log::debug!("Reviewing untagged entity {:?} for {:?}", entity, user_id);
+
+  let preview = match (&entity.file_id, &entity.entity_type) {
+    (Some(file_id), Some(entity_type)) => {
+      send_entity(bot, msg.chat.id, file_id.to_owned(), entity_type).await
+    }
+    _ => Err(anyhow::anyhow!("No file known for this entity")),
+  };
+
+  if let Err(e) = &preview {
+    log::debug!("Could not show untagged entity: {:?}", e);
+  }
+
+  bot
+    .send_message_buttons(
+      msg.chat.id,
+      format!(
+        "{}\
+        <code>{}</code> untagged entities left to review\n\
+        You've used this <code>{}</code> times\n\
+        You've last used this <code>{}</code>\n\
+        \n\
+        Which tags do you want to add to this?\n\
+        - Send <code>skip</code> to leave this untagged for now\n\
+        - Send <code>delete</code> to forget this and its usage entirely",
+        if preview.is_err() {
+          "<i>This can no longer be displayed</i>\n"
+        } else {
+          ""
+        },
+        remaining,
+        entity.count,
+        unix_to_humantime(entity.last_used),
+      ),
+      vec!["skip", "delete", "/cancel"],
+    )
+    .await?;
+
+  dialogue
+    .update(ConversationState::ReviewUntagged { entity, skipped })
+    .await?;
+
+  Ok(())
+}
+
+pub async fn receive_untagged_review(
+  db: Arc<DbConn>,
+  bot: BotType,
+  dialogue: DialogueWithState,
+  msg: Message,
+  (entity, skipped): (UntaggedEntity, i64),
+) -> Result<()> {
+  if msg.text().is_none() {
+    bot
+      .send_message_easy(
+        msg.chat.id,
+        "Please send me a space seperated list of tags, skip, delete or /cancel",
+      )
+      .await?;
+    return Ok(());
+  }
+
+  let user_id = msg.from.as_ref().unwrap().id.to_string();
+  let tags: Vec<String> = msg
+    .text()
+    .unwrap()
+    .to_lowercase()
+    .replace(",", " ")
+    .split(" ")
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect();
+
+  log::debug!("Got review answer: {:?} from {:?}", tags, user_id);
+
+  match tags.first().map(|t| t.as_str()) {
+    None => {
+      bot
+        .send_message_easy(msg.chat.id, "No tags provided")
+        .await?;
+      return Ok(());
+    }
+    Some("skip") => {
+      return send_next_untagged(&db, &bot, &dialogue, &msg, skipped + 1).await;
+    }
+    Some("delete") => {
+      let journal_id = queries::start_journal(&db, user_id.clone(), "Deleting").await?;
+      queries::delete_entity(&db, user_id.clone(), entity.entity_id.clone(), journal_id).await?;
+      bot
+        .send_message_easy(msg.chat.id, "Removed this and its usage statistics")
+        .await?;
+    }
+    Some(_) => {
+      let (Some(file_id), Some(entity_type)) = (entity.file_id, entity.entity_type) else {
+        bot
+          .send_message_easy(
+            msg.chat.id,
+            "This can no longer be tagged, please skip or delete it",
+          )
+          .await?;
+        return Ok(());
+      };
+
+      let add_tags: Vec<String> = tags
+        .into_iter()
+        .map(|tag| tag.replace("-", ""))
+        .filter(|tag| !tag.is_empty())
+        .collect();
+
+      let journal_id = queries::start_journal(&db, user_id.clone(), "Tagging").await?;
+      queries::insert_tags(
+        &db,
+        user_id.clone(),
+        vec![InsertEntity {
+          entity_id: entity.entity_id.clone(),
+          file_id,
+        }],
+        entity_type,
+        add_tags.clone(),
+        journal_id,
+      )
+      .await?;
+
+      bot
+        .send_message_easy(
+          msg.chat.id,
+          format!("Added tags: <b>{}</b>", add_tags.join(", ")),
+        )
+        .await?;
+    }
+  }
+
+  send_next_untagged(&db, &bot, &dialogue, &msg, skipped).await
+}
+
+async fn send_entity(
+  bot: &BotType,
+  chat_id: ChatId,
+  file_id: String,
+  entity_type: &EntityType,
+) -> Result<()> {
+  let file = InputFile::file_id(file_id);
+
+  match entity_type {
+    EntityType::Sticker => {
+      bot.send_sticker(chat_id, file).await?;
+    }
+    EntityType::Animation => {
+      bot.send_animation(chat_id, file).await?;
+    }
+    EntityType::Photo => {
+      bot.send_photo(chat_id, file).await?;
+    }
+    EntityType::Video => {
+      bot.send_video(chat_id, file).await?;
+    }
+  }
+
+  Ok(())
+}
@@ -0,0 +1,6 @@
+// Everything is shared between the bot and the offline export tool in src/bin
+pub mod database;
+pub mod handlers;
+pub mod tasks;
+pub mod types;
+pub mod util;
@@ -1,16 +1,11 @@
 use anyhow::Result;
 use dotenv::dotenv;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
-use std::sync::Arc;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use std::{sync::Arc, time::Duration};
+use sticker_bot::{handlers, tasks, types::*, util};
 use teloxide::dispatching::dialogue::InMemStorage;
 use teloxide::prelude::*;
 use teloxide::utils::command::BotCommands;
-use types::*;
-
-mod database;
-mod handlers;
-mod types;
-mod util;
 
 use handlers::inline;
 
@@ -23,7 +18,17 @@ async fn main() -> Result<()> {
 
   let db = get_db().await?;
 
-  let bot = Bot::from_env().parse_mode(teloxide::types::ParseMode::Html);
+  tasks::maintenance::run_on_startup(&db).await?;
+
+  let pending_deletions = PendingDeletions::load(&db).await?;
+  let maintenance_mode = MaintenanceMode::load(&db).await?;
+
+  let mut bot = Bot::from_env();
+  // A self hosted Bot API server, or a stand-in for testing
+  if let Ok(api_url) = std::env::var("BOT_API_URL") {
+    bot = bot.set_api_url(url::Url::parse(&api_url)?);
+  }
+  let bot = bot.parse_mode(teloxide::types::ParseMode::Html);
 
   let command_handler =
     dptree::filter(|msg: Message| msg.text().map(|t| t.starts_with("/")).unwrap_or(false))
@@ -32,9 +37,16 @@ async fn main() -> Result<()> {
   let message_recieve_qsimport_tree =
     dptree::case![ConversationState::ReceiveQSImport].endpoint(handlers::import::receive_qs_import);
 
+  let message_recieve_tdimport_tree =
+    dptree::case![ConversationState::ReceiveTDImport].endpoint(handlers::import::receive_td_import);
+
   let message_recieve_botimport_tree = dptree::case![ConversationState::ReceiveBotImport]
     .endpoint(handlers::import::receive_bot_import);
 
+  let message_recieve_botimport_mode_tree =
+    dptree::case![ConversationState::ReceiveBotImportMode { file_ids }]
+      .endpoint(handlers::import::receive_bot_import_mode);
+
   let message_verify_stop_tree =
     dptree::case![ConversationState::VerifyStop].endpoint(handlers::stop::verify_stop);
 
@@ -54,32 +66,77 @@ async fn main() -> Result<()> {
   }]
   .endpoint(handlers::tags::receive_entity_tags);
 
+  let message_verify_retag_tree = dptree::case![ConversationState::VerifyRetag {
+    query,
+    add_tags,
+    remove_tags
+  }]
+  .endpoint(handlers::retag::verify_retag);
+
+  let message_review_untagged_tree =
+    dptree::case![ConversationState::ReviewUntagged { entity, skipped }]
+      .endpoint(handlers::untagged::receive_untagged_review);
+
+  let message_verify_broadcast_tree = dptree::case![ConversationState::VerifyBroadcast { text }]
+    .endpoint(handlers::admin::verify_broadcast);
+
+  let message_pending_deletion_tree = dptree::filter(handlers::stop::is_pending_deletion)
+    .endpoint(handlers::stop::receive_while_deleted);
+
+  let message_maintenance_tree = dptree::filter(handlers::admin::is_under_maintenance)
+    .endpoint(handlers::admin::receive_while_under_maintenance);
+
   let message_tree = Update::filter_message()
     .enter_dialogue::<Message, InMemStorage<ConversationState>, ConversationState>()
+    .branch(message_maintenance_tree)
+    .branch(message_pending_deletion_tree)
     .branch(command_handler)
     .branch(message_recieve_qsimport_tree)
+    .branch(message_recieve_tdimport_tree)
     .branch(message_recieve_botimport_tree)
+    .branch(message_recieve_botimport_mode_tree)
     .branch(message_verify_stop_tree)
     .branch(message_receive_entities_ids_tree)
     .branch(message_receive_entities_tags_tree)
     .branch(message_receive_entity_id_tree)
-    .branch(message_receive_entity_tags_tree);
-
-  let inline_tree = Update::filter_inline_query().endpoint({
-    let db = db.clone();
-    move |bot, query| inline::handler_inline_query(db.clone(), bot, query)
-  });
-
-  let inline_result_tree = Update::filter_chosen_inline_result().endpoint({
-    let db = db.clone();
-    move |query| inline::handle_inline_choice(db.clone(), query)
-  });
+    .branch(message_receive_entity_tags_tree)
+    .branch(message_verify_retag_tree)
+    .branch(message_review_untagged_tree)
+    .branch(message_verify_broadcast_tree);
+
+  let inline_maintenance_tree = dptree::filter(handlers::admin::is_inline_query_under_maintenance)
+    .endpoint(handlers::admin::receive_inline_query_while_under_maintenance);
+
+  let inline_tree = Update::filter_inline_query()
+    .branch(inline_maintenance_tree)
+    .endpoint({
+      let db = db.clone();
+      move |bot, query| inline::handler_inline_query(db.clone(), bot, query)
+    });
+
+  // Only stats are updated, nobody can search in maintenance mode anyway
+  let inline_result_maintenance_tree =
+    dptree::filter(handlers::admin::is_inline_choice_under_maintenance)
+      .endpoint(|| async { Ok(()) });
+
+  let inline_result_tree = Update::filter_chosen_inline_result()
+    .branch(inline_result_maintenance_tree)
+    .endpoint({
+      let db = db.clone();
+      move |query| inline::handle_inline_choice(db.clone(), query)
+    });
 
   let tree = dptree::entry()
     .branch(message_tree)
     .branch(inline_tree)
     .branch(inline_result_tree);
 
+  tokio::spawn(tasks::purge::run(db.clone(), pending_deletions.clone()));
+  tokio::spawn(tasks::jobs::run(db.clone(), bot.clone()));
+  tokio::spawn(tasks::health::run(db.clone(), bot.clone()));
+  tokio::spawn(tasks::maintenance::run(db.clone()));
+  tokio::spawn(tasks::backup::run(db.clone()));
+
   log::debug!("Sending commands");
 
   bot
@@ -89,7 +146,12 @@ async fn main() -> Result<()> {
   log::debug!("Starting dispatcher");
 
   Dispatcher::builder(bot, tree)
-    .dependencies(dptree::deps![InMemStorage::<ConversationState>::new(), db])
+    .dependencies(dptree::deps![
+      InMemStorage::<ConversationState>::new(),
+      db,
+      pending_deletions,
+      maintenance_mode
+    ])
     .enable_ctrlc_handler()
     .build()
     .dispatch()
@@ -107,24 +169,39 @@ async fn get_db() -> Result<DbType> {
 
   log::debug!("Opening/creating and migrating database");
 
-  touch(database_location.clone());
+  // WAL lets inline queries read while an import is writing, and waiting a bit
+  // for a lock beats failing with "database is locked"
+  let options = SqliteConnectOptions::new()
+    .filename(database_location)
+    .create_if_missing(true)
+    .foreign_keys(true)
+    .journal_mode(util::env_or(
+      "DATABASE_JOURNAL_MODE",
+      SqliteJournalMode::Wal,
+    ))
+    .synchronous(util::env_or(
+      "DATABASE_SYNCHRONOUS",
+      SqliteSynchronous::Normal,
+    ))
+    .busy_timeout(Duration::from_millis(util::env_or(
+      "DATABASE_BUSY_TIMEOUT_MS",
+      10_000,
+    )))
+    .statement_cache_capacity(util::env_or("DATABASE_STATEMENT_CACHE", 100))
+    // Keeps the query planner statistics up to date for long lived connections
+    .optimize_on_close(true, None);
+
   let db: DbType = Arc::new(
     SqlitePoolOptions::new()
-      .connect_with(SqliteConnectOptions::new().filename(database_location))
-      .await
-      .unwrap(),
+      .min_connections(util::env_or("DATABASE_MIN_CONNECTIONS", 1))
+      .max_connections(util::env_or("DATABASE_MAX_CONNECTIONS", 8))
+      .acquire_timeout(Duration::from_secs(30))
+      .connect_with(options)
+      .await?,
   );
+  tasks::maintenance::check_before_migrations(&db).await?;
   sqlx::migrate!().run(db.as_ref()).await?;
   log::debug!("Successfully opened database");
 
   return Ok(db);
 }
-
-fn touch(path: String) {
-  std::fs::OpenOptions::new()
-    .read(true)
-    .create(true)
-    .write(true)
-    .open(path)
-    .unwrap();
-}
@@ -0,0 +1,240 @@
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+
+use crate::{database::queries, types::DbType, util};
+
+const FILE_PREFIX: &str = "stimkerbot-";
+const FILE_EXTENSION: &str = ".db";
+const TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+pub struct Backup {
+  pub path: PathBuf,
+  pub created_at: DateTime<Utc>,
+  pub size: u64,
+}
+
+// Where backups are written, None if backups are disabled
+pub fn backup_directory() -> Option<PathBuf> {
+  std::env::var("BACKUP_DIRECTORY")
+    .ok()
+    .filter(|directory| !directory.is_empty())
+    .map(PathBuf::from)
+}
+
+// Snapshots the database every BACKUP_INTERVAL_HOURS, and removes the backups
+// that are no longer kept
+pub async fn run(db: DbType) {
+  let Some(directory) = backup_directory() else {
+    log::info!("Backups are disabled, set BACKUP_DIRECTORY to enable them");
+    return;
+  };
+
+  let hours = util::env_or("BACKUP_INTERVAL_HOURS", 24);
+  if hours == 0 {
+    log::info!("Scheduled backups are disabled");
+    return;
+  }
+
+  let mut interval = tokio::time::interval(Duration::from_secs(hours * 60 * 60));
+  loop {
+    interval.tick().await;
+
+    // Right after a restart there could already be a recent one
+    match list_backups(&directory) {
+      Ok(backups)
+        if backups.first().is_some_and(|backup| {
+          Utc::now() - backup.created_at < chrono::Duration::hours(hours as i64)
+        }) =>
+      {
+        continue
+      }
+      Ok(_) => {}
+      Err(e) => log::error!("Failed to list the backups: {:?}", e),
+    }
+
+    if let Err(e) = create_backup(&db, &directory).await {
+      log::error!("Failed to back up the database: {:?}", e);
+    }
+  }
+}
+
+// Writes a consistent copy of the live database, and applies the retention
+pub async fn create_backup(db: &DbType, directory: &Path) -> Result<Backup> {
+  std::fs::create_dir_all(directory)
+    .with_context(|| format!("Could not create {}", directory.display()))?;
+
+  let created_at = Utc::now();
+  let file_name = format!(
+    "{}{}{}",
+    FILE_PREFIX,
+    created_at.format(TIME_FORMAT),
+    FILE_EXTENSION
+  );
+  let path = directory.join(&file_name);
+  // VACUUM INTO fails if the file exists, and an unfinished backup should never
+  // look like a finished one
+  let partial_path = directory.join(format!("{}.partial", file_name));
+  if partial_path.exists() {
+    std::fs::remove_file(&partial_path)?;
+  }
+
+  log::info!("Backing up the database to {}", path.display());
+  queries::backup_into(db, &partial_path.to_string_lossy()).await?;
+  std::fs::rename(&partial_path, &path)?;
+
+  let size = std::fs::metadata(&path)?.len();
+
+  match rotate_backups(directory) {
+    Ok(0) => {}
+    Ok(removed) => log::info!("Removed {} old backups", removed),
+    Err(e) => log::error!("Failed to remove old backups: {:?}", e),
+  }
+
+  Ok(Backup {
+    path,
+    created_at,
+    size,
+  })
+}
+
+// The backups in the directory, newest first
+pub fn list_backups(directory: &Path) -> Result<Vec<Backup>> {
+  if !directory.exists() {
+    return Ok(Vec::new());
+  }
+
+  let mut backups = Vec::new();
+  for entry in std::fs::read_dir(directory)? {
+    let entry = entry?;
+    let file_name = entry.file_name().to_string_lossy().to_string();
+
+    // Anything else in the directory is left alone
+    let Some(time) = file_name
+      .strip_prefix(FILE_PREFIX)
+      .and_then(|name| name.strip_suffix(FILE_EXTENSION))
+    else {
+      continue;
+    };
+    let Ok(created_at) = NaiveDateTime::parse_from_str(time, TIME_FORMAT) else {
+      continue;
+    };
+
+    backups.push(Backup {
+      path: entry.path(),
+      created_at: created_at.and_utc(),
+      size: entry.metadata()?.len(),
+    });
+  }
+
+  backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+
+  Ok(backups)
+}
+
+// Keeps the newest backup of each of the last BACKUP_KEEP_DAILY days, and of
+// each of the last BACKUP_KEEP_WEEKLY weeks. Returns how many were removed.
+fn rotate_backups(directory: &Path) -> Result<usize> {
+  let keep_daily = util::env_or("BACKUP_KEEP_DAILY", 7);
+  let keep_weekly = util::env_or("BACKUP_KEEP_WEEKLY", 4);
+
+  let backups = list_backups(directory)?;
+  let keep = backups_to_keep(&backups, keep_daily, keep_weekly);
+
+  let mut removed = 0;
+  for (index, backup) in backups.iter().enumerate() {
+    if !keep.contains(&index) {
+      std::fs::remove_file(&backup.path)?;
+      removed += 1;
+    }
+  }
+
+  Ok(removed)
+}
+
+// The indices of the backups to keep, from a list that is sorted newest first
+fn backups_to_keep(backups: &[Backup], keep_daily: usize, keep_weekly: usize) -> HashSet<usize> {
+  let mut keep = HashSet::new();
+  let mut days = Vec::new();
+  let mut weeks = Vec::new();
+
+  for (index, backup) in backups.iter().enumerate() {
+    let day = backup.created_at.date_naive();
+    if !days.contains(&day) && days.len() < keep_daily {
+      days.push(day);
+      keep.insert(index);
+    }
+
+    let week = backup.created_at.iso_week();
+    if !weeks.contains(&week) && weeks.len() < keep_weekly {
+      weeks.push(week);
+      keep.insert(index);
+    }
+  }
+
+  // Never remove the backup that was just made
+  if !backups.is_empty() {
+    keep.insert(0);
+  }
+
+  keep
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+
+  use super::*;
+
+  fn backup(year: i32, month: u32, day: u32, hour: u32) -> Backup {
+    Backup {
+      path: PathBuf::new(),
+      created_at: Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap(),
+      size: 0,
+    }
+  }
+
+  fn kept(backups: &[Backup], keep_daily: usize, keep_weekly: usize) -> Vec<usize> {
+    let mut kept: Vec<usize> = backups_to_keep(backups, keep_daily, keep_weekly)
+      .into_iter()
+      .collect();
+    kept.sort();
+    kept
+  }
+
+  #[test]
+  fn daily() {
+    // Two a day, newest first
+    let backups: Vec<Backup> = (1..=5)
+      .rev()
+      .flat_map(|day| [backup(2024, 5, day, 18), backup(2024, 5, day, 6)])
+      .collect();
+
+    // The newest of each of the last 3 days
+    assert_eq!(kept(&backups, 3, 0), [0, 2, 4]);
+  }
+
+  #[test]
+  fn weekly() {
+    // Wednesday 2024-05-01 until Tuesday 2024-05-21, one a day
+    let backups: Vec<Backup> = (1..=21).rev().map(|day| backup(2024, 5, day, 12)).collect();
+
+    // Week 21 starts on the 20th, week 20 on the 13th, week 19 on the 6th
+    assert_eq!(kept(&backups, 1, 3), [0, 2, 9]);
+    // Daily and weekly overlap
+    assert_eq!(kept(&backups, 3, 2), [0, 1, 2]);
+  }
+
+  #[test]
+  fn newest_is_always_kept() {
+    let backups = vec![backup(2024, 5, 2, 12), backup(2024, 5, 1, 12)];
+
+    assert_eq!(kept(&backups, 0, 0), [0]);
+    assert!(backups_to_keep(&[], 7, 4).is_empty());
+  }
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use teloxide::{
+  requests::Requester, types::ChatId, utils::html::escape as html_escape, ApiError, RequestError,
+};
+
+use crate::{database::queries, util};
+
+use super::jobs::{JobCancelled, JobHandle};
+
+// How many users are loaded at once
+const BATCH_SIZE: i64 = 100;
+
+// How often the progress message is updated
+const PROGRESS_INTERVAL: i64 = 50;
+
+// How often a paused broadcast checks if maintenance mode is still on
+const MAINTENANCE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Only the first failures are reported, the checkpoint is saved after every message
+const MAX_FAILURES: usize = 20;
+
+// How far the broadcast got, users are messaged in order so everything up to
+// last_user_id is done
+#[derive(Serialize, Deserialize, Default)]
+struct BroadcastCheckpoint {
+  last_user_id: String,
+  sent: i64,
+  inactive: i64,
+  failed: i64,
+  // The first users the message could not be sent to, and why
+  failures: Vec<(String, String)>,
+}
+
+impl BroadcastCheckpoint {
+  fn add_failure(&mut self, user_id: &str, reason: String) {
+    self.failed += 1;
+    if self.failures.len() < MAX_FAILURES {
+      self.failures.push((user_id.to_string(), reason));
+    }
+  }
+}
+
+// Sends the message to every user, rate limited to BROADCAST_MESSAGES_PER_SECOND.
+// Continues where the job left off before a restart, and reports back to the
+// admin that started it.
+pub async fn run(job: &JobHandle, text: String) -> Result<()> {
+  let db = job.db.as_ref();
+
+  let mut checkpoint: BroadcastCheckpoint = job.checkpoint()?;
+  let done = checkpoint.sent + checkpoint.inactive + checkpoint.failed;
+  let total =
+    queries::count_broadcast_recipients(db, checkpoint.last_user_id.clone()).await? + done;
+
+  // Telegram allows about 30 messages per second, a 429 is waited out as well
+  let per_second = util::env_or("BROADCAST_MESSAGES_PER_SECOND", 20).clamp(1, 30);
+  let mut interval = tokio::time::interval(Duration::from_millis(1000 / per_second));
+  let mut cancelled = false;
+
+  'batches: loop {
+    let users =
+      queries::get_broadcast_recipients(db, checkpoint.last_user_id.clone(), BATCH_SIZE).await?;
+    if users.is_empty() {
+      break;
+    }
+
+    for user_id in users {
+      if job.is_cancelled().await? || !wait_for_maintenance(job).await? {
+        cancelled = true;
+        break 'batches;
+      }
+
+      let done = checkpoint.sent + checkpoint.inactive + checkpoint.failed;
+      if done % PROGRESS_INTERVAL == 0 {
+        job
+          .progress(format!("Sending the broadcast, {} of {} done", done, total))
+          .await;
+      }
+
+      interval.tick().await;
+      send_to_user(job, &mut checkpoint, &user_id, &text).await?;
+
+      checkpoint.last_user_id = user_id;
+      // Saved after every message, so a restart sends it twice to one user at most
+      job.save_checkpoint(&checkpoint).await?;
+    }
+  }
+
+  job
+    .bot
+    .send_message(job.chat_id(), report_message(&checkpoint, cancelled))
+    .await?;
+
+  if cancelled {
+    return Err(JobCancelled.into());
+  }
+
+  Ok(())
+}
+
+// Holds the broadcast while maintenance mode is on, false when it was cancelled
+// in the meantime
+async fn wait_for_maintenance(job: &JobHandle) -> Result<bool> {
+  let mut paused = false;
+  while queries::is_maintenance_mode(&job.db).await? {
+    if !paused {
+      job.progress("Paused until maintenance mode is off").await;
+      paused = true;
+    }
+
+    if job.is_cancelled().await? {
+      return Ok(false);
+    }
+    tokio::time::sleep(MAINTENANCE_POLL_INTERVAL).await;
+  }
+
+  Ok(true)
+}
+
+async fn send_to_user(
+  job: &JobHandle,
+  checkpoint: &mut BroadcastCheckpoint,
+  user_id: &str,
+  text: &str,
+) -> Result<()> {
+  let Ok(chat_id) = user_id.parse::<i64>() else {
+    checkpoint.add_failure(user_id, "Not a user id".to_string());
+    return Ok(());
+  };
+
+  match util::retry_after(|| job.bot.send_message(ChatId(chat_id), text)).await {
+    Ok(_) => checkpoint.sent += 1,
+    // The user can't be reached anymore until they come back with /start
+    Err(RequestError::Api(
+      ApiError::BotBlocked
+      | ApiError::UserDeactivated
+      | ApiError::ChatNotFound
+      | ApiError::CantInitiateConversation,
+    )) => {
+      log::debug!("User {:?} can not be reached, marking inactive", user_id);
+      queries::set_user_inactive(&job.db, user_id.to_string(), util::get_unix()).await?;
+      checkpoint.inactive += 1;
+    }
+    Err(e) => {
+      log::warn!("Failed to send the broadcast to {:?}: {:?}", user_id, e);
+      checkpoint.add_failure(user_id, e.to_string());
+    }
+  }
+
+  Ok(())
+}
+
+fn report_message(checkpoint: &BroadcastCheckpoint, cancelled: bool) -> String {
+  let mut message = format!(
+    "<b>{}</b>\n\
+    Delivered: <code>{}</code>\n\
+    Blocked the bot or gone: <code>{}</code>\n\
+    Failed: <code>{}</code>",
+    if cancelled {
+      "Broadcast cancelled"
+    } else {
+      "Broadcast finished"
+    },
+    checkpoint.sent,
+    checkpoint.inactive,
+    checkpoint.failed
+  );
+
+  for (user_id, reason) in &checkpoint.failures {
+    message += &format!(
+      "\n- <code>{}</code>: {}",
+      html_escape(user_id),
+      html_escape(reason)
+    );
+  }
+  if checkpoint.failed > checkpoint.failures.len() as i64 {
+    message += &format!(
+      "\nand {} more",
+      checkpoint.failed - checkpoint.failures.len() as i64
+    );
+  }
+
+  message
+}
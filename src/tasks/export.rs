@@ -0,0 +1,128 @@
+use anyhow::Result;
+use teloxide::{
+  requests::Requester,
+  types::{ChatId, InputFile},
+};
+
+use crate::{
+  database::import::{self, types::ExportFormat},
+  types::{BotType, DbConn},
+  util::unix_to_humantime,
+};
+
+use super::jobs::JobHandle;
+
+// Runs /export as a job
+pub async fn run(job: &JobHandle, format: ExportFormat, since: Option<i64>) -> Result<()> {
+  if let Some(since) = since {
+    job
+      .progress(format!(
+        "Exporting the changes since {}...",
+        unix_to_humantime(since)
+      ))
+      .await;
+  }
+
+  send_bot_export(
+    &job.db,
+    &job.bot,
+    job.chat_id(),
+    job.user_id(),
+    format,
+    since,
+  )
+  .await
+}
+
+// Runs /exportuser as a job, the export is sent to the admin
+pub async fn run_user(job: &JobHandle, user_id: String, format: ExportFormat) -> Result<()> {
+  send_bot_export(&job.db, &job.bot, job.chat_id(), user_id, format, None).await
+}
+
+// Sends an export right away, for when it has to be done before anything else
+pub async fn send_bot_export(
+  db: &DbConn,
+  bot: &BotType,
+  chat_id: ChatId,
+  user_id: String,
+  format: ExportFormat,
+  since: Option<i64>,
+) -> Result<()> {
+  let mut part_count = 0;
+  import::export(
+    db,
+    user_id,
+    format,
+    since,
+    import::EXPORT_PART_SIZE,
+    |data, file_name| {
+      part_count += 1;
+      async move {
+        bot
+          .send_document(chat_id, InputFile::memory(data).file_name(file_name))
+          .await?;
+        Ok(())
+      }
+    },
+  )
+  .await?;
+
+  if part_count > 1 {
+    bot
+      .send_message(
+        chat_id,
+        format!(
+          "Your export was split into <code>{}</code> parts, send all of them to /import to import it again",
+          part_count
+        ),
+      )
+      .await?;
+  }
+
+  Ok(())
+}
+
+// Exports with the media files, to move to a bot with a different token
+pub async fn run_archive(job: &JobHandle) -> Result<()> {
+  let bot = &job.bot;
+  let chat_id = job.chat_id();
+
+  let mut part_count = 0;
+  let missing_media = import::export_archive(
+    &job.db,
+    bot,
+    job.user_id(),
+    job,
+    import::EXPORT_PART_SIZE,
+    |data, file_name| {
+      part_count += 1;
+      async move {
+        bot
+          .send_document(chat_id, InputFile::memory(data).file_name(file_name))
+          .await?;
+        Ok(())
+      }
+    },
+  )
+  .await?;
+
+  let mut message = if part_count > 1 {
+    format!(
+      "Your archive was split into <code>{}</code> parts, send all of them to /import in the new bot",
+      part_count
+    )
+  } else {
+    "Send this archive to /import in the new bot".to_string()
+  };
+
+  if !missing_media.is_empty() {
+    message += &format!(
+      "\n<code>{}</code> files could not be downloaded, they only work in this bot",
+      missing_media.len()
+    );
+  }
+
+  bot.send_message(chat_id, message).await?;
+
+  Ok(())
+}
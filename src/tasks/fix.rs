@@ -0,0 +1,74 @@
+use anyhow::Result;
+use teloxide::{requests::Requester, utils::html::escape as html_escape};
+
+use crate::{
+  database::{import, queries},
+  util,
+};
+
+use super::jobs::{JobCancelled, JobHandle};
+
+// Runs /fixentities as a job, and reports back what was quarantined, also when
+// it was cancelled halfway
+pub async fn run(job: &JobHandle) -> Result<()> {
+  let report = import::fix(&job.db, &job.bot, job.user_id(), job).await?;
+
+  if !report.cancelled {
+    queries::set_last_fix_time(&job.db, job.user_id(), util::get_unix()).await?;
+  }
+
+  job
+    .bot
+    .send_message(job.chat_id(), report_message(&report))
+    .await?;
+
+  if report.cancelled {
+    return Err(JobCancelled.into());
+  }
+
+  Ok(())
+}
+
+fn report_message(report: &import::FixReport) -> String {
+  let mut message = if report.cancelled {
+    format!(
+      "Stopped fixing your entities, checked {} files and fixed {} entities",
+      report.checked, report.fixed
+    )
+  } else {
+    format!("Fixed {} entities!", report.fixed)
+  };
+
+  for (entity_id, diagnostic) in report.diagnostics.iter().take(20) {
+    message += &format!(
+      "\n- <code>{}</code>: {}",
+      html_escape(entity_id),
+      html_escape(diagnostic)
+    );
+  }
+  if report.diagnostics.len() > 20 {
+    message += &format!("\nand {} more", report.diagnostics.len() - 20);
+  }
+  if report.fixed > 0 {
+    message += "\nThese are hidden until you send them to me again, see /quarantine";
+  }
+
+  if !report.errors.is_empty() {
+    message += &format!(
+      "\n\nCould not check {} files, they are left as they are:",
+      report.errors.len()
+    );
+    for (entity_id, error) in report.errors.iter().take(5) {
+      message += &format!(
+        "\n- <code>{}</code>: {}",
+        html_escape(entity_id),
+        html_escape(error)
+      );
+    }
+    if report.errors.len() > 5 {
+      message += &format!("\nand {} more", report.errors.len() - 5);
+    }
+  }
+
+  message
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{
+  database::{
+    import,
+    queries::{self, FileStatus},
+  },
+  types::{BotType, DbType},
+  util,
+};
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+// Checks the files of all users with Telegram, one at a time, so stickers of
+// deleted packs are quarantined before anyone tries to send them
+pub async fn run(db: DbType, bot: BotType) {
+  let seconds = util::env_or("HEALTH_CHECK_INTERVAL_SECONDS", 10);
+  if seconds == 0 {
+    log::info!("The file health check is disabled");
+    return;
+  }
+
+  let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+
+  loop {
+    interval.tick().await;
+
+    // Nothing is checked or quarantined while the admins work on the database
+    match queries::is_maintenance_mode(&db).await {
+      Ok(false) => {}
+      Ok(true) => continue,
+      Err(e) => {
+        log::error!("Failed to check for maintenance mode: {:?}", e);
+        continue;
+      }
+    }
+
+    if let Err(e) = check_next_file(&db, &bot).await {
+      log::error!("Failed to check a file: {:?}", e);
+    }
+  }
+}
+
+async fn check_next_file(db: &DbType, bot: &BotType) -> Result<()> {
+  let recheck_after = util::env_or("HEALTH_CHECK_RECHECK_DAYS", 7) * DAY;
+
+  let Some((entity_id, file_id)) =
+    queries::get_file_to_check(db, util::get_unix() - recheck_after).await?
+  else {
+    return Ok(());
+  };
+
+  let problem = match import::check_file(bot, &file_id).await {
+    Ok(problem) => problem,
+    Err(e) => {
+      // Otherwise the same file would be picked again right away, forever
+      queries::set_file_checked(db, entity_id, FileStatus::Error).await?;
+      return Err(e);
+    }
+  };
+  let status = match problem {
+    Some(_) => FileStatus::Broken,
+    None => FileStatus::Ok,
+  };
+  queries::set_file_checked(db, entity_id.clone(), status).await?;
+
+  match problem {
+    Some(diagnostic) => {
+      log::info!("Quarantining file {:?}: {}", file_id, diagnostic);
+      queries::quarantine_entity(db, entity_id, diagnostic).await?;
+    }
+    // Files can come back, e.g. after Telegram had problems
+    None => {
+      if queries::relink_entity(db, entity_id.clone(), file_id).await? {
+        log::info!("File of {:?} works again", entity_id);
+      }
+    }
+  }
+
+  Ok(())
+}
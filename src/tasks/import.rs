@@ -0,0 +1,222 @@
+use anyhow::{Context, Result};
+use teloxide::{
+  net::Download,
+  requests::Requester,
+  types::{ChatId, InputFile},
+  utils::html::escape as html_escape,
+};
+
+use crate::{
+  database::{
+    import::{
+      self,
+      types::{ImportMode, ImportReport},
+    },
+    queries,
+  },
+  types::BotType,
+};
+
+use super::jobs::JobHandle;
+
+// Runs /import as a job, all parts of the export were sent before it was queued
+pub async fn run(
+  job: &JobHandle,
+  file_ids: Vec<String>,
+  mode: ImportMode,
+  dry_run: bool,
+) -> Result<()> {
+  let mut report = ImportReport::default();
+  let mut exports = Vec::new();
+
+  // Every file is read right after it is downloaded, so only one of them is in
+  // memory at a time
+  for (index, file_id) in file_ids.iter().enumerate() {
+    job.check_cancelled().await?;
+    if file_ids.len() > 1 {
+      job
+        .progress(format!("Reading file {} of {}", index + 1, file_ids.len()))
+        .await;
+    }
+
+    let file = download_file(&job.bot, file_id).await?;
+
+    // Archives come with their media, which has to be uploaded again first
+    let export = if import::is_archive(&file) {
+      import::upload_archive_media(
+        &job.db,
+        &job.bot,
+        job.chat_id(),
+        job,
+        &file,
+        dry_run,
+        &mut report,
+      )
+      .await
+    } else {
+      import::parse_file(&file, &mut report)
+    }
+    .context("Failed to read your export")?;
+
+    exports.push(export);
+  }
+
+  job.check_cancelled().await?;
+
+  let journal_id = queries::start_journal(&job.db, job.user_id(), "Importing").await?;
+
+  let report = import::import(
+    &job.db,
+    job.user_id(),
+    exports,
+    mode,
+    dry_run,
+    journal_id,
+    report,
+  )
+  .await
+  .context("Failed to import your entities")?;
+
+  send_import_report(&job.bot, job.chat_id(), &report, dry_run).await
+}
+
+async fn send_import_report(
+  bot: &BotType,
+  chat_id: ChatId,
+  report: &ImportReport,
+  dry_run: bool,
+) -> Result<()> {
+  let mut message = if dry_run {
+    "<b>Dry run, nothing was changed</b>\n".to_string()
+  } else {
+    "<b>Imported your entities!</b>\n".to_string()
+  };
+
+  message += &format!(
+    "New entities: <code>{}</code>\n\
+    Already in your library: <code>{}</code>\n",
+    report.new_entities, report.existing_entities
+  );
+
+  if report.removed_entities > 0 {
+    message += &format!(
+      "Removed entities: <code>{}</code>\n",
+      report.removed_entities
+    );
+  }
+
+  if report.remapped_entities > 0 {
+    message += &format!(
+      "Changed by Telegram when uploading: <code>{}</code>\n",
+      report.remapped_entities
+    );
+  }
+
+  message += &format!(
+    "New tags created: <code>{}</code>\n\
+    Tags added: <code>{}</code>\n\
+    Tags removed: <code>{}</code>\n\
+    Skipped items: <code>{}</code>",
+    report.tags_created,
+    report.tags_added,
+    report.tags_removed,
+    report.skipped.len()
+  );
+
+  for skipped in report.skipped.iter().take(20) {
+    message += &format!(
+      "\n- <code>{}</code>: {}",
+      html_escape(&skipped.item),
+      html_escape(&skipped.reason)
+    );
+  }
+
+  if !report.warnings.is_empty() {
+    message += &format!(
+      "\nImported with warnings: <code>{}</code>",
+      report.warnings.len()
+    );
+  }
+
+  for warning in report.warnings.iter().take(5) {
+    message += &format!(
+      "\n- <code>{}</code>: {}",
+      html_escape(&warning.item),
+      html_escape(&warning.reason)
+    );
+  }
+
+  bot.send_message(chat_id, message).await?;
+
+  // Long lists of skipped items are easier to read as a file
+  if report.skipped.len() > 20 {
+    let skipped_list = report
+      .skipped
+      .iter()
+      .map(|skipped| format!("{}: {}", skipped.item, skipped.reason))
+      .collect::<Vec<String>>()
+      .join("\n");
+
+    bot
+      .send_document(
+        chat_id,
+        InputFile::memory(skipped_list.into_bytes()).file_name("skipped.txt"),
+      )
+      .await?;
+  }
+
+  Ok(())
+}
+
+// Runs /qsimport as a job
+pub async fn run_qs(job: &JobHandle, file_id: String) -> Result<()> {
+  let file_data = download_file(&job.bot, &file_id).await?;
+
+  let journal_id =
+    queries::start_journal(&job.db, job.user_id(), "Importing from QuickStickBot").await?;
+
+  let report = import::quickstickbot_import(&job.db, job.user_id(), file_data, journal_id)
+    .await
+    .context("Failed to import your entities")?;
+
+  send_import_report(&job.bot, job.chat_id(), &report, false).await
+}
+
+// Runs /tdimport as a job
+pub async fn run_td(job: &JobHandle, file_id: String) -> Result<()> {
+  let file_data = download_file(&job.bot, &file_id).await?;
+
+  let journal_id =
+    queries::start_journal(&job.db, job.user_id(), "Importing from Telegram Desktop").await?;
+
+  let report = import::telegram_desktop_import(
+    &job.db,
+    &job.bot,
+    job.user_id(),
+    job.chat_id(),
+    job,
+    file_data,
+    journal_id,
+  )
+  .await
+  .context("Failed to import your entities")?;
+
+  send_import_report(&job.bot, job.chat_id(), &report, false).await?;
+
+  if report.new_entities > 0 {
+    job
+      .bot
+      .send_message(job.chat_id(), "Use /untagged to tag what was imported")
+      .await?;
+  }
+
+  Ok(())
+}
+
+async fn download_file(bot: &BotType, file_id: &str) -> Result<Vec<u8>> {
+  let doc_data = bot.get_file(file_id).await?;
+  let mut file_data = Vec::new();
+  bot.download_file(&doc_data.path, &mut file_data).await?;
+
+  Ok(file_data)
+}
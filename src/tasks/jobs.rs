@@ -0,0 +1,368 @@
+use std::{fmt, time::Duration};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use teloxide::{
+  requests::Requester,
+  types::{ChatId, MessageId},
+  utils::html::escape as html_escape,
+};
+
+use crate::{
+  database::{
+    import::{
+      types::{ExportFormat, ImportMode},
+      Progress,
+    },
+    queries::{self, Job},
+  },
+  tasks,
+  types::{BotType, DbConn, DbType},
+  util,
+};
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+// How often idle workers look for new jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Broadcasts run on their own worker, see Queue
+const BROADCAST_KIND: &str = "broadcast";
+
+// Nobody needs more waiting, and it keeps a single user from filling the queue
+const MAX_ACTIVE_JOBS_PER_USER: i64 = 5;
+
+// Everything that can run as a job, stored as JSON so queued jobs survive a
+// restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+  Export {
+    format: ExportFormat,
+    since: Option<i64>,
+  },
+  ArchiveExport,
+  Import {
+    file_ids: Vec<String>,
+    mode: ImportMode,
+    dry_run: bool,
+  },
+  QuickStickBotImport {
+    file_id: String,
+  },
+  TelegramDesktopImport {
+    file_id: String,
+  },
+  FixEntities,
+  // An admin exporting someone else, the job belongs to the admin
+  UserExport {
+    user_id: String,
+    format: ExportFormat,
+  },
+  Broadcast {
+    text: String,
+  },
+}
+
+impl JobKind {
+  pub fn name(&self) -> &'static str {
+    match self {
+      JobKind::Export { .. } => "export",
+      JobKind::ArchiveExport => "archive_export",
+      JobKind::Import { .. } => "import",
+      JobKind::QuickStickBotImport { .. } => "quickstickbot_import",
+      JobKind::TelegramDesktopImport { .. } => "telegram_desktop_import",
+      JobKind::FixEntities => "fix_entities",
+      JobKind::UserExport { .. } => "user_export",
+      JobKind::Broadcast { .. } => BROADCAST_KIND,
+    }
+  }
+
+  pub fn description(&self) -> &'static str {
+    match self {
+      JobKind::Export { .. } => "Exporting your entities",
+      JobKind::ArchiveExport => "Exporting an archive",
+      JobKind::Import { dry_run: true, .. } => "Dry run of an import",
+      JobKind::Import { .. } => "Importing your entities",
+      JobKind::QuickStickBotImport { .. } => "Importing from QuickStickBot",
+      JobKind::TelegramDesktopImport { .. } => "Importing from Telegram Desktop",
+      JobKind::FixEntities => "Fixing your entities",
+      JobKind::UserExport { .. } => "Exporting a user",
+      JobKind::Broadcast { .. } => "Sending a broadcast to every user",
+    }
+  }
+}
+
+// Which jobs a worker runs. A broadcast takes hours for many users, so it gets
+// a worker of its own instead of holding up everyone else's jobs, and it
+// doesn't count against the admin's JOB_USER_CONCURRENCY.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queue {
+  Jobs,
+  Broadcasts,
+}
+
+// Returned by a job that stopped because it was cancelled
+#[derive(Debug)]
+pub struct JobCancelled;
+
+impl fmt::Display for JobCancelled {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "The job was cancelled")
+  }
+}
+
+impl std::error::Error for JobCancelled {}
+
+// What a running job gets to report its progress and check if it should stop
+pub struct JobHandle {
+  pub db: DbType,
+  pub bot: BotType,
+  pub job: Job,
+}
+
+impl JobHandle {
+  pub fn user_id(&self) -> String {
+    self.job.user_id.clone()
+  }
+
+  pub fn chat_id(&self) -> ChatId {
+    ChatId(self.job.chat_id)
+  }
+
+  // Shown in the progress message and in /jobs
+  pub async fn progress(&self, text: impl Into<String>) {
+    let text = text.into();
+
+    if let Err(e) = queries::set_job_progress(&self.db, self.job.job_id, &text).await {
+      log::error!("Failed to save the progress of a job: {:?}", e);
+    }
+
+    // Progress is only informational, a deleted message should not stop the job
+    let result = self
+      .bot
+      .edit_message_text(
+        self.chat_id(),
+        MessageId(self.job.message_id),
+        html_escape(&text),
+      )
+      .await;
+    if let Err(e) = result {
+      log::debug!("Could not edit the job progress: {:?}", e);
+    }
+  }
+
+  pub async fn is_cancelled(&self) -> Result<bool> {
+    queries::is_job_cancelled(&self.db, self.job.job_id).await
+  }
+
+  // For jobs that can't report what they did so far, they just stop
+  pub async fn check_cancelled(&self) -> Result<()> {
+    if self.is_cancelled().await? {
+      return Err(JobCancelled.into());
+    }
+
+    Ok(())
+  }
+
+  pub fn checkpoint<T: DeserializeOwned + Default>(&self) -> Result<T> {
+    match &self.job.checkpoint {
+      Some(checkpoint) => Ok(serde_json::from_str(checkpoint)?),
+      None => Ok(T::default()),
+    }
+  }
+
+  pub async fn save_checkpoint<T: Serialize>(&self, checkpoint: &T) -> Result<()> {
+    queries::set_job_checkpoint(
+      &self.db,
+      self.job.job_id,
+      serde_json::to_string(checkpoint)?,
+    )
+    .await
+  }
+}
+
+impl Progress for JobHandle {
+  async fn report(&self, text: String) {
+    self.progress(text).await
+  }
+
+  async fn is_cancelled(&self) -> Result<bool> {
+    JobHandle::is_cancelled(self).await
+  }
+
+  async fn check_cancelled(&self) -> Result<()> {
+    JobHandle::check_cancelled(self).await
+  }
+
+  fn checkpoint<T: DeserializeOwned + Default>(&self) -> Result<T> {
+    JobHandle::checkpoint(self)
+  }
+
+  async fn save_checkpoint<T: Serialize + Sync>(&self, checkpoint: &T) -> Result<()> {
+    JobHandle::save_checkpoint(self, checkpoint).await
+  }
+}
+
+// Queues a job for a user, the progress is shown in a new message. Returns
+// false if the user already has too many jobs.
+pub async fn submit(
+  db: &DbConn,
+  bot: &BotType,
+  chat_id: ChatId,
+  user_id: String,
+  kind: JobKind,
+) -> Result<bool> {
+  if queries::count_active_jobs(db, user_id.clone(), None).await? >= MAX_ACTIVE_JOBS_PER_USER {
+    bot
+      .send_message(
+        chat_id,
+        "You already have too many jobs, please wait for them to finish, see /jobs",
+      )
+      .await?;
+    return Ok(false);
+  }
+
+  let progress_message = bot
+    .send_message(
+      chat_id,
+      format!(
+        "{}, this runs in the background so you can keep using the bot\n\
+        Use /jobs to see how it is going, or /cancel to stop",
+        kind.description()
+      ),
+    )
+    .await?;
+
+  queries::create_job(
+    db,
+    user_id,
+    chat_id.0,
+    progress_message.id.0,
+    kind.name(),
+    serde_json::to_string(&kind)?,
+  )
+  .await?;
+
+  Ok(true)
+}
+
+// Starts the workers, after queueing again what was running when the bot stopped
+pub async fn run(db: DbType, bot: BotType) {
+  match queries::requeue_running_jobs(&db).await {
+    Ok(0) => {}
+    Ok(count) => log::info!("Continuing {} jobs that were running", count),
+    Err(e) => log::error!("Failed to requeue the running jobs: {:?}", e),
+  }
+
+  let workers = util::env_or("JOB_WORKERS", 2).max(1);
+  for _ in 0..workers {
+    tokio::spawn(work(db.clone(), bot.clone(), Queue::Jobs));
+  }
+  tokio::spawn(work(db.clone(), bot.clone(), Queue::Broadcasts));
+
+  // Finished jobs are only kept around to show them in /jobs
+  let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+  loop {
+    interval.tick().await;
+
+    if let Err(e) = queries::delete_jobs_finished_before(&db, util::get_unix() - 7 * DAY).await {
+      log::error!("Failed to remove finished jobs: {:?}", e);
+    }
+  }
+}
+
+async fn work(db: DbType, bot: BotType, queue: Queue) {
+  let max_running_per_user = util::env_or("JOB_USER_CONCURRENCY", 1).max(1);
+
+  loop {
+    let job = match claim_job(&db, queue, max_running_per_user).await {
+      Ok(Some(job)) => job,
+      Ok(None) => {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        continue;
+      }
+      Err(e) => {
+        log::error!("Failed to get the next job: {:?}", e);
+        tokio::time::sleep(POLL_INTERVAL).await;
+        continue;
+      }
+    };
+
+    let job_id = job.job_id;
+    log::info!(
+      "Running job {} ({}) of user {:?}",
+      job_id,
+      job.kind,
+      job.user_id
+    );
+
+    let handle = JobHandle {
+      db: db.clone(),
+      bot: bot.clone(),
+      job,
+    };
+    let (status, progress) = match run_job(&handle).await {
+      Ok(()) => ("done", "Done".to_string()),
+      Err(e) if e.is::<JobCancelled>() => ("cancelled", "Cancelled".to_string()),
+      Err(e) => {
+        log::error!("Job {} failed: {:?}", job_id, e);
+        let message = format!("Failed: {:#}", e);
+        send_or_log(&handle, html_escape(&message)).await;
+        ("failed", message)
+      }
+    };
+
+    handle.progress(progress.clone()).await;
+    if let Err(e) = queries::finish_job(&db, job_id, status, &progress).await {
+      log::error!("Failed to finish job {}: {:?}", job_id, e);
+    }
+  }
+}
+
+// Only the jobs of admins start in maintenance mode, the rest waits until it is off
+async fn claim_job(db: &DbConn, queue: Queue, max_running_per_user: i64) -> Result<Option<Job>> {
+  if queue == Queue::Broadcasts {
+    // Only admins can broadcast, a broadcast pauses itself in maintenance mode
+    return queries::claim_job_of_kind(db, BROADCAST_KIND).await;
+  }
+
+  let only_users = match queries::is_maintenance_mode(db).await? {
+    true => Some(util::admin_ids()),
+    false => None,
+  };
+
+  queries::claim_job(
+    db,
+    max_running_per_user,
+    only_users.as_deref(),
+    BROADCAST_KIND,
+  )
+  .await
+}
+
+async fn run_job(job: &JobHandle) -> Result<()> {
+  job.check_cancelled().await?;
+  job.progress("Starting...").await;
+
+  match serde_json::from_str(&job.job.payload)? {
+    JobKind::Export { format, since } => tasks::export::run(job, format, since).await,
+    JobKind::ArchiveExport => tasks::export::run_archive(job).await,
+    JobKind::Import {
+      file_ids,
+      mode,
+      dry_run,
+    } => tasks::import::run(job, file_ids, mode, dry_run).await,
+    JobKind::QuickStickBotImport { file_id } => tasks::import::run_qs(job, file_id).await,
+    JobKind::TelegramDesktopImport { file_id } => tasks::import::run_td(job, file_id).await,
+    JobKind::FixEntities => tasks::fix::run(job).await,
+    JobKind::UserExport { user_id, format } => tasks::export::run_user(job, user_id, format).await,
+    JobKind::Broadcast { text } => tasks::broadcast::run(job, text).await,
+  }
+}
+
+async fn send_or_log(job: &JobHandle, message: String) {
+  if let Err(e) = job.bot.send_message(job.chat_id(), message).await {
+    log::error!("Failed to send the result of a job: {:?}", e);
+  }
+}
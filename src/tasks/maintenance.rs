@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::{
+  database::queries,
+  types::{DbConn, DbType},
+  util,
+};
+
+const HOUR: u64 = 60 * 60;
+
+// Checks the database before the bot starts, when MAINTENANCE_ON_STARTUP is
+// check or repair
+pub async fn run_on_startup(db: &DbConn) -> Result<()> {
+  let dry_run = match std::env::var("MAINTENANCE_ON_STARTUP").as_deref() {
+    Err(_) | Ok("" | "off") => return Ok(()),
+    Ok("check") => true,
+    Ok("repair") => false,
+    Ok(value) => bail!(
+      "MAINTENANCE_ON_STARTUP must be off, check or repair, not {:?}",
+      value
+    ),
+  };
+
+  let report = queries::check_integrity(db, dry_run).await?;
+
+  if report.is_clean() {
+    log::info!("Database integrity check found nothing to repair");
+  } else if dry_run {
+    log::warn!("Database integrity check, nothing was changed:\n{}", report);
+  } else {
+    log::info!("Database repaired:\n{}", report);
+  }
+
+  Ok(())
+}
+
+// Runs before the migrations, to tell what they are going to remove
+pub async fn check_before_migrations(db: &DbConn) -> Result<()> {
+  match queries::count_rows_dropped_by_migration(db).await? {
+    None | Some(0) => {}
+    Some(dropped) => log::warn!(
+      "Migrating removes the usage statistics of {} entities that have no file, \
+      they could not be sent anyway",
+      dropped
+    ),
+  }
+
+  Ok(())
+}
+
+// Optimizes the database every few hours, and vacuums it every few days if
+// DATABASE_VACUUM_DAYS is set
+pub async fn run(db: DbType) {
+  let optimize_hours = util::env_or("DATABASE_OPTIMIZE_HOURS", 6);
+  let vacuum_days = util::env_or("DATABASE_VACUUM_DAYS", 0);
+  if optimize_hours == 0 && vacuum_days == 0 {
+    return;
+  }
+
+  // Checked every hour, so both can be configured independently
+  let mut interval = tokio::time::interval(Duration::from_secs(HOUR));
+  // The first tick is right away, nothing to do yet
+  interval.tick().await;
+
+  let mut hours = 0;
+  loop {
+    interval.tick().await;
+    hours += 1;
+
+    if vacuum_days > 0 && hours % (vacuum_days * 24) == 0 {
+      log::info!("Vacuuming the database");
+      if let Err(e) = queries::vacuum(&db).await {
+        log::error!("Failed to vacuum the database: {:?}", e);
+      }
+    } else if optimize_hours > 0 && hours % optimize_hours == 0 {
+      if let Err(e) = queries::optimize(&db).await {
+        log::error!("Failed to optimize the database: {:?}", e);
+      }
+    }
+  }
+}
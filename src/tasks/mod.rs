@@ -0,0 +1,9 @@
+pub mod backup;
+pub mod broadcast;
+pub mod export;
+pub mod fix;
+pub mod health;
+pub mod import;
+pub mod jobs;
+pub mod maintenance;
+pub mod purge;
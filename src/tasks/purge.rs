@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{
+  database::queries,
+  types::{DbType, PendingDeletions},
+  util,
+};
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+// How long the data of a user that used /stop is kept around, in milliseconds
+pub fn grace_period() -> i64 {
+  util::env_or("DELETION_GRACE_DAYS", 7) * DAY
+}
+
+// Permanently wipes users whose grace period has passed, checked every hour
+pub async fn run(db: DbType, pending_deletions: PendingDeletions) {
+  let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+  loop {
+    interval.tick().await;
+
+    if let Err(e) = purge_deleted_users(&db, &pending_deletions).await {
+      log::error!("Failed to purge deleted users: {:?}", e);
+    }
+  }
+}
+
+async fn purge_deleted_users(db: &DbType, pending_deletions: &PendingDeletions) -> Result<()> {
+  let user_ids = queries::get_users_deleted_before(db, util::get_unix() - grace_period()).await?;
+
+  for user_id in user_ids {
+    log::info!("Purging user {:?}", user_id);
+    queries::wipe_user(db, user_id.clone()).await?;
+    pending_deletions.remove(&user_id);
+  }
+
+  Ok(())
+}
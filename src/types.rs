@@ -1,11 +1,18 @@
+use anyhow::Result;
 use sqlx::{Pool, Sqlite};
-use std::sync::Arc;
+use std::{
+  collections::HashSet,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+  },
+};
 use teloxide::{
   adaptors::DefaultParseMode, dispatching::dialogue::InMemStorage, prelude::Dialogue,
   types::FileMeta, Bot,
 };
 
-use crate::database::EntityType;
+use crate::database::{queries, EntityType, UntaggedEntity};
 
 pub type DialogueWithState = Dialogue<ConversationState, InMemStorage<ConversationState>>;
 
@@ -13,6 +20,50 @@ pub type BotType = DefaultParseMode<Bot>;
 pub type DbConn = Pool<Sqlite>;
 pub type DbType = Arc<DbConn>;
 
+// The users that used /stop, kept in memory since every message is checked
+// against it
+#[derive(Clone, Default)]
+pub struct PendingDeletions(Arc<RwLock<HashSet<String>>>);
+
+impl PendingDeletions {
+  pub async fn load(db: &DbConn) -> Result<Self> {
+    let user_ids = queries::get_deleted_users(db).await?;
+    Ok(Self(Arc::new(RwLock::new(user_ids.into_iter().collect()))))
+  }
+
+  pub fn contains(&self, user_id: &str) -> bool {
+    self.0.read().unwrap().contains(user_id)
+  }
+
+  pub fn insert(&self, user_id: String) {
+    self.0.write().unwrap().insert(user_id);
+  }
+
+  pub fn remove(&self, user_id: &str) {
+    self.0.write().unwrap().remove(user_id);
+  }
+}
+
+// Whether only admins can use the bot, kept in memory since every message and
+// inline query is checked against it
+#[derive(Clone, Default)]
+pub struct MaintenanceMode(Arc<AtomicBool>);
+
+impl MaintenanceMode {
+  pub async fn load(db: &DbConn) -> Result<Self> {
+    let enabled = queries::is_maintenance_mode(db).await?;
+    Ok(Self(Arc::new(AtomicBool::new(enabled))))
+  }
+
+  pub fn is_on(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+
+  pub fn set(&self, enabled: bool) {
+    self.0.store(enabled, Ordering::Relaxed);
+  }
+}
+
 #[derive(Clone, Default, PartialEq)]
 pub enum ConversationState {
   #[default]
@@ -30,7 +81,27 @@ pub enum ConversationState {
   },
 
   ReceiveQSImport,
+  ReceiveTDImport,
   ReceiveBotImport,
+  ReceiveBotImportMode {
+    // Every part of a split export is sent before picking the mode
+    file_ids: Vec<String>,
+  },
+
+  VerifyRetag {
+    query: InlineSearchQuery,
+    add_tags: Vec<String>,
+    remove_tags: Vec<String>,
+  },
+
+  ReviewUntagged {
+    entity: UntaggedEntity,
+    skipped: i64,
+  },
+
+  VerifyBroadcast {
+    text: String,
+  },
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -66,4 +137,5 @@ pub struct InlineSearchQuery {
   pub sort: EntitySort,
   pub entity_type: Option<EntityType>,
   pub get_all: bool,
+  pub untagged: bool,
 }
@@ -1,4 +1,10 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use std::{
+  hash::{BuildHasher, RandomState},
+  str::FromStr,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use teloxide::RequestError;
 use time_humanize::HumanTime;
 
 pub fn get_unix() -> i64 {
@@ -17,3 +23,72 @@ pub fn unix_to_humantime(unix: i64) -> String {
   let humantime = HumanTime::from_duration_since_timestamp((unix / 1000).try_into().unwrap());
   humantime.to_string()
 }
+
+// Read an optional setting from the environment, falling back to the default
+// when it is missing or invalid
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+  match std::env::var(name) {
+    Ok(value) => value.parse().unwrap_or_else(|_| {
+      log::warn!("Invalid value for {}: {:?}, using the default", name, value);
+      default
+    }),
+    Err(_) => default,
+  }
+}
+
+// How tags are stored, lowercase without whitespace or commas
+pub fn normalize_tag(tag: &str) -> String {
+  tag
+    .to_lowercase()
+    .chars()
+    .filter(|c| !c.is_whitespace() && *c != ',')
+    .collect()
+}
+
+// Users listed in ADMIN_IDS, separated by commas
+pub fn admin_ids() -> Vec<String> {
+  std::env::var("ADMIN_IDS")
+    .unwrap_or_default()
+    .split(',')
+    .map(|admin_id| admin_id.trim().to_string())
+    .filter(|admin_id| !admin_id.is_empty())
+    .collect()
+}
+
+pub fn is_admin(user_id: &str) -> bool {
+  admin_ids().iter().any(|admin_id| admin_id == user_id)
+}
+
+// An id nobody can guess or trace back to a user, such as the id shared by the
+// parts of an export
+pub fn random_id() -> String {
+  let state = RandomState::new();
+  format!("{:016x}", state.hash_one((get_unix(), std::process::id())))
+}
+
+pub fn sha256_hex<T: AsRef<[u8]>>(data: T) -> String {
+  format!("{:x}", Sha256::digest(data))
+}
+
+// Tells exports of different users apart without storing the id itself
+pub fn user_hash(secret: &str, user_id: &str) -> String {
+  sha256_hex(format!("{}:{}", secret, user_id))
+}
+
+// Sends a request again after waiting as long as Telegram asks, when sending too
+// quickly
+pub async fn retry_after<T, R, F>(request: F) -> Result<T, RequestError>
+where
+  F: Fn() -> R,
+  R: std::future::IntoFuture<Output = Result<T, RequestError>>,
+{
+  loop {
+    match request().await {
+      Err(RequestError::RetryAfter(seconds)) => {
+        log::debug!("Rate limited, waiting {:?}", seconds);
+        tokio::time::sleep(seconds.duration()).await;
+      }
+      result => return result,
+    }
+  }
+}